@@ -0,0 +1,101 @@
+//! Times `render` on a mid-sized Mandelbrot view, a 4000x3000 render with and without tiling,
+//! and a full view with and without the cardioid and bulb skip: `cargo bench --bench render`. Criterion isn't a dependency, so these are plain
+//! timing loops.
+
+use regen::render::{
+    Aspect, ColorClamp, Coloring, DEFAULT_BAILOUT, DEFAULT_POWER, DEFAULT_TILE_SIZE, GenParams,
+    Palette, Pattern, Trap, render, render_without_interior_skip,
+};
+use std::time::Instant;
+
+const RUNS: u32 = 5;
+
+fn main() {
+    let params = GenParams {
+        pattern: Pattern::Mandelbrot,
+        width: 800,
+        height: 600,
+        x_pos: -0.745,
+        y_pos: 0.113,
+        view_scale: 0.05,
+        max_iterations: 800,
+        smoothness: 8,
+        color_step: 6000.0,
+        palette: Palette::Grayscale,
+        palette_stops: Vec::new(),
+        palette_offset: 0.0,
+        coloring: Coloring::Escape,
+        color_clamp: ColorClamp::Clamp,
+        trap: Trap::Point,
+        supersample: 1,
+        julia_constant: None,
+        power: DEFAULT_POWER,
+        bailout: DEFAULT_BAILOUT,
+        seed: Some(0),
+        index: 0,
+        deep_zoom: false,
+        aspect: Aspect::Pixel,
+        rgba: false,
+        pixel_center: true,
+        tile_size: DEFAULT_TILE_SIZE,
+    };
+    // The first render warms up the rayon pool
+    render(&params).unwrap();
+    let started = Instant::now();
+    for _ in 0..RUNS {
+        render(&params).unwrap();
+    }
+    println!(
+        "render {}x{}: {:.1?} per image",
+        params.width,
+        params.height,
+        started.elapsed() / RUNS
+    );
+
+    // One-pixel tiles leave each band a single row scanned left to right
+    let large = GenParams {
+        width: 4000,
+        height: 3000,
+        ..params.clone()
+    };
+    for tile_size in [1, DEFAULT_TILE_SIZE] {
+        let started = Instant::now();
+        render(&GenParams {
+            tile_size,
+            ..large.clone()
+        })
+        .unwrap();
+        println!(
+            "render {}x{} with {}-pixel tiles: {:.1?}",
+            large.width,
+            large.height,
+            tile_size,
+            started.elapsed()
+        );
+    }
+
+    // The whole set in view, so a good share of the pixels are in the cardioid or the bulb
+    let full_view = GenParams {
+        x_pos: -0.75,
+        y_pos: 0.0,
+        view_scale: 0.875,
+        max_iterations: 500,
+        ..params
+    };
+    for (label, render_view) in [
+        ("with", render as fn(&GenParams) -> _),
+        ("without", render_without_interior_skip),
+    ] {
+        let started = Instant::now();
+        for _ in 0..RUNS {
+            render_view(&full_view).unwrap();
+        }
+        println!(
+            "full view {}x{} {} the cardioid and bulb skip: {:.1?} per image",
+            full_view.width,
+            full_view.height,
+            label,
+            started.elapsed() / RUNS
+        );
+    }
+}
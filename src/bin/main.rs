@@ -1,557 +1,6188 @@
-use clap::Parser as ClapParser;
+use clap::builder::NonEmptyStringValueParser;
+use clap::{Parser, ValueEnum};
 use csv::ReaderBuilder;
 use csv::WriterBuilder;
-use env_logger;
-use futures::future::try_join_all;
-use image::{ImageBuffer, Rgb, RgbImage};
-use log::{error, info, warn}; // Import logging macros
-use rand::Rng;
-use rusoto_core::Region;
-use rusoto_s3::{PutObjectRequest, S3, S3Client};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use futures::StreamExt;
+use futures::future::{join_all, try_join_all};
+use futures::stream::FuturesUnordered;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{Delay, DynamicImage, Frame, GenericImage, ImageFormat, Rgb, RgbImage, Rgba};
+use log::{LevelFilter, error, info, warn}; // Import logging macros
+use md5::Md5;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use regen::error::RegenError;
+use regen::mime::{content_type_for, is_compressible};
+use regen::render::{
+    Aspect, ColorClamp, Coloring, DEFAULT_BAILOUT, DEFAULT_MANDELBROT_PARAMS, DEFAULT_POWER,
+    DEFAULT_TILE_SIZE, GenParams, MandelbrotParams, Palette, Pattern, RenderStats, Trap,
+    check_deep_zoom, default_params, image_rng, palette_offset, render, render_with_stats,
+    resolve_palette, view_bounds,
+};
+use regen::timings::{TimingSummary, throughput};
+use regen::util::{format_kib, human_readable_size, natural_cmp, parse_size};
+use regen::watermark::{WatermarkPosition, draw_watermark};
+use rusoto_core::credential::{
+    AutoRefreshingProvider, AwsCredentials, CredentialsError, EnvironmentProvider,
+    InstanceMetadataProvider, ProfileProvider, ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, HeadObjectError, HeadObjectRequest,
+    PutObjectRequest, S3, S3Client, UploadPartRequest,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::io::{Seek, SeekFrom};
+use std::io::{Cursor, IsTerminal, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use walkdir::WalkDir; // Import env_logger for initialization
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio_util::sync::CancellationToken;
+use walkdir::WalkDir;
 
-// For concurrent uploads
-#[allow(clippy::too_many_arguments)] // This function signature is intentionally long for demonstration
-pub fn generate_mathematical_image(
+/// Image size for jobs that don't give one and have no --width/--height to fall back on.
+const DEFAULT_JOB_SIZE: (u32, u32) = (1920, 1080);
+/// Inserted before the extension of thumbnail file names.
+const THUMBNAIL_SUFFIX: &str = "_thumb";
+/// Where generated images are written and uploaded from unless `--output-dir` is given.
+const DEFAULT_OUTPUT_DIR: &str = "output";
+/// Strong enough to read on any background while the image still shows through.
+const DEFAULT_WATERMARK_OPACITY: f64 = 0.6;
+/// Tiles per row of a contact sheet unless `--columns` is given.
+const DEFAULT_SHEET_COLUMNS: u32 = 5;
+/// Side of a contact sheet cell; images are scaled to fit and centered in it.
+const DEFAULT_SHEET_TILE_SIZE: u32 = 256;
+/// Space between contact sheet cells and around the edge.
+const SHEET_GAP: u32 = 4;
+const SHEET_BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+
+/// Share of max-iteration pixels above which `--auto-iterations` treats a render as under-iterated.
+const UNDER_ITERATED_RATIO: f64 = 0.7;
+/// Largest max_iterations `--auto-iterations` will double up to.
+const AUTO_ITERATIONS_CAP: u32 = 10_000;
+/// Luma variance (in 8-bit levels squared) below which `--reject-degenerate` discards a render
+/// as flat. Real fractal renders are in the thousands; a few stray pixels on white are below 25.
+const DEGENERATE_VARIANCE: f64 = 25.0;
+
+/// Whether `--reject-degenerate` discards a render with these statistics as nearly one color.
+fn is_degenerate(stats: &RenderStats) -> bool {
+    stats.variance < DEGENERATE_VARIANCE
+}
+
+/// Fractal images never change once uploaded, so they can be cached for a year.
+const DEFAULT_CACHE_CONTROL: &str = "public, max-age=31536000";
+/// Default number of retries for an upload that failed with a transient error.
+const DEFAULT_UPLOAD_RETRIES: u32 = 3;
+/// Delay before the first upload retry; doubled for every further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between upload retries, jitter included, however many retries are allowed.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Upload bodies larger than this are sent in parts.
+const DEFAULT_MULTIPART_THRESHOLD: &str = "16MB";
+/// Size of every part but the last. S3 needs at least 5 MiB and allows 10,000 parts, so
+/// objects over about 80 GB get larger parts.
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+const MAX_MULTIPART_PARTS: usize = 10_000;
+/// Parts of one object uploaded at once.
+const MULTIPART_UPLOADS: usize = 4;
+/// NeuQuant sampling factor for GIF frames, from 1 (best colors) to 30 (fastest). 10 is the
+/// gif crate's own default.
+const GIF_QUANTIZE_SPEED: i32 = 10;
+/// Attempts at writing an image before a transient filesystem error fails it.
+const SAVE_ATTEMPTS: u32 = 3;
+/// Wait before retrying a failed image write, doubled for the next retry.
+const SAVE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Width of the classic [-2.5, 1] view of the Mandelbrot set, shown at `--zoom 1`.
+const CLASSIC_VIEW_WIDTH: f64 = 3.5;
+/// Center of the classic view, used by `--zoom` when no `--center-x`/`--center-y` is given.
+const CLASSIC_VIEW_CENTER: (f64, f64) = (-0.75, 0.0);
+
+/// Whether `--auto-iterations` should render again with doubled max_iterations: the render is
+/// mostly black, which for an escape-time pattern usually means points ran out of iterations
+/// before escaping, and doubling stays within the cap.
+fn looks_under_iterated(pattern: Pattern, black_ratio: f64, max_iterations: u32) -> bool {
+    pattern.is_escape_time()
+        && black_ratio > UNDER_ITERATED_RATIO
+        && max_iterations * 2 <= AUTO_ITERATIONS_CAP
+}
+
+/// Converts a `--zoom` magnification into the view_scale that sizes the view window, so
+/// zoom 1 spans the classic view and every doubling halves the window width.
+fn zoom_view_scale(zoom: f64) -> f64 {
+    CLASSIC_VIEW_WIDTH / 4.0 / zoom
+}
+
+/// Prints the derived view window for an image as a single JSON line.
+fn dump_view_bounds(
+    index: usize,
+    width: u32,
+    height: u32,
+    x_pos: f64,
+    y_pos: f64,
+    view_scale: f64,
+    aspect: Aspect,
+) {
+    println!(
+        "{}",
+        view_bounds_json(index, width, height, x_pos, y_pos, view_scale, aspect)
+    );
+}
+
+/// The `--dump-view-bounds` line for an image.
+fn view_bounds_json(
+    index: usize,
     width: u32,
     height: u32,
-    pattern_type: &str,
+    x_pos: f64,
+    y_pos: f64,
+    view_scale: f64,
+    aspect: Aspect,
+) -> String {
+    let bounds = view_bounds(width, height, x_pos, y_pos, view_scale, aspect);
+    format!(
+        "{{\"image\":{},\"width\":{},\"height\":{},\"x_pos\":{},\"y_pos\":{},\"view_scale\":{},\"x_min\":{},\"x_max\":{},\"y_min\":{},\"y_max\":{}}}",
+        index,
+        width,
+        height,
+        x_pos,
+        y_pos,
+        view_scale,
+        bounds.x_min,
+        bounds.x_max,
+        bounds.y_min,
+        bounds.y_max
+    )
+}
+
+/// Renders `params` with the library's `render_with_stats` and saves the image with the
+/// parameters embedded, returning its path.
+pub fn generate_mathematical_image(
+    params: &GenParams,
+    output_dir: &Path,
     filename: &str,
-    mandelbrot_params: Option<(f64, f64, f64, u32, u32, f64)>,
-) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    fsync: bool,
+    format: OutputFormat,
+    quality: u8,
+) -> Result<PathBuf, RegenError> {
     info!(
         "Generating mathematical image: pattern_type={}, filename={}, width={}, height={}",
-        pattern_type, filename, width, height
+        params.pattern, filename, params.width, params.height
     );
-    let mut img: RgbImage = ImageBuffer::new(width, height);
-    let mut rng = rand::thread_rng();
+    let (img, _) = render_with_stats(params)?;
+    let temp_path = output_dir.join(filename);
+    save_with_retry(
+        &temp_path,
+        &encode_generated(&img, params, format, quality)?,
+        fsync,
+    )?;
+    info!("Image saved to {}", temp_path.display());
 
-    // Default to white background for all images
-    for x in 0..width {
-        for y in 0..height {
-            img.put_pixel(x, y, Rgb([255, 255, 255]));
-        }
-    }
+    Ok(temp_path)
+}
 
-    match pattern_type {
-        "mandelbrot" => {
-            info!(
-                "Generating Mandelbrot pattern with params: {:?}",
-                mandelbrot_params
-            );
-            // Default Mandelbrot parameters, can be overridden by `mandelbrot_params`
-            let (x_pos, y_pos, escape_radius, max_iterations, smoothness, color_step) =
-                mandelbrot_params.unwrap_or((-0.00275, 0.78912, 0.125689, 800, 8, 6000.0));
-
-            // Calculate the view window based on x_pos, y_pos, and escape_radius
-            let view_width = 4.0 * escape_radius;
-            let view_height = view_width * (height as f64 / width as f64);
-
-            let x_min = x_pos - view_width / 2.0;
-            let x_max = x_pos + view_width / 2.0;
-            let y_min = y_pos - view_height / 2.0;
-            let y_max = y_pos + view_height / 2.0;
-
-            for x in 0..width {
-                for y in 0..height {
-                    let c_real = x_min + (x as f64 / width as f64) * (x_max - x_min);
-                    let c_imag = y_min + (y as f64 / height as f64) * (y_max - y_min);
-
-                    let mut z_real = 0.0;
-                    let mut z_imag = 0.0;
-
-                    let mut iterations = 0;
-                    let mut magnitude_sq = 0.0;
-
-                    while magnitude_sq < 4.0 && iterations < max_iterations {
-                        let next_z_real = z_real * z_real - z_imag * z_imag + c_real;
-                        z_imag = 2.0 * z_real * z_imag + c_imag;
-                        z_real = next_z_real;
-                        magnitude_sq = z_real * z_real + z_imag * z_imag;
-                        iterations += 1;
-                    }
+/// Encodes a rendered image, recording `params` in it when the format is PNG (the only one
+/// with a place for them).
+fn encode_generated(
+    img: &DynamicImage,
+    params: &GenParams,
+    format: OutputFormat,
+    quality: u8,
+) -> Result<Vec<u8>, RegenError> {
+    let mut bytes = encode_image(img, format, quality)?;
+    if format == OutputFormat::Png {
+        embed_params(&mut bytes, params)?;
+    }
+    Ok(bytes)
+}
 
-                    if iterations == max_iterations {
-                        // Point is in the set (black)
-                        img.put_pixel(x, y, Rgb([0, 0, 0]));
-                    } else {
-                        // Point escaped, color based on iteration count with smoothing
-                        let log_zn = magnitude_sq.ln() / 2.0;
-                        let nu = (log_zn / 2.0_f64.ln()).ln() / 2.0_f64.ln();
-                        let smoothed_iterations = iterations as f64 + 1.0 - nu;
+/// Encodes an image in the given format.
+fn encode_image(
+    img: &DynamicImage,
+    format: OutputFormat,
+    quality: u8,
+) -> Result<Vec<u8>, RegenError> {
+    let mut bytes = Vec::new();
+    match format {
+        OutputFormat::Jpeg => {
+            // JPEG has no alpha channel, so transparency is dropped (to the white background)
+            DynamicImage::ImageRgb8(img.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))?;
+        }
+        OutputFormat::Png => img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?,
+        OutputFormat::Webp => img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::WebP)?,
+        OutputFormat::Svg => bytes = encode_svg(img)?,
+    }
+    Ok(bytes)
+}
 
-                        let color_val = (smoothed_iterations / color_step) * 255.0;
-                        let _intensity = (color_val.min(255.0)) as u8;
+/// Most rectangles an SVG may hold. Past this the file is too big for viewers and print
+/// tools to handle, and a raster format is the better choice.
+const MAX_SVG_RECTS: usize = 250_000;
+/// SVG colors are rounded to multiples of this in each channel, so neighbouring pixels of a
+/// smooth gradient fall into the same band and merge into one rectangle. 255 is a multiple,
+/// so black and white stay exact.
+const SVG_COLOR_STEP: u8 = 17;
 
-                        if smoothness == 0 {
-                            img.put_pixel(x, y, Rgb([255, 255, 255]));
-                        } else {
-                            img.put_pixel(x, y, Rgb([255, 255, 255]));
-                        }
-                    }
-                }
+/// Encodes an image as SVG: every row becomes rectangles covering runs of the same color band.
+/// Transparent pixels are left out. Fails if the image needs more than `MAX_SVG_RECTS`.
+fn encode_svg(img: &DynamicImage) -> Result<Vec<u8>, RegenError> {
+    let img = img.to_rgba8();
+    let (width, height) = img.dimensions();
+    let band = |c: u8| (c as f64 / SVG_COLOR_STEP as f64).round() as u8 * SVG_COLOR_STEP;
+    let mut rects = Vec::new();
+    for (y, row) in img.rows().enumerate() {
+        let colors: Vec<[u8; 4]> = row
+            .map(|&Rgba([r, g, b, a])| [band(r), band(g), band(b), a])
+            .collect();
+        let mut x = 0;
+        for run in colors.chunk_by(|a, b| a == b) {
+            let [r, g, b, a] = run[0];
+            if a > 0 {
+                let opacity = if a < 255 {
+                    format!(r#" fill-opacity="{:.3}""#, a as f64 / 255.0)
+                } else {
+                    String::new()
+                };
+                rects.push(format!(
+                    r##"<rect x="{}" y="{}" width="{}" height="1" fill="#{:02x}{:02x}{:02x}"{}/>"##,
+                    x,
+                    y,
+                    run.len(),
+                    r,
+                    g,
+                    b,
+                    opacity
+                ));
             }
-            info!("Finished Mandelbrot pattern generation for {}", filename);
+            x += run.len();
         }
-        _ => {
-            // Default to random noise if pattern_type is not recognized
-            warn!(
-                "Unrecognized pattern type: {}. Defaulting to random noise.",
-                pattern_type
-            );
-            for x in 0..width {
-                for y in 0..height {
-                    let r_val = rng.r#gen();
-                    let g_val = rng.r#gen();
-                    let b_val = rng.r#gen();
-                    img.put_pixel(x, y, Rgb([r_val, g_val, b_val]));
-                }
-            }
-            info!("Random noise image generated for {}", filename);
+        if rects.len() > MAX_SVG_RECTS {
+            return Err(RegenError::InvalidParams(format!(
+                "a {}x{} SVG needs more than {} rectangles; use a smaller --width/--height or a raster --format",
+                width, height, MAX_SVG_RECTS
+            )));
         }
     }
-    let temp_dir = PathBuf::from("src/data/images");
-    std::fs::create_dir_all(&temp_dir)?; // Ensure the directory exists
-    let temp_path = temp_dir.join(filename);
-
-    img.save(&temp_path)?;
-    info!("Image saved to {}", temp_path.display());
-
-    Ok(temp_path)
+    let svg = format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            "\n",
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}" shape-rendering="crispEdges">"#,
+            "\n{2}\n</svg>\n"
+        ),
+        width,
+        height,
+        rects.join("\n")
+    );
+    Ok(svg.into_bytes())
 }
 
-/// Opens the given image file using the system's default image viewer.
-/// This function is OS-dependent.
-pub fn preview_image(image_path: &PathBuf) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let path_str = image_path.to_str().ok_or("Invalid path for preview")?;
-    info!("Attempting to preview image: {}", image_path.display());
-
-    #[cfg(target_os = "macos")]
-    {
-        Command::new("open").arg(path_str).spawn()?;
-    }
-    #[cfg(target_os = "linux")]
-    {
-        Command::new("xdg-open").arg(path_str).spawn()?;
+/// Writes `bytes` to `path` in one go, syncing them to disk if asked.
+fn write_file(path: &Path, bytes: &[u8], fsync: bool) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(bytes)?;
+    if fsync {
+        file.sync_all()?;
     }
-
-    info!("Previewing image at: {}", image_path.display());
     Ok(())
 }
 
-// Main function for testing purposes
-
-#[derive(clap::Parser)]
-#[clap(name = "FractalGen")]
-#[clap(about = "Generate and upload fractal images", long_about = None)]
-struct Cli {
-    #[clap(subcommand)]
-    command: Commands,
+/// Creates the directory for `path` and writes `bytes` to it, trying again a couple of times
+/// after a transient error so one hiccup on a network filesystem doesn't fail the image.
+fn save_with_retry(path: &Path, bytes: &[u8], fsync: bool) -> std::io::Result<()> {
+    retry_save(path, || {
+        path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| write_file(path, bytes, fsync))
+    })
 }
 
-#[derive(clap::Subcommand)]
-enum Commands {
-    /// Generate N Mandelbrot images
-    Generate {
-        /// Number of images to generate
-        #[clap(short, long)]
-        count: usize,
+/// Runs `save` until it succeeds, fails with an error that isn't transient, or has been tried
+/// `SAVE_ATTEMPTS` times, waiting longer before each retry. `path` is only used in the log.
+fn retry_save(path: &Path, mut save: impl FnMut() -> std::io::Result<()>) -> std::io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match save() {
+            Err(e) if attempt < SAVE_ATTEMPTS && is_transient_io_error(&e) => {
+                let delay = SAVE_RETRY_DELAY * 2u32.pow(attempt - 1);
+                warn!(
+                    "Saving {} failed ({}), retry {}/{} in {:?}",
+                    path.display(),
+                    e,
+                    attempt,
+                    SAVE_ATTEMPTS - 1,
+                    delay
+                );
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
 
-        #[clap(short, long, default_value_t = false)]
-        preview: bool,
-    },
-    /// Upload images to DigitalOcean Spaces
-    Upload,
+/// Whether a filesystem error can clear up by itself, e.g. a network share timing out or
+/// another process briefly holding the file. A full disk or a missing parent is not.
+fn is_transient_io_error(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        err.kind(),
+        Interrupted
+            | WouldBlock
+            | TimedOut
+            | PermissionDenied
+            | ResourceBusy
+            | StaleNetworkFileHandle
+    )
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    env_logger::init(); // Initialize the logger
-    info!("Logger initialized.");
+/// Name of the thumbnail for an image file name or object key, e.g. `a/b.png` -> `a/b_thumb.png`.
+pub fn thumbnail_name(name: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !extension.contains('/') && !stem.ends_with('/') => {
+            format!("{}{}.{}", stem, THUMBNAIL_SUFFIX, extension)
+        }
+        _ => format!("{}{}", name, THUMBNAIL_SUFFIX),
+    }
+}
 
-    match Cli::parse().command {
-        Commands::Generate { count, preview } => {
-            info!("Generating {} Mandelbrot images...", count);
-            let tasks: Vec<_> = (0..count)
-                .map(|i| {
-                    tokio::spawn(async move {
-                        info!("Starting generation for image {}", i);
-                        let mut rng = rand::thread_rng();
-                        let width = rng.gen_range(3000..=5000);
-                        let height = rng.gen_range(2000..=3500);
-                        let x_pos = rng.gen_range(-0.5..0.5);
-                        let y_pos = rng.gen_range(0.6..0.9);
-                        let escape_radius = rng.gen_range(0.01..0.2);
-                        let max_iterations = rng.gen_range(400..1200);
-                        let smoothness = rng.gen_range(1..20);
-                        let color_step = rng.gen_range(1000.0..10000.0);
-
-                        info!("Params for image {}: width={}, height={}, x_pos={}, y_pos={}, escape_radius={}, max_iterations={}, smoothness={}, color_step={}", i, width, height, x_pos, y_pos, escape_radius, max_iterations, smoothness, color_step);
-
-                        let path = generate_mathematical_image(
-                            width,
-                            height,
-                            "mandelbrot",
-                            &format!("mandelbrot_{}.png", i),
-                            Some((
-                                x_pos,
-                                y_pos,
-                                escape_radius,
-                                max_iterations,
-                                smoothness,
-                                color_step,
-                            )),
-                        )?;
+/// Writes a downscaled copy of `img`, saved at `path`, next to it, fitting within `max_dim`
+/// on both sides with the aspect ratio preserved.
+pub fn write_thumbnail(
+    path: &Path,
+    img: &DynamicImage,
+    max_dim: u32,
+    format: OutputFormat,
+    quality: u8,
+) -> Result<PathBuf, RegenError> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| RegenError::PathConversion(path.display().to_string()))?;
+    let thumb_path = path.with_file_name(thumbnail_name(file_name));
+    let thumb = img.resize(max_dim, max_dim, FilterType::Lanczos3);
+    write_file(&thumb_path, &encode_image(&thumb, format, quality)?, false)?;
+    let (width, height) = (thumb.width(), thumb.height());
+    info!(
+        "Thumbnail saved to {} ({}x{})",
+        thumb_path.display(),
+        width,
+        height
+    );
+    Ok(thumb_path)
+}
 
-                        // Regenerate the image until the fractal ratio is at least 0.4
-                        let mut fractal_ratio = 0.0;
-                        let mut path = path;
-                        let mut attempts = 0;
-                        while fractal_ratio < 0.3 || fractal_ratio > 0.7 {
-                            if attempts > 0 {
-                                info!("Fractal ratio out of range ({:.4}). Regenerating image {}...", fractal_ratio, i);
-                                // Regenerate with new random parameters
-                                let width = rng.gen_range(3000..=5000);
-                                let height = rng.gen_range(2000..=3500);
-                                let x_pos = rng.gen_range(-0.5..0.5);
-                                let y_pos = rng.gen_range(0.6..0.9);
-                                let escape_radius = rng.gen_range(0.01..0.2);
-                                let max_iterations = rng.gen_range(400..1200);
-                                let smoothness = rng.gen_range(1..20);
-                                let color_step = rng.gen_range(1000.0..10000.0);
-                                info!("Regeneration params for image {}: width={}, height={}, x_pos={}, y_pos={}, escape_radius={}, max_iterations={}, smoothness={}, color_step={}", i, width, height, x_pos, y_pos, escape_radius, max_iterations, smoothness, color_step);
-                                path = generate_mathematical_image(
-                                    width,
-                                    height,
-                                    "mandelbrot",
-                                    &format!("mandelbrot_{}.png", i),
-                                    Some((
-                                        x_pos,
-                                        y_pos,
-                                        escape_radius,
-                                        max_iterations,
-                                        smoothness,
-                                        color_step,
-                                    )),
-                                )?;
-                            }
-                            // Calculate the ratio of black (fractal) pixels to total pixels
-                            let img = image::open(&path)?.to_rgb8();
-                            let (width, height) = img.dimensions();
-                            let total_pixels = (width * height) as f64;
-                            let mut black_pixels = 0u64;
-                            for pixel in img.pixels() {
-                                if pixel.0 == [0, 0, 0] {
-                                    black_pixels += 1;
-                                }
-                            }
-                            fractal_ratio = black_pixels as f64 / total_pixels;
-                            info!("Image {}: attempt {}, fractal_ratio={:.4}", i, attempts, fractal_ratio);
-                            attempts += 1;
-                        }
+/// Write handle onto a byte buffer that can still be read once the encoder writing into it is
+/// gone, since the GIF and PNG encoders never hand their writer back.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
 
-                        // Add random noise to the image file to defeat PNG compression
-                        {
-                            let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
-                            let metadata = file.metadata()?;
-                            let file_size = metadata.len();
-                            let noise_bytes = rng.gen_range(1_000_000..=3_000_000);
-                            let mut noise = vec![0u8; noise_bytes];
-                            rng.fill(&mut noise[..]);
-                            file.seek(SeekFrom::End(0))?;
-                            file.write_all(&noise)?;
-                            // Helper to format bytes as human-readable string
-                            fn human_readable_size(bytes: u64) -> String {
-                                const KB: u64 = 1024;
-                                const MB: u64 = KB * 1024;
-                                const GB: u64 = MB * 1024;
-                                match bytes {
-                                    b if b >= GB => format!("{:.2} GB", b as f64 / GB as f64),
-                                    b if b >= MB => format!("{:.2} MB", b as f64 / MB as f64),
-                                    b if b >= KB => format!("{:.2} KB", b as f64 / KB as f64),
-                                    b => format!("{} bytes", b),
-                                }
-                            }
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
 
-                            info!(
-                                "Appended {} bytes of noise to {} (original size: {}, new size: {}), fractal ratio: {:.4}",
-                                noise_bytes,
-                                path.display(),
-                                human_readable_size(file_size),
-                                human_readable_size(file_size + noise_bytes as u64),
-                                fractal_ratio
-                            );
-                        }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
 
-                        if preview {
-                            info!("Preview flag set, previewing image {}", i);
-                            preview_image(&path)?;
-                        }
-                        info!("Finished generation for image {}", i);
-                        Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
-                    })
-                })
-                .collect();
+/// Encodes `zoom --animate` frames one at a time as they are rendered, so only the compressed
+/// animation is held in memory rather than every frame.
+enum AnimationWriter {
+    Gif(GifEncoder<SharedBuffer>, Delay, SharedBuffer),
+    Apng(png::Writer<SharedBuffer>, SharedBuffer),
+}
 
-            // Await all tasks and propagate errors
-            info!("Awaiting all image generation tasks...");
-            try_join_all(tasks).await?;
-            info!("All image generation tasks completed.");
+impl AnimationWriter {
+    fn new(
+        animation: Animation,
+        width: u32,
+        height: u32,
+        frames: u32,
+        fps: u16,
+    ) -> Result<AnimationWriter, RegenError> {
+        let buffer = SharedBuffer::default();
+        match animation {
+            Animation::Gif => {
+                let mut encoder = GifEncoder::new_with_speed(buffer.clone(), GIF_QUANTIZE_SPEED);
+                encoder.set_repeat(Repeat::Infinite)?;
+                let delay = Delay::from_numer_denom_ms(1000, fps as u32);
+                Ok(AnimationWriter::Gif(encoder, delay, buffer))
+            }
+            Animation::Apng => {
+                let mut encoder = png::Encoder::new(buffer.clone(), width, height);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder
+                    .set_animated(frames, 0)
+                    .map_err(std::io::Error::from)?;
+                encoder
+                    .set_frame_delay(1, fps)
+                    .map_err(std::io::Error::from)?;
+                let writer = encoder.write_header().map_err(std::io::Error::from)?;
+                Ok(AnimationWriter::Apng(writer, buffer))
+            }
         }
-        Commands::Upload => {
-            info!("Starting upload process...");
-            upload().await?;
-            info!("Upload process finished.");
+    }
+
+    fn add_frame(&mut self, frame: RgbImage) -> Result<(), RegenError> {
+        match self {
+            AnimationWriter::Gif(encoder, delay, _) => {
+                let frame = DynamicImage::ImageRgb8(frame).into_rgba8();
+                encoder.encode_frame(Frame::from_parts(frame, 0, 0, *delay))?;
+            }
+            AnimationWriter::Apng(writer, _) => writer
+                .write_image_data(frame.as_raw())
+                .map_err(std::io::Error::from)?,
         }
+        Ok(())
     }
 
-    info!("Program finished.");
-    Ok(())
+    /// Finishes the file and returns its bytes. An APNG fails here unless it got exactly the
+    /// number of frames it was created for.
+    fn finish(self) -> Result<Vec<u8>, RegenError> {
+        let buffer = match self {
+            AnimationWriter::Gif(encoder, _, buffer) => {
+                // The GIF trailer is written when the encoder is dropped
+                drop(encoder);
+                buffer
+            }
+            AnimationWriter::Apng(writer, buffer) => {
+                writer.finish().map_err(std::io::Error::from)?;
+                buffer
+            }
+        };
+        Ok(std::mem::take(
+            &mut *buffer.0.lock().unwrap_or_else(PoisonError::into_inner),
+        ))
+    }
 }
 
-pub async fn upload_folder_to_do_space(
-    local_folder_path: &Path,
-    bucket_name: &str,
-    do_region_name: &str,
-    space_folder_prefix: Option<&str>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // 1. Initialize S3 Client with DigitalOcean Endpoint
-    let endpoint = format!("https://{}.digitaloceanspaces.com", do_region_name);
-    let region = Region::Custom {
-        endpoint,
-        name: do_region_name.to_string(),
-    };
-    let s3_client = S3Client::new(region);
+/// One row of a `--jobs` file. Every column is optional and falls back to the command line
+/// or the pattern's defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GenerationJob {
+    pub filename: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub x_pos: Option<f64>,
+    pub y_pos: Option<f64>,
+    #[serde(alias = "escape_radius")]
+    pub view_scale: Option<f64>,
+    pub max_iterations: Option<u32>,
+    pub smoothness: Option<u32>,
+    pub color_step: Option<f64>,
+    pub pattern: Option<Pattern>,
+}
 
-    info!("Starting upload of folder: {}", local_folder_path.display());
-    info!("To Space: {} in region: {}", bucket_name, do_region_name);
+impl GenerationJob {
+    /// Fills in missing columns, returning the width, height and parameters to render with.
+    pub fn resolve(
+        &self,
+        pattern: Pattern,
+        fixed_width: Option<u32>,
+        fixed_height: Option<u32>,
+    ) -> (u32, u32, MandelbrotParams) {
+        let (x_pos, y_pos, view_scale, max_iterations, smoothness, color_step) =
+            default_params(pattern);
+        (
+            self.width.or(fixed_width).unwrap_or(DEFAULT_JOB_SIZE.0),
+            self.height.or(fixed_height).unwrap_or(DEFAULT_JOB_SIZE.1),
+            (
+                self.x_pos.unwrap_or(x_pos),
+                self.y_pos.unwrap_or(y_pos),
+                self.view_scale.unwrap_or(view_scale),
+                self.max_iterations.unwrap_or(max_iterations),
+                self.smoothness.unwrap_or(smoothness),
+                self.color_step.unwrap_or(color_step),
+            ),
+        )
+    }
+}
 
-    let mut upload_tasks = Vec::new();
+/// Reads a `--jobs` file: a JSON array of objects if it ends in `.json`, otherwise a CSV with
+/// a header row naming the columns.
+fn read_jobs(path: &Path) -> Result<Vec<GenerationJob>, Box<dyn std::error::Error + Send + Sync>> {
+    let jobs: Vec<GenerationJob> = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_reader(fs::File::open(path)?)?
+    } else {
+        ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?
+            .deserialize()
+            .collect::<Result<_, _>>()?
+    };
+    info!("Loaded {} jobs from {}", jobs.len(), path.display());
+    Ok(jobs)
+}
 
-    // 2. Traverse the local folder
-    for entry in WalkDir::new(local_folder_path)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        let path = entry.path().to_path_buf();
-        if path.is_file() {
-            // Get the relative path for the S3 key
-            let relative_path = path.strip_prefix(local_folder_path)?;
-            let mut s3_key_path = PathBuf::new();
+/// Escape radii for a zoom, interpolated geometrically so every frame zooms by the same factor.
+pub fn zoom_radii(start_radius: f64, end_radius: f64, frames: u32) -> Vec<f64> {
+    if frames == 1 {
+        return vec![start_radius];
+    }
+    let ratio = end_radius / start_radius;
+    (0..frames)
+        .map(|frame| start_radius * ratio.powf(frame as f64 / (frames - 1) as f64))
+        .collect()
+}
 
-            if let Some(prefix) = space_folder_prefix {
-                s3_key_path.push(prefix);
-            }
-            s3_key_path.push(relative_path);
+/// Private, ancillary, safe-to-copy chunk type that holds the random padding.
+pub const PADDING_CHUNK_TYPE: &[u8; 4] = b"prVt";
 
-            let s3_key = s3_key_path.to_string_lossy().replace("\\", "/"); // Ensure forward slashes
+/// The IEND chunk that terminates every PNG: zero length, type, and CRC.
+const PNG_IEND: [u8; 12] = [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82];
 
-            info!("- Preparing to upload: {} -> {}", path.display(), s3_key);
+/// Inserts a chunk just before the IEND chunk of an encoded PNG, keeping it valid.
+pub fn insert_png_chunk(
+    png: &mut Vec<u8>,
+    chunk_type: &[u8; 4],
+    data: &[u8],
+) -> Result<(), RegenError> {
+    let length = u32::try_from(data.len())
+        .ok()
+        .filter(|len| *len <= i32::MAX as u32)
+        .ok_or_else(|| RegenError::InvalidParams("PNG chunk data is too large".to_string()))?;
 
-            let file_data = fs::read(&path)?;
-            let client_clone = s3_client.clone();
-            let bucket_name_clone = bucket_name.to_string();
-            let path_clone = path.clone();
+    if !png.ends_with(&PNG_IEND) {
+        return Err(RegenError::InvalidParams(
+            "image does not end with a PNG IEND chunk".to_string(),
+        ));
+    }
 
-            // Create an async task for each file upload
-            let task = tokio::spawn(async move {
-                info!(
-                    "Uploading file {} to S3 key {}",
-                    path_clone.display(),
-                    s3_key
-                );
-                let mut put_request = PutObjectRequest {
-                    bucket: bucket_name_clone,
-                    key: s3_key.clone(),
-                    body: Some(file_data.into()),
-                    acl: Some("public-read".to_string()), // Make the object public
-                    ..Default::default()
-                };
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(chunk_type);
+    crc.update(data);
 
-                if let Some(extension) = path_clone.extension().and_then(|s| s.to_str()) {
-                    let mime_type = match extension.to_lowercase().as_str() {
-                        "png" => "image/png",
-                        "jpg" | "jpeg" => "image/jpeg",
-                        "gif" => "image/gif",
-                        "webp" => "image/webp",
-                        _ => "application/octet-stream", // Default to download if unknown
-                    };
-                    put_request.content_type = Some(mime_type.to_string());
-                }
+    // Replace IEND with the new chunk and put IEND back after it
+    png.truncate(png.len() - PNG_IEND.len());
+    png.extend_from_slice(&length.to_be_bytes());
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&crc.finalize().to_be_bytes());
+    png.extend_from_slice(&PNG_IEND);
+    Ok(())
+}
 
-                match client_clone.put_object(put_request).await {
-                    Ok(_) => {
-                        info!("  - Successfully uploaded: {}", s3_key);
-                        Ok(())
-                    }
-                    Err(e) => {
-                        error!("  - Failed to upload {}: {:?}", s3_key, e);
-                        Err(Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
-                    }
-                }
-            });
-            upload_tasks.push(task);
+/// Adds `noise` to an encoded PNG where `strategy` puts it. Works on the bytes about to be
+/// written, so the accepted image is never read back from disk.
+pub fn pad_png(png: &mut Vec<u8>, strategy: PadStrategy, noise: &[u8]) -> Result<(), RegenError> {
+    match strategy {
+        PadStrategy::Chunk => insert_png_chunk(png, PADDING_CHUNK_TYPE, noise),
+        PadStrategy::Append => {
+            png.extend_from_slice(noise);
+            Ok(())
         }
+        PadStrategy::None => Ok(()),
     }
+}
 
-    // 3. Wait for all upload tasks to complete
-    info!("Waiting for all upload tasks to complete...");
-    try_join_all(upload_tasks).await?;
+/// PNG signature every file starts with.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
 
-    info!("Folder upload complete!");
-    Ok(())
+/// Keyword of the iTXt chunk holding the generation parameters.
+pub const PARAMS_KEYWORD: &str = "regen-params";
+
+/// Stores `params` as JSON in an uncompressed iTXt chunk of an encoded PNG.
+pub fn embed_params(png: &mut Vec<u8>, params: &GenParams) -> Result<(), RegenError> {
+    let json = serde_json::to_string(params)
+        .map_err(|e| RegenError::InvalidParams(format!("could not encode parameters: {}", e)))?;
+    // keyword, NUL, compression flag and method, empty language tag and translated keyword
+    let mut data = PARAMS_KEYWORD.as_bytes().to_vec();
+    data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    data.extend_from_slice(json.as_bytes());
+    insert_png_chunk(png, b"iTXt", &data)
 }
 
-async fn upload() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Upload all files from the src/data/images folder
-    let test_folder = PathBuf::from("src/data/images");
-    if !test_folder.exists() {
-        warn!("No images to upload: src/data/images folder does not exist.");
-        return Ok(());
+/// Reads the parameters stored by `embed_params`, if the file is a PNG that has them.
+pub fn read_params(path: &Path) -> Option<GenParams> {
+    let bytes = fs::read(path).ok()?;
+    let mut chunks = bytes.strip_prefix(&PNG_SIGNATURE)?;
+    while chunks.len() >= 12 {
+        let length = u32::from_be_bytes(chunks[..4].try_into().ok()?) as usize;
+        let chunk_type = &chunks[4..8];
+        let data = chunks.get(8..8 + length)?;
+        if chunk_type == b"iTXt"
+            && let Some(text) = data
+                .strip_prefix(PARAMS_KEYWORD.as_bytes())
+                .and_then(|rest| rest.strip_prefix(&[0, 0, 0, 0, 0]))
+        {
+            return serde_json::from_slice(text).ok();
+        }
+        chunks = chunks.get(12 + length..)?;
     }
+    None
+}
 
-    // IMPORTANT: Replace with your actual DigitalOcean Space details
-    let bucket = "benchmarkap"; // e.g., "my-app-space"
-    let region = "lon1"; // e.g., "nyc3", "lon1", "fra1"
-    let space_prefix = Some("fractals/"); // Optional: upload into a specific folder within the Space
+/// How long `doctor` waits for a connection to the endpoint.
+const DOCTOR_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
-    info!(
-        "Uploading folder {} to DigitalOcean Space {}/{} with prefix {:?}",
-        test_folder.display(),
-        bucket,
-        region,
-        space_prefix
-    );
+/// Outcome of one `doctor` check, with what was found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    Pass(String),
+    Fail(String),
+    /// Not applicable to this setup
+    Skip(String),
+}
 
-    // Ensure your AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables are set.
-    match upload_folder_to_do_space(&test_folder, bucket, region, space_prefix).await {
-        Ok(_) => info!("\nFolder upload to DigitalOcean Spaces succeeded!"),
-        Err(e) => error!("\nFolder upload failed: {}", e),
+/// Runs every `doctor` check in order. Nothing is written: directories are judged by their
+/// metadata and the endpoint only gets a TCP connection.
+async fn doctor(target: &UploadTarget, output_dir: &Path) -> Vec<(&'static str, Check)> {
+    let mut checks = vec![
+        ("credentials", check_credentials(target).await),
+        ("output directory", check_writable(output_dir)),
+    ];
+    if let Some(dir) = target.local_dir() {
+        checks.push(("local directory", check_writable(dir)));
     }
-    // After upload, append URLs to a CSV file
-
-    // Path to your CSV file
-    let csv_path = PathBuf::from("src/data/urls.csv");
-    let csv_path = csv_path.as_path();
+    checks.push(("image viewer", check_viewer()));
+    checks.push(("endpoint", check_endpoint(target).await));
+    checks
+}
 
-    // Read all files in the uploaded folder
-    let mut urls = Vec::new();
-    for entry in WalkDir::new(&test_folder)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.path().is_file())
-    {
-        let rel_path = entry.path().strip_prefix(&test_folder)?;
-        let file_name = rel_path.to_string_lossy().replace("\\", "/");
-        let url = format!(
-            "https://{}.{}.cdn.digitaloceanspaces.com/{}{}",
-            bucket,
-            region,
-            space_prefix.unwrap_or(""),
-            file_name
-        );
-        info!("Generated CDN URL for file {}: {}", file_name, url);
-        urls.push((file_name, url));
+/// Resolves the keys from the selected source, which for `instance` means asking the
+/// metadata service.
+async fn check_credentials(target: &UploadTarget) -> Check {
+    if target.local_dir().is_some() {
+        return Check::Skip("not needed for --backend local".to_string());
     }
-
-    // Read existing CSV (if any)
-    let mut existing_rows = Vec::new();
-    if std::path::Path::new(csv_path).exists() {
-        info!("Reading existing CSV file: {}", csv_path.display());
-        let mut rdr = ReaderBuilder::new().has_headers(true).from_path(csv_path)?;
-        for result in rdr.records() {
-            let record = result?;
-            if record.len() == 4 {
-                existing_rows.push((
-                    record[0].to_string(),
-                    record[1].to_string(),
-                    record[2].to_string(),
-                    record[3].to_string(),
-                ));
-            } else if record.len() == 2 {
-                existing_rows.push((
-                    record[0].to_string(),
-                    record[1].to_string(),
-                    String::new(),
-                    String::new(),
-                ));
-            } else if record.len() == 1 {
-                existing_rows.push((
-                    record[0].to_string(),
-                    String::new(),
-                    String::new(),
-                    String::new(),
-                ));
-            }
+    let provider = match target.credentials.provider() {
+        Ok(provider) => provider,
+        Err(e) => return Check::Fail(e.to_string()),
+    };
+    match provider.credentials().await {
+        Ok(_) if target.credentials.source == CredentialSource::Keys => {
+            Check::Pass("access key and secret key are set".to_string())
         }
-        info!("Loaded {} existing rows from CSV.", existing_rows.len());
+        Ok(_) => Check::Pass(format!(
+            "keys loaded from --credentials {}",
+            target
+                .credentials
+                .source
+                .to_possible_value()
+                .map(|v| v.get_name().to_string())
+                .unwrap_or_default()
+        )),
+        Err(e) => Check::Fail(format!("could not load credentials: {}", e.message)),
     }
+}
 
-    // Append new URLs, avoiding duplicates
-    for (file, _cdn_url) in &urls {
-        let origin_url = format!(
-            "https://{}.{}.digitaloceanspaces.com/{}{}",
-            bucket,
-            region,
-            space_prefix.unwrap_or(""),
-            file
-        );
-        let cdn_url = format!(
-            "https://{}.{}.cdn.digitaloceanspaces.com/{}{}",
-            bucket,
-            region,
-            space_prefix.unwrap_or(""),
-            file
-        );
-        // File name
-        let file_name = Path::new(file)
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or(file);
-
-        // File size in KiB
-        let file_path = test_folder.join(file);
-        let file_size_kib = match fs::metadata(&file_path) {
-            Ok(meta) => format!("{:.2}", meta.len() as f64 / 1024.0),
-            Err(_) => {
-                warn!("Could not get metadata for file: {}", file_path.display());
-                String::from("")
-            }
-        };
-
-        if !existing_rows.iter().any(|(f, _, _, _)| f == file) {
-            info!(
-                "Appending new row to CSV: cdn_url={}, origin_url={}, file_name={}, file_size_kib={}",
-                cdn_url, origin_url, file_name, file_size_kib
-            );
-            existing_rows.push((cdn_url, origin_url, file_name.to_string(), file_size_kib));
-        } else {
-            info!("Skipping duplicate file in CSV: {}", file);
+/// Whether images could be written to `dir`, or to the closest existing parent it would be
+/// created in. Only read-only permissions are caught, since anything more needs a write.
+fn check_writable(dir: &Path) -> Check {
+    // A relative path with no existing parent would be created in the working directory
+    let existing = dir
+        .ancestors()
+        .find(|path| path.exists())
+        .unwrap_or(Path::new("."));
+    match fs::metadata(existing) {
+        Ok(meta) if !meta.is_dir() => {
+            Check::Fail(format!("{} is not a directory", existing.display()))
         }
+        Ok(meta) if meta.permissions().readonly() => {
+            Check::Fail(format!("{} is read-only", existing.display()))
+        }
+        Ok(_) if existing == dir => Check::Pass(format!("{} is writable", dir.display())),
+        Ok(_) => Check::Pass(format!(
+            "{} doesn't exist yet, it will be created in {}",
+            dir.display(),
+            existing.display()
+        )),
+        Err(e) => Check::Fail(format!("{}: {}", existing.display(), e)),
     }
+}
 
-    // Write back to CSV (cdn_url, origin_url columns)
-    if let Some(parent) = csv_path.parent() {
-        fs::create_dir_all(parent)?;
+/// Looks for the program `--preview` opens images with on the PATH.
+fn check_viewer() -> Check {
+    let Some(command) = viewer_command("") else {
+        return Check::Fail(format!(
+            "previewing images is not supported on {}",
+            std::env::consts::OS
+        ));
+    };
+    let program = command.get_program();
+    match find_in_path(program) {
+        Some(path) => Check::Pass(format!("found {}", path.display())),
+        None => Check::Fail(format!(
+            "{} is not on the PATH, --preview won't work",
+            program.to_string_lossy()
+        )),
     }
-    info!(
-        "Writing {} rows to CSV file: {}",
-        existing_rows.len(),
-        csv_path.display()
-    );
-    let mut wtr = WriterBuilder::new().has_headers(true).from_path(csv_path)?;
-    wtr.write_record(&["cdn_url", "origin_url", "file_name", "file_size_kib"])?;
-    for (cdn_url, origin_url, file_name, file_size_kib) in existing_rows {
-        wtr.write_record(&[cdn_url, origin_url, file_name, file_size_kib])?;
+}
+
+/// Finds an executable on the PATH like `which`, trying `.exe` too on Windows.
+fn find_in_path(program: &std::ffi::OsStr) -> Option<PathBuf> {
+    let names = [
+        PathBuf::from(program),
+        Path::new(program).with_extension("exe"),
+    ];
+    let names = if cfg!(windows) {
+        &names[..]
+    } else {
+        &names[..1]
+    };
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// Opens a TCP connection to the Space's endpoint, which needs DNS and the network to work
+/// but sends no request.
+async fn check_endpoint(target: &UploadTarget) -> Check {
+    if target.local_dir().is_some() {
+        return Check::Skip("not needed for --backend local".to_string());
+    }
+    let endpoint = match (&target.endpoint, &target.region) {
+        (Some(endpoint), _) => endpoint.clone(),
+        (None, Some(region)) => format!("https://{}.digitaloceanspaces.com", region),
+        (None, None) => return Check::Skip("no --region or --endpoint given".to_string()),
+    };
+    let Some((host, port)) = endpoint_address(&endpoint) else {
+        return Check::Fail(format!("can't tell the host of {}", endpoint));
+    };
+    let connect = tokio::net::TcpStream::connect((host.as_str(), port));
+    match tokio::time::timeout(DOCTOR_CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(_)) => Check::Pass(format!("connected to {}:{}", host, port)),
+        Ok(Err(e)) => Check::Fail(format!("can't connect to {}:{}: {}", host, port, e)),
+        Err(_) => Check::Fail(format!(
+            "no answer from {}:{} within {:?}",
+            host, port, DOCTOR_CONNECT_TIMEOUT
+        )),
     }
-    wtr.flush()?;
-    info!("CSV file write complete.");
+}
 
-    Ok(())
+/// Host and port of an endpoint URL such as `https://nyc3.digitaloceanspaces.com` or
+/// `http://localhost:9000`, with the scheme's default port when none is given.
+fn endpoint_address(endpoint: &str) -> Option<(String, u16)> {
+    let (default_port, rest) = match endpoint.split_once("://") {
+        Some(("http", rest)) => (80, rest),
+        Some(("https", rest)) => (443, rest),
+        Some(_) => return None,
+        None => (443, endpoint),
+    };
+    let authority = rest.split('/').next()?;
+    let (host, port) = match authority.rsplit_once(':') {
+        // A colon inside brackets belongs to an IPv6 address
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+/// Opens the given image file using the system's default image viewer.
+/// This function is OS-dependent.
+pub fn preview_image(image_path: &Path) -> Result<(), RegenError> {
+    let path_str = image_path
+        .to_str()
+        .ok_or_else(|| RegenError::PathConversion("Invalid path for preview".to_string()))?;
+    info!("Attempting to preview image: {}", image_path.display());
+
+    let mut command = viewer_command(path_str).ok_or_else(|| {
+        RegenError::Io(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "Previewing images is not supported on {}",
+                std::env::consts::OS
+            ),
+        ))
+    })?;
+    command.spawn()?;
+
+    info!("Previewing image at: {}", image_path.display());
+    Ok(())
+}
+
+/// Builds the command that opens `path` in the default viewer, if the OS has one.
+#[cfg(target_os = "macos")]
+fn viewer_command(path: &str) -> Option<Command> {
+    let mut command = Command::new("open");
+    command.arg(path);
+    Some(command)
+}
+
+#[cfg(target_os = "linux")]
+fn viewer_command(path: &str) -> Option<Command> {
+    let mut command = Command::new("xdg-open");
+    command.arg(path);
+    Some(command)
+}
+
+#[cfg(target_os = "windows")]
+fn viewer_command(path: &str) -> Option<Command> {
+    // `start` treats its first quoted argument as the window title, hence the empty string
+    let mut command = Command::new("cmd");
+    command.args(["/C", "start", "", path]);
+    Some(command)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+fn viewer_command(_path: &str) -> Option<Command> {
+    None
+}
+
+// Main function for testing purposes
+
+#[derive(clap::Parser)]
+#[clap(name = "FractalGen")]
+#[clap(about = "Generate and upload fractal images", long_about = None)]
+struct Cli {
+    /// Write Prometheus textfile metrics to this path after each batch
+    #[clap(long, global = true)]
+    metrics_file: Option<PathBuf>,
+
+    /// Print how long each image took to generate or upload
+    #[clap(long, global = true)]
+    timings: bool,
+
+    /// Log more: -v for info, -vv for debug, -vvv for trace
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log errors
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log as human-readable lines or as one JSON object per line
+    #[clap(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+// Parsed once per run, so the size of the largest subcommand doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(clap::Subcommand)]
+enum Commands {
+    /// Generate N fractal images
+    Generate {
+        /// Number of images to generate
+        #[clap(short, long, required_unless_present_any = ["jobs", "data_uri"])]
+        count: Option<usize>,
+
+        /// Render one small PNG and print it as a data:image/png;base64 URI instead of writing
+        /// files, e.g. to paste a preview into a chat or issue
+        #[clap(long, default_value_t = false, conflicts_with_all = ["count", "jobs", "and_upload", "format"])]
+        data_uri: bool,
+
+        /// CSV or JSON file listing exact parameters to render instead of random ones
+        #[clap(long)]
+        jobs: Option<PathBuf>,
+
+        /// Most images rendered at the same time [default: number of CPUs]
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+        gen_concurrency: Option<u32>,
+
+        /// Index of the first image, so a batch can be split across runs or machines
+        #[clap(long, default_value_t = 0)]
+        start_index: usize,
+
+        /// Image width in pixels (random between 3000 and 5000 if omitted)
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+        width: Option<u32>,
+
+        /// Image height in pixels (random between 2000 and 3500 if omitted)
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+        height: Option<u32>,
+
+        /// Directory the images are written to
+        #[clap(long, default_value = DEFAULT_OUTPUT_DIR)]
+        output_dir: PathBuf,
+
+        /// Pattern to render, see `regen list patterns`
+        #[clap(long, value_enum, default_value_t = Pattern::Mandelbrot)]
+        pattern: Pattern,
+
+        /// Constant c of `--pattern julia` as RE,IM, e.g. -0.4,0.6 [default: -0.8,0.156]
+        #[clap(long, allow_hyphen_values = true, value_parser = parse_complex)]
+        julia_c: Option<(f64, f64)>,
+
+        #[clap(short, long, default_value_t = false)]
+        preview: bool,
+
+        /// Seed for all random choices, making the output reproducible
+        #[clap(long)]
+        seed: Option<u64>,
+
+        /// Save RGBA PNGs with a transparent background instead of opaque white
+        #[clap(long, default_value_t = false)]
+        rgba: bool,
+
+        /// Sample pixels at their top-left corner, matching renders from older versions
+        #[clap(long, default_value_t = false)]
+        pixel_corner: bool,
+
+        /// Sync image files to disk after writing them (the default)
+        #[clap(long, overrides_with = "no_fsync")]
+        fsync: bool,
+
+        /// Skip syncing image files and rely on the OS page cache, for ephemeral workloads
+        #[clap(long, overrides_with = "fsync")]
+        no_fsync: bool,
+
+        /// Lowest accepted share of black (in-set) pixels, between 0 and 1
+        #[clap(long, default_value_t = 0.3, value_parser = parse_ratio)]
+        min_ratio: f64,
+
+        /// Highest accepted share of black (in-set) pixels, between 0 and 1
+        #[clap(long, default_value_t = 0.7, value_parser = parse_ratio)]
+        max_ratio: f64,
+
+        /// Give up on the fractal ratio band after this many renders and keep the closest one
+        #[clap(long, default_value_t = 25, value_parser = clap::value_parser!(u32).range(1..))]
+        max_attempts: u32,
+
+        /// Range of random padding bytes added to each image, as MIN..MAX
+        #[clap(long, default_value = "1000000..3000000", value_parser = parse_byte_range)]
+        pad_bytes: (usize, usize),
+
+        /// Where to put the random padding bytes
+        #[clap(long, value_enum, default_value_t = PadStrategy::Chunk)]
+        pad_strategy: PadStrategy,
+
+        /// Don't pad images with random bytes, same as `--pad-strategy none`
+        #[clap(long, default_value_t = false, conflicts_with = "pad_strategy")]
+        no_pad: bool,
+
+        /// Re-render mostly black images with doubled max_iterations (up to 10000)
+        #[clap(long, default_value_t = false)]
+        auto_iterations: bool,
+
+        /// Discard renders that are nearly a single color, even within the ratio band, and try
+        /// new parameters
+        #[clap(long, default_value_t = false)]
+        reject_degenerate: bool,
+
+        /// Print the complex-plane window of each image as JSON before rendering
+        #[clap(long, default_value_t = false)]
+        dump_view_bounds: bool,
+
+        /// Image file format; padding is only added to PNGs
+        #[clap(long, value_enum, default_value_t = OutputFormat::Png)]
+        format: OutputFormat,
+
+        /// JPEG quality, from 1 (smallest) to 100 (best)
+        #[clap(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: u8,
+
+        /// Color scheme for escape-time patterns, see `regen list palettes`
+        #[clap(long, value_enum, default_value_t = Palette::Grayscale)]
+        palette: Palette,
+
+        /// JSON list of color stops like {"position": 0.5, "rgb": [255, 128, 0]}, used instead
+        /// of --palette
+        #[clap(long)]
+        palette_file: Option<PathBuf>,
+
+        /// Turn the palette by this fraction of its length; colors pushed past the end wrap
+        /// around to the start
+        #[clap(long, default_value_t = 0.0, allow_hyphen_values = true, value_parser = parse_offset)]
+        color_offset: f64,
+
+        /// Turn each image's palette this much further than the previous image's, so a batch
+        /// steps through the colors in order
+        #[clap(long, default_value_t = 0.0, allow_hyphen_values = true, value_parser = parse_offset)]
+        color_offset_by_index: f64,
+
+        /// Average NxN samples per pixel to smooth fractal edges (renders N² times slower)
+        #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=16))]
+        supersample: u32,
+
+        /// How escaped points are shaded
+        #[clap(long, value_enum, default_value_t = Coloring::Escape)]
+        coloring: Coloring,
+
+        /// What `--coloring escape` does with values past the end of the palette
+        #[clap(long, value_enum, default_value_t = ColorClamp::Clamp)]
+        color_clamp: ColorClamp,
+
+        /// Trap shape for `--coloring orbittrap`
+        #[clap(long, value_enum, default_value_t = Trap::Point)]
+        trap: Trap,
+
+        /// Iterate z^d + c instead of z² + c, giving multibrot sets with d-1 fold symmetry
+        #[clap(long, default_value_t = DEFAULT_POWER, value_parser = clap::value_parser!(u32).range(2..))]
+        power: u32,
+
+        /// |z| at which an orbit counts as escaped; larger values smooth `--coloring escape` better
+        #[clap(long, default_value_t = DEFAULT_BAILOUT, value_parser = parse_bailout)]
+        bailout: f64,
+
+        /// Render in square tiles of this many pixels; only affects speed, not the image
+        #[clap(long, default_value_t = DEFAULT_TILE_SIZE, value_parser = clap::value_parser!(u32).range(1..))]
+        tile_size: u32,
+
+        /// Follow a high-precision reference orbit so views too deep for f64 stay sharp
+        /// (mandelbrot with power 2 and escape or histogram coloring only)
+        #[clap(long, default_value_t = false)]
+        deep_zoom: bool,
+
+        /// Shape of the view window: pixel (follow the image size), square, or a w:h ratio
+        #[clap(long, default_value_t = Aspect::Pixel, value_parser = parse_aspect)]
+        aspect: Aspect,
+
+        /// Also write a *_thumb image fitting within this many pixels on each side
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+        thumbnail: Option<u32>,
+
+        /// Real part of the view center; fixes the view instead of picking a random one
+        #[clap(long, allow_hyphen_values = true, conflicts_with = "jobs")]
+        center_x: Option<f64>,
+
+        /// Imaginary part of the view center; fixes the view instead of picking a random one
+        #[clap(long, allow_hyphen_values = true, conflicts_with = "jobs")]
+        center_y: Option<f64>,
+
+        /// Magnification of the view: 1 shows the whole set ([-2.5, 1] wide), 2 halves the width
+        #[clap(long, value_parser = parse_zoom, conflicts_with = "jobs")]
+        zoom: Option<f64>,
+
+        /// Name for generated images, with {pattern}, {index}, {width}, {height}, {seed} and
+        /// {ext} placeholders
+        #[clap(long, default_value = DEFAULT_FILENAME_TEMPLATE, value_parser = parse_filename_template)]
+        filename_template: String,
+
+        /// Text to draw in a corner of each image (ASCII; other characters show as ?)
+        #[clap(long, value_parser = NonEmptyStringValueParser::new())]
+        watermark: Option<String>,
+
+        /// Where --watermark goes
+        #[clap(long, value_enum, default_value_t, requires = "watermark")]
+        watermark_position: WatermarkPosition,
+
+        /// How strongly --watermark covers the image, from 0 (invisible) to 1 (solid)
+        #[clap(long, default_value_t = DEFAULT_WATERMARK_OPACITY, value_parser = parse_opacity, requires = "watermark")]
+        watermark_opacity: f64,
+
+        /// Upload each image as soon as it is written, overlapping rendering and uploading
+        #[clap(long, default_value_t = false)]
+        and_upload: bool,
+
+        #[clap(flatten, next_help_heading = "Upload (with --and-upload)")]
+        upload_target: UploadTarget,
+
+        #[clap(flatten, next_help_heading = "Upload (with --and-upload)")]
+        upload_object: ObjectSettings,
+
+        #[clap(flatten, next_help_heading = "Upload (with --and-upload)")]
+        public: PublicUpload,
+
+        /// Times to retry an upload that failed with a transient (network or 5xx) error
+        #[clap(long, default_value_t = DEFAULT_UPLOAD_RETRIES, requires = "and_upload", help_heading = "Upload (with --and-upload)")]
+        retries: u32,
+
+        /// Directory to write the URL manifest in [default: the directory holding --output-dir]
+        #[clap(
+            long,
+            requires = "and_upload",
+            help_heading = "Upload (with --and-upload)"
+        )]
+        manifest_dir: Option<PathBuf>,
+
+        /// Format of the URL manifest written after uploading
+        #[clap(long, value_enum, default_value_t = ManifestFormat::Csv, requires = "and_upload", help_heading = "Upload (with --and-upload)")]
+        manifest_format: ManifestFormat,
+
+        /// Order of the rows in the URL manifest; numbers in names and URLs sort by value
+        #[clap(long, value_enum, default_value_t = ManifestSort::Name, requires = "and_upload", help_heading = "Upload (with --and-upload)")]
+        sort_manifest: ManifestSort,
+
+        /// Fail on a URL CSV with an unexpected header or column count instead of padding rows
+        #[clap(
+            long,
+            default_value_t = false,
+            requires = "and_upload",
+            help_heading = "Upload (with --and-upload)"
+        )]
+        strict_csv: bool,
+    },
+    /// Render a sequence of frames zooming in on a point, for stitching into a video
+    Zoom {
+        /// Real part of the point to zoom in on
+        #[clap(long, allow_hyphen_values = true)]
+        center_x: f64,
+
+        /// Imaginary part of the point to zoom in on
+        #[clap(long, allow_hyphen_values = true)]
+        center_y: f64,
+
+        /// View scale of the first frame; the view is four times this wide
+        #[clap(long, default_value_t = 1.0)]
+        start_radius: f64,
+
+        /// View scale of the last frame
+        #[clap(long)]
+        end_radius: f64,
+
+        /// Number of frames to render
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+        frames: u32,
+
+        /// Frame width in pixels
+        #[clap(long, default_value_t = 1920, value_parser = clap::value_parser!(u32).range(1..))]
+        width: u32,
+
+        /// Frame height in pixels
+        #[clap(long, default_value_t = 1080, value_parser = clap::value_parser!(u32).range(1..))]
+        height: u32,
+
+        /// Max iterations for every frame; deep zooms need more
+        #[clap(long, default_value_t = 800, value_parser = clap::value_parser!(u32).range(1..))]
+        max_iterations: u32,
+
+        /// Directory the frames are written to
+        #[clap(long, default_value = DEFAULT_OUTPUT_DIR)]
+        output_dir: PathBuf,
+
+        /// Pattern to render, see `regen list patterns`
+        #[clap(long, value_enum, default_value_t = Pattern::Mandelbrot)]
+        pattern: Pattern,
+
+        /// Constant c of `--pattern julia` as RE,IM, e.g. -0.4,0.6 [default: -0.8,0.156]
+        #[clap(long, allow_hyphen_values = true, value_parser = parse_complex)]
+        julia_c: Option<(f64, f64)>,
+
+        /// Color scheme for escape-time patterns, see `regen list palettes`
+        #[clap(long, value_enum, default_value_t = Palette::Grayscale)]
+        palette: Palette,
+
+        /// JSON list of color stops like {"position": 0.5, "rgb": [255, 128, 0]}, used instead
+        /// of --palette
+        #[clap(long)]
+        palette_file: Option<PathBuf>,
+
+        /// Average NxN samples per pixel to smooth fractal edges
+        #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=16))]
+        supersample: u32,
+
+        /// How escaped points are shaded
+        #[clap(long, value_enum, default_value_t = Coloring::Escape)]
+        coloring: Coloring,
+
+        /// What `--coloring escape` does with values past the end of the palette
+        #[clap(long, value_enum, default_value_t = ColorClamp::Clamp)]
+        color_clamp: ColorClamp,
+
+        /// Trap shape for `--coloring orbittrap`
+        #[clap(long, value_enum, default_value_t = Trap::Point)]
+        trap: Trap,
+
+        /// Iterate z^d + c instead of z² + c, giving multibrot sets with d-1 fold symmetry
+        #[clap(long, default_value_t = DEFAULT_POWER, value_parser = clap::value_parser!(u32).range(2..))]
+        power: u32,
+
+        /// |z| at which an orbit counts as escaped; larger values smooth `--coloring escape` better
+        #[clap(long, default_value_t = DEFAULT_BAILOUT, value_parser = parse_bailout)]
+        bailout: f64,
+
+        /// Render in square tiles of this many pixels; only affects speed, not the image
+        #[clap(long, default_value_t = DEFAULT_TILE_SIZE, value_parser = clap::value_parser!(u32).range(1..))]
+        tile_size: u32,
+
+        /// Follow a high-precision reference orbit so views too deep for f64 stay sharp
+        /// (mandelbrot with power 2 and escape or histogram coloring only)
+        #[clap(long, default_value_t = false)]
+        deep_zoom: bool,
+
+        /// Shape of the view window: pixel (follow the image size), square, or a w:h ratio
+        #[clap(long, default_value_t = Aspect::Pixel, value_parser = parse_aspect)]
+        aspect: Aspect,
+
+        /// Assemble the frames into one animated file instead of writing each frame
+        #[clap(long, value_enum)]
+        animate: Option<Animation>,
+
+        /// Frames per second of the --animate file
+        #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u16).range(1..=100), requires = "animate")]
+        fps: u16,
+    },
+    /// Upload images to DigitalOcean Spaces
+    Upload {
+        #[clap(flatten)]
+        target: UploadTarget,
+
+        /// Directory containing the images to upload
+        #[clap(long, default_value = DEFAULT_OUTPUT_DIR)]
+        output_dir: PathBuf,
+
+        /// Upload every file directly under the prefix, dropping local subdirectories
+        #[clap(long, default_value_t = false)]
+        flatten: bool,
+
+        /// Only upload files with these extensions, e.g. png,jpg,webp [default: image files]
+        #[clap(long, value_delimiter = ',', value_parser = parse_extension)]
+        only_ext: Vec<String>,
+
+        /// Refuse to upload anything if a file is larger than this, e.g. 50MB
+        #[clap(long, value_parser = parse_size)]
+        max_file_size: Option<u64>,
+
+        /// Times to retry an upload that failed with a transient (network or 5xx) error
+        #[clap(long, default_value_t = DEFAULT_UPLOAD_RETRIES)]
+        retries: u32,
+
+        /// Print the planned object keys without uploading anything or writing the URL CSV
+        #[clap(long, default_value_t = false)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        object: ObjectSettings,
+
+        /// Directory to write the URL manifest in [default: the directory holding --output-dir]
+        #[clap(long)]
+        manifest_dir: Option<PathBuf>,
+
+        /// Format of the URL manifest written after uploading
+        #[clap(long, value_enum, default_value_t = ManifestFormat::Csv)]
+        manifest_format: ManifestFormat,
+
+        /// Order of the rows in the URL manifest; numbers in names and URLs sort by value
+        #[clap(long, value_enum, default_value_t = ManifestSort::Name)]
+        sort_manifest: ManifestSort,
+
+        /// Storage price per GB-month, to print an estimated monthly cost before uploading
+        #[clap(long, value_parser = parse_price)]
+        price_per_gb: Option<f64>,
+
+        /// Also write the URL manifest after every N successful uploads, not just at the end
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+        flush_every: Option<u64>,
+
+        /// Upload everything again instead of resuming an unfinished run
+        #[clap(long, default_value_t = false)]
+        restart: bool,
+
+        /// Fail on a URL CSV with an unexpected header or column count instead of padding rows
+        #[clap(long, default_value_t = false)]
+        strict_csv: bool,
+
+        #[clap(flatten)]
+        public: PublicUpload,
+    },
+    /// Check that every object in the URL manifest exists in the Space with the recorded size
+    Verify {
+        #[clap(flatten)]
+        target: UploadTarget,
+
+        /// Directory the URL manifest is in [default: the current directory, which holds the
+        /// default --output-dir]
+        #[clap(long)]
+        manifest_dir: Option<PathBuf>,
+
+        /// Format of the URL manifest to read
+        #[clap(long, value_enum, default_value_t = ManifestFormat::Csv)]
+        manifest_format: ManifestFormat,
+
+        /// Fail on a URL CSV with an unexpected header or column count instead of padding rows
+        #[clap(long, default_value_t = false)]
+        strict_csv: bool,
+    },
+    /// Print the parameters a PNG was generated with
+    Params {
+        /// Image to read
+        path: PathBuf,
+    },
+    /// Summarize the images in the output directory
+    Stats {
+        /// Directory to summarize
+        #[clap(long, default_value = DEFAULT_OUTPUT_DIR)]
+        output_dir: PathBuf,
+    },
+    /// Tile the images in the output directory into one contact sheet image
+    Sheet {
+        /// Image to write; the format follows the extension
+        path: PathBuf,
+
+        /// Directory to take the images from
+        #[clap(long, default_value = DEFAULT_OUTPUT_DIR)]
+        output_dir: PathBuf,
+
+        /// Tiles per row
+        #[clap(long, default_value_t = DEFAULT_SHEET_COLUMNS, value_parser = clap::value_parser!(u32).range(1..))]
+        columns: u32,
+
+        /// Side of each square tile in pixels; images are scaled down to fit
+        #[clap(long, default_value_t = DEFAULT_SHEET_TILE_SIZE, value_parser = clap::value_parser!(u32).range(16..))]
+        tile_size: u32,
+
+        /// Write each image's file name on its tile
+        #[clap(long, default_value_t = false)]
+        labels: bool,
+    },
+    /// Remove generated images from the output directory
+    Clean {
+        /// Directory to remove images from
+        #[clap(long, default_value = DEFAULT_OUTPUT_DIR)]
+        output_dir: PathBuf,
+
+        /// List the images that would be removed without deleting anything
+        #[clap(long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Don't ask for confirmation
+        #[clap(short, long, default_value_t = false)]
+        yes: bool,
+    },
+    /// List the names accepted by generation options
+    List {
+        #[clap(value_enum)]
+        what: ListKind,
+    },
+    /// Check credentials, the output directory, the image viewer and the endpoint without
+    /// changing anything
+    Doctor {
+        #[clap(flatten)]
+        target: UploadTarget,
+
+        /// Directory images are written to
+        #[clap(long, default_value = DEFAULT_OUTPUT_DIR)]
+        output_dir: PathBuf,
+    },
+}
+
+/// Where uploaded objects are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// A DigitalOcean Space, or another S3-compatible store with `--endpoint`
+    S3,
+    /// A local directory standing in for the Space, for trying out the pipeline without one
+    Local,
+}
+
+/// The Space that images are uploaded to.
+#[derive(Debug, Clone, PartialEq, clap::Args)]
+pub struct UploadTarget {
+    /// Where to upload to
+    #[clap(long, value_enum, env = "REGEN_BACKEND", default_value_t = Backend::S3)]
+    pub backend: Backend,
+
+    /// Name of the Space (bucket) to upload into; required for --backend s3
+    #[clap(long, env = "REGEN_BUCKET", value_parser = NonEmptyStringValueParser::new())]
+    pub bucket: Option<String>,
+
+    /// Region of the Space, e.g. nyc3, lon1, fra1; required for --backend s3
+    #[clap(long, env = "REGEN_REGION", value_parser = NonEmptyStringValueParser::new())]
+    pub region: Option<String>,
+
+    /// Directory to copy files into with `--backend local`; the prefix becomes a subdirectory
+    #[clap(long, env = "REGEN_LOCAL_DIR", required_if_eq("backend", "local"))]
+    pub local_dir: Option<PathBuf>,
+
+    /// Base of the manifest URLs with `--backend local`, instead of file:// URLs into the directory
+    #[clap(long, env = "REGEN_BASE_URL")]
+    pub base_url: Option<String>,
+
+    /// Template for CDN URLs in the manifest, with {bucket}, {region}, {prefix} and {file}
+    /// placeholders [default: https://{bucket}.{region}.cdn.digitaloceanspaces.com/{prefix}{file}]
+    #[clap(long, env = "REGEN_CDN_BASE_URL", value_parser = parse_url_template)]
+    pub cdn_base_url: Option<String>,
+
+    /// Template for origin URLs in the manifest, with the same placeholders as --cdn-base-url
+    /// [default: https://{bucket}.{region}.digitaloceanspaces.com/{prefix}{file}]
+    #[clap(long, env = "REGEN_ORIGIN_BASE_URL", value_parser = parse_url_template)]
+    pub origin_base_url: Option<String>,
+
+    /// Folder inside the Space to upload into, e.g. fractals/
+    #[clap(long, env = "REGEN_PREFIX")]
+    pub prefix: Option<String>,
+
+    /// S3-compatible endpoint to use instead of DigitalOcean Spaces, e.g. http://localhost:9000
+    #[clap(long, env = "REGEN_ENDPOINT")]
+    pub endpoint: Option<String>,
+
+    /// Use a --region that isn't in `regen list regions`, e.g. one opened after this release
+    #[clap(long, default_value_t = false)]
+    pub allow_unknown_region: bool,
+
+    #[clap(flatten)]
+    pub credentials: Credentials,
+}
+
+/// DigitalOcean regions with Spaces, and where they are. The region is part of the endpoint's
+/// host name, so anything else fails with a DNS error on the first request.
+const SPACES_REGIONS: [(&str, &str); 11] = [
+    ("ams3", "Amsterdam"),
+    ("atl1", "Atlanta"),
+    ("blr1", "Bangalore"),
+    ("fra1", "Frankfurt"),
+    ("lon1", "London"),
+    ("nyc3", "New York"),
+    ("sfo2", "San Francisco"),
+    ("sfo3", "San Francisco"),
+    ("sgp1", "Singapore"),
+    ("syd1", "Sydney"),
+    ("tor1", "Toronto"),
+];
+
+/// Rejects a region that isn't in `SPACES_REGIONS`, suggesting the closest one when it looks
+/// like a typo.
+fn check_region(region: &str) -> Result<(), RegenError> {
+    if SPACES_REGIONS.iter().any(|(name, _)| *name == region) {
+        return Ok(());
+    }
+    let lowercase = region.to_lowercase();
+    let suggestion = SPACES_REGIONS
+        .iter()
+        .map(|(name, _)| (strsim::levenshtein(&lowercase, name), name))
+        .min()
+        .filter(|(distance, _)| *distance <= 2)
+        .map(|(_, name)| format!(", did you mean '{}'?", name))
+        .unwrap_or_default();
+    Err(RegenError::InvalidParams(format!(
+        "unknown Spaces region '{}'{} See `regen list regions`, or pass --allow-unknown-region",
+        region,
+        if suggestion.is_empty() {
+            "."
+        } else {
+            &suggestion
+        }
+    )))
+}
+
+/// Where the keys that sign requests come from, selected with `--credentials`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CredentialSource {
+    /// --access-key and --secret-key, or AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    #[default]
+    Keys,
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN, read on each request
+    Env,
+    /// ~/.aws/credentials, using AWS_PROFILE and AWS_SHARED_CREDENTIALS_FILE if set
+    Profile,
+    /// The instance metadata service of the VM this runs on
+    Instance,
+}
+
+/// Spaces access key pair used to sign requests.
+#[derive(Clone, PartialEq, clap::Args)]
+pub struct Credentials {
+    /// Where to get the keys that sign requests: keys, env, profile or instance
+    #[clap(
+        long = "credentials",
+        id = "credentials",
+        env = "REGEN_CREDENTIALS",
+        value_enum,
+        default_value_t
+    )]
+    pub source: CredentialSource,
+
+    /// Spaces access key
+    #[clap(long, env = "AWS_ACCESS_KEY_ID", hide_env_values = true)]
+    pub access_key: Option<String>,
+
+    /// Spaces secret key
+    #[clap(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub secret_key: Option<String>,
+}
+
+/// Credentials provider for each `CredentialSource`, so one client type serves them all.
+pub enum CredentialProvider {
+    Static(StaticProvider),
+    Environment(EnvironmentProvider),
+    Profile(ProfileProvider),
+    /// Cached until shortly before the instance's temporary keys expire
+    Instance(AutoRefreshingProvider<InstanceMetadataProvider>),
+}
+
+#[async_trait::async_trait]
+impl ProvideAwsCredentials for CredentialProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        match self {
+            CredentialProvider::Static(provider) => provider.credentials().await,
+            CredentialProvider::Environment(provider) => provider.credentials().await,
+            CredentialProvider::Profile(provider) => provider.credentials().await,
+            CredentialProvider::Instance(provider) => provider.credentials().await,
+        }
+    }
+}
+
+impl Credentials {
+    /// Returns a provider for the selected source. With `keys` that's `MissingCredentials` if
+    /// either half of the pair is unset or empty; the other sources are only asked for keys
+    /// when a request is signed.
+    pub fn provider(&self) -> Result<CredentialProvider, RegenError> {
+        let failed = |e: CredentialsError| RegenError::Credentials(e.message);
+        Ok(match self.source {
+            CredentialSource::Keys => {
+                match (self.access_key.as_deref(), self.secret_key.as_deref()) {
+                    (Some(access_key), Some(secret_key))
+                        if !access_key.is_empty() && !secret_key.is_empty() =>
+                    {
+                        CredentialProvider::Static(StaticProvider::new_minimal(
+                            access_key.to_string(),
+                            secret_key.to_string(),
+                        ))
+                    }
+                    _ => return Err(RegenError::MissingCredentials),
+                }
+            }
+            CredentialSource::Env => {
+                CredentialProvider::Environment(EnvironmentProvider::default())
+            }
+            CredentialSource::Profile => {
+                CredentialProvider::Profile(ProfileProvider::new().map_err(failed)?)
+            }
+            CredentialSource::Instance => CredentialProvider::Instance(
+                AutoRefreshingProvider::new(InstanceMetadataProvider::new()).map_err(failed)?,
+            ),
+        })
+    }
+}
+
+// Written by hand so the secret key never ends up in logs
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("source", &self.source)
+            .field("access_key", &self.access_key)
+            .field(
+                "secret_key",
+                &self.secret_key.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl UploadTarget {
+    /// Checks the options clap can't: the Space has to be named for the s3 backend, and a
+    /// `--local-dir` without `--backend local` would otherwise be silently ignored.
+    pub fn validate(&self) -> Result<(), RegenError> {
+        if self.backend == Backend::S3 && (self.bucket.is_none() || self.region.is_none()) {
+            return Err(RegenError::InvalidParams(
+                "--bucket and --region are required for --backend s3".to_string(),
+            ));
+        }
+        if self.backend == Backend::S3 && self.local_dir.is_some() {
+            return Err(RegenError::InvalidParams(
+                "--local-dir needs --backend local".to_string(),
+            ));
+        }
+        // Other S3-compatible services have their own region names
+        if self.backend == Backend::S3 && self.endpoint.is_none() && !self.allow_unknown_region {
+            check_region(self.region())?;
+        }
+        Ok(())
+    }
+
+    /// The directory objects are copied into, for the local backend.
+    pub fn local_dir(&self) -> Option<&Path> {
+        match self.backend {
+            Backend::Local => self.local_dir.as_deref(),
+            Backend::S3 => None,
+        }
+    }
+
+    /// Name of the Space. `validate` requires it for the S3 backend.
+    pub fn bucket(&self) -> &str {
+        self.bucket.as_deref().unwrap_or_default()
+    }
+
+    /// Region of the Space. Clap requires it unless `--local-dir` is given.
+    pub fn region(&self) -> &str {
+        self.region.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the prefix with a trailing slash (or empty), ready to be joined with a file name.
+    pub fn key_prefix(&self) -> String {
+        match self.prefix.as_deref().map(|p| p.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{}/", prefix),
+            _ => String::new(),
+        }
+    }
+
+    /// Public URL of the object stored under `key`, from `--cdn-base-url` or
+    /// `--origin-base-url` if given. Otherwise Spaces objects are addressed through the bucket
+    /// subdomain, on the CDN or the origin, and custom endpoints have no CDN and are addressed
+    /// path-style. Local objects are under `--base-url`, or file:// URLs.
+    pub fn object_url(&self, key: &str, cdn: bool) -> String {
+        if let Some(dir) = self.local_dir() {
+            let base = match &self.base_url {
+                Some(base_url) => base_url.clone(),
+                None => format!("file://{}", absolute_path(dir).display()),
+            };
+            return format!("{}/{}", base.trim_end_matches('/'), key);
+        }
+        let template = if cdn {
+            self.cdn_base_url.as_deref()
+        } else {
+            self.origin_base_url.as_deref()
+        };
+        let template = match (template, &self.endpoint) {
+            (Some(template), _) => template,
+            (None, Some(endpoint)) => {
+                return format!(
+                    "{}/{}/{}",
+                    endpoint.trim_end_matches('/'),
+                    self.bucket(),
+                    key
+                );
+            }
+            (None, None) if cdn => DEFAULT_CDN_URL_TEMPLATE,
+            (None, None) => DEFAULT_ORIGIN_URL_TEMPLATE,
+        };
+        // Keys are built as prefix + file name, so the prefix can be split back off
+        let prefix = self.key_prefix();
+        let (prefix, file) = match key.strip_prefix(&prefix) {
+            Some(file) => (prefix.as_str(), file),
+            None => ("", key),
+        };
+        render_url_template(template, self.bucket(), self.region(), prefix, file)
+    }
+}
+
+/// CDN URL of a Spaces object, used unless `--cdn-base-url` is given.
+const DEFAULT_CDN_URL_TEMPLATE: &str =
+    "https://{bucket}.{region}.cdn.digitaloceanspaces.com/{prefix}{file}";
+/// Origin URL of a Spaces object, used unless `--origin-base-url` is given.
+const DEFAULT_ORIGIN_URL_TEMPLATE: &str =
+    "https://{bucket}.{region}.digitaloceanspaces.com/{prefix}{file}";
+
+/// Fills in the placeholders of a URL template. `prefix` is empty or ends in a slash.
+pub fn render_url_template(
+    template: &str,
+    bucket: &str,
+    region: &str,
+    prefix: &str,
+    file: &str,
+) -> String {
+    template
+        .replace("{bucket}", bucket)
+        .replace("{region}", region)
+        .replace("{prefix}", prefix)
+        .replace("{file}", file)
+}
+
+/// Names generated images unless `--filename-template` is given.
+const DEFAULT_FILENAME_TEMPLATE: &str = "{pattern}_{index}.{ext}";
+/// Image size of `generate --data-uri` without --width/--height: small enough for the URI to
+/// paste anywhere.
+const DATA_URI_SIZE: (u32, u32) = (320, 240);
+/// Placeholders a `--filename-template` can use.
+const FILENAME_PLACEHOLDERS: [&str; 6] = [
+    "{pattern}",
+    "{index}",
+    "{width}",
+    "{height}",
+    "{seed}",
+    "{ext}",
+];
+
+/// Fills in the placeholders of a filename template. `{seed}` is empty without a seed.
+pub fn render_filename_template(
+    template: &str,
+    pattern: Pattern,
+    index: usize,
+    width: u32,
+    height: u32,
+    seed: Option<u64>,
+    extension: &str,
+) -> String {
+    template
+        .replace("{pattern}", &pattern.to_string())
+        .replace("{index}", &index.to_string())
+        .replace("{width}", &width.to_string())
+        .replace("{height}", &height.to_string())
+        .replace("{seed}", &seed.map(|s| s.to_string()).unwrap_or_default())
+        .replace("{ext}", extension)
+}
+
+/// Accepts a filename template that gives every image of a run its own plain file name: it
+/// needs `{index}`, kept apart from the other numbers so `{index}{width}` can't render 1 and
+/// 23 the same as 12 and 3, and no path separators or characters Windows rejects.
+fn parse_filename_template(value: &str) -> Result<String, String> {
+    if !value.contains("{index}") {
+        return Err(format!(
+            "filename template needs an {{index}} placeholder, got '{}'",
+            value
+        ));
+    }
+    for number in ["{width}", "{height}", "{seed}"] {
+        if value.contains(&format!("{}{{index}}", number))
+            || value.contains(&format!("{{index}}{}", number))
+        {
+            return Err(format!(
+                "filename template needs a separator between {{index}} and {}",
+                number
+            ));
+        }
+    }
+    let literal = FILENAME_PLACEHOLDERS
+        .iter()
+        .fold(value.to_string(), |rest, placeholder| {
+            rest.replace(placeholder, "")
+        });
+    if literal.contains(['{', '}']) {
+        return Err(format!(
+            "unknown placeholder in filename template '{}', expected {}",
+            value,
+            FILENAME_PLACEHOLDERS.join(", ")
+        ));
+    }
+    if literal.contains(['/', '\\', '<', '>', ':', '"', '|', '?', '*'])
+        || literal.chars().any(char::is_control)
+    {
+        return Err(format!(
+            "filename template must be a plain file name, got '{}'",
+            value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Accepts a URL template only if it has a `{file}` placeholder, since without one every
+/// object would get the same URL.
+fn parse_url_template(value: &str) -> Result<String, String> {
+    if !value.contains("{file}") {
+        return Err(format!(
+            "URL template needs a {{file}} placeholder, got '{}'",
+            value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Creates a client for the Space's region (or the overridden endpoint) that signs requests
+/// with `credentials`.
+pub fn s3_client(
+    region_name: &str,
+    endpoint_override: Option<&str>,
+    credentials: CredentialProvider,
+) -> S3Client {
+    let dispatcher = HttpClient::new().expect("failed to create request dispatcher");
+    S3Client::new_with(
+        dispatcher,
+        credentials,
+        s3_region(region_name, endpoint_override),
+    )
+}
+
+/// When a public upload goes ahead without asking.
+#[derive(Debug, Clone, PartialEq, clap::Args)]
+pub struct PublicUpload {
+    /// Spaces that public uploads go to without asking for confirmation
+    #[clap(long, env = "REGEN_PUBLIC_BUCKETS", value_delimiter = ',')]
+    pub public_bucket: Vec<String>,
+
+    /// Don't ask for confirmation before a public upload
+    #[clap(short, long, default_value_t = false)]
+    pub yes: bool,
+}
+
+impl PublicUpload {
+    /// Asks before uploading `what` with a public ACL, unless `--yes` was given or the Space is
+    /// pre-approved. public-read is the default, so a run pointed at the wrong Space would
+    /// otherwise publish whatever it uploads. Without a terminal to ask on, that's an error.
+    pub fn confirm(
+        &self,
+        target: &UploadTarget,
+        object: &ObjectSettings,
+        what: &str,
+    ) -> Result<bool, RegenError> {
+        let approved = self.public_bucket.iter().any(|b| b == target.bucket());
+        if self.yes || approved || target.backend != Backend::S3 || !object.acl.is_public() {
+            return Ok(true);
+        }
+        let summary = format!("{} to {} as {}", what, target.bucket(), object.acl.as_str());
+        if !std::io::stdin().is_terminal() {
+            return Err(RegenError::InvalidParams(format!(
+                "not uploading {} without confirmation; pass --yes when not running interactively",
+                summary
+            )));
+        }
+        confirm(&format!("Upload {}?", summary))
+    }
+}
+
+/// Settings applied to every uploaded object.
+#[derive(Debug, Clone, PartialEq, clap::Args)]
+pub struct ObjectSettings {
+    /// Canned ACL for uploaded objects
+    #[clap(long, value_enum, default_value_t = ObjectAcl::PublicRead)]
+    pub acl: ObjectAcl,
+
+    /// Cache-Control header for uploaded objects
+    #[clap(long, default_value = DEFAULT_CACHE_CONTROL)]
+    pub cache_control: String,
+
+    /// Don't set a Cache-Control header, leaving caching to the Space and CDN defaults
+    #[clap(long, default_value_t = false, conflicts_with = "cache_control")]
+    pub no_cache_control: bool,
+
+    /// Content-Disposition header for uploaded objects, e.g. inline
+    #[clap(long)]
+    pub content_disposition: Option<String>,
+
+    /// Gzip text-like objects such as SVG and JSON and upload them with Content-Encoding: gzip
+    #[clap(long, default_value_t = false)]
+    pub compress: bool,
+
+    /// Replace objects that already exist at their key (the default)
+    #[clap(long, default_value_t = false, conflicts_with = "skip_existing")]
+    pub overwrite: bool,
+
+    /// Check each key first and leave objects that already exist alone
+    #[clap(long, default_value_t = false)]
+    pub skip_existing: bool,
+
+    /// With --skip-existing, only skip objects whose stored size matches the local file
+    #[clap(long, default_value_t = false, requires = "skip_existing")]
+    pub match_size: bool,
+
+    /// Tag uploaded objects with key=value, e.g. for cost allocation or lifecycle rules; repeatable
+    #[clap(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Upload objects larger than this in parts, each retried on its own, e.g. 64MB
+    #[clap(long, default_value = DEFAULT_MULTIPART_THRESHOLD, value_parser = parse_size)]
+    pub multipart_threshold: u64,
+}
+
+impl ObjectSettings {
+    /// The Cache-Control header to send, if any.
+    pub fn cache_control(&self) -> Option<&str> {
+        (!self.no_cache_control).then_some(self.cache_control.as_str())
+    }
+
+    /// The tag set for the `x-amz-tagging` header, URL-encoded like a query string, if any
+    /// tags were given.
+    pub fn tagging(&self) -> Option<String> {
+        let encode = |s: &str| utf8_percent_encode(s, TAG_ENCODE_SET).to_string();
+        (!self.tags.is_empty()).then(|| {
+            self.tags
+                .iter()
+                .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+                .collect::<Vec<_>>()
+                .join("&")
+        })
+    }
+}
+
+/// Most tags S3 accepts on one object.
+const MAX_OBJECT_TAGS: usize = 10;
+
+/// Everything but the unreserved URL characters is escaped in tag keys and values.
+const TAG_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Parses a `--tag` as `KEY=VALUE`, within S3's limits of 128 characters for keys and 256
+/// for values.
+fn parse_tag(value: &str) -> Result<(String, String), String> {
+    let (key, tag_value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", value))?;
+    if key.is_empty() || tag_value.is_empty() {
+        return Err(format!(
+            "tag key and value must not be empty, got '{}'",
+            value
+        ));
+    }
+    if key.chars().count() > 128 || tag_value.chars().count() > 256 {
+        return Err(format!(
+            "tag keys are limited to 128 characters and values to 256, got '{}'",
+            value
+        ));
+    }
+    Ok((key.to_string(), tag_value.to_string()))
+}
+
+/// S3 canned ACLs accepted by `--acl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ObjectAcl {
+    Private,
+    PublicRead,
+    PublicReadWrite,
+    AuthenticatedRead,
+    BucketOwnerRead,
+    BucketOwnerFullControl,
+}
+
+impl ObjectAcl {
+    /// Value sent in the `x-amz-acl` header.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ObjectAcl::Private => "private",
+            ObjectAcl::PublicRead => "public-read",
+            ObjectAcl::PublicReadWrite => "public-read-write",
+            ObjectAcl::AuthenticatedRead => "authenticated-read",
+            ObjectAcl::BucketOwnerRead => "bucket-owner-read",
+            ObjectAcl::BucketOwnerFullControl => "bucket-owner-full-control",
+        }
+    }
+
+    /// Whether anonymous readers can fetch the object, i.e. whether its URLs are usable.
+    pub fn is_public(self) -> bool {
+        matches!(self, ObjectAcl::PublicRead | ObjectAcl::PublicReadWrite)
+    }
+}
+
+/// Builds the rusoto region for a Space, using `endpoint_override` verbatim when given.
+pub fn s3_region(region_name: &str, endpoint_override: Option<&str>) -> Region {
+    let endpoint = match endpoint_override {
+        Some(endpoint) => endpoint.to_string(),
+        None => format!("https://{}.digitaloceanspaces.com", region_name),
+    };
+    Region::Custom {
+        endpoint,
+        name: region_name.to_string(),
+    }
+}
+
+/// Where the random padding goes, selected with `--pad-strategy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PadStrategy {
+    /// In a private PNG chunk, so the file stays a valid PNG
+    Chunk,
+    /// Raw bytes after the IEND chunk, as older versions did; strict decoders reject the file
+    Append,
+    /// No padding, keeping files small
+    None,
+}
+
+/// Encoding used for generated images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    /// Rectangles per color band, for print; only practical at modest sizes
+    Svg,
+}
+
+impl OutputFormat {
+    /// File extension for images in this format, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Svg => "svg",
+        }
+    }
+}
+
+/// Animated file `zoom --animate` assembles the frames into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Animation {
+    /// Animated GIF, reduced to 256 colors per frame
+    Gif,
+    /// Animated PNG, lossless but larger
+    Apng,
+}
+
+impl Animation {
+    /// Name of the animation in the output directory.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Animation::Gif => "zoom.gif",
+            Animation::Apng => "zoom.png",
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ListKind {
+    /// Fractal and noise patterns
+    Patterns,
+    /// Color schemes for escape-time patterns
+    Palettes,
+    /// DigitalOcean regions with Spaces, for --region
+    Regions,
+}
+
+/// Prints each value of a `--flag` enum with the description from its doc comment.
+fn print_choices<T: ValueEnum>() {
+    for choice in T::value_variants() {
+        if let Some(value) = choice.to_possible_value() {
+            let description = value.get_help().map(ToString::to_string);
+            println!(
+                "{:<12} {}",
+                value.get_name(),
+                description.unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// Parses a fractal ratio, which must lie within 0.0..=1.0.
+fn parse_ratio(value: &str) -> Result<f64, String> {
+    let ratio = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid ratio '{}': {}", value, e))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("ratio must be between 0 and 1, got {}", ratio));
+    }
+    Ok(ratio)
+}
+
+fn parse_opacity(value: &str) -> Result<f64, String> {
+    let opacity = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid opacity '{}': {}", value, e))?;
+    if !(0.0..=1.0).contains(&opacity) {
+        return Err(format!("opacity must be between 0 and 1, got {}", value));
+    }
+    Ok(opacity)
+}
+
+fn parse_offset(value: &str) -> Result<f64, String> {
+    let offset = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid offset '{}': {}", value, e))?;
+    if !offset.is_finite() {
+        return Err(format!("offset must be a finite number, got {}", value));
+    }
+    Ok(offset)
+}
+
+fn parse_complex(value: &str) -> Result<(f64, f64), String> {
+    let parse = |part: &str| {
+        part.trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| format!("invalid complex number '{}', expected RE,IM", value))
+    };
+    match value.split_once(',') {
+        Some((re, im)) => Ok((parse(re)?, parse(im)?)),
+        None => Err(format!(
+            "invalid complex number '{}', expected RE,IM",
+            value
+        )),
+    }
+}
+
+fn parse_price(value: &str) -> Result<f64, String> {
+    let price = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid price '{}': {}", value, e))?;
+    if !(price.is_finite() && price >= 0.0) {
+        return Err(format!("price must be zero or more, got {}", value));
+    }
+    Ok(price)
+}
+
+fn parse_aspect(value: &str) -> Result<Aspect, String> {
+    value.parse()
+}
+
+fn parse_zoom(value: &str) -> Result<f64, String> {
+    let zoom = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid zoom '{}': {}", value, e))?;
+    if !(zoom.is_finite() && zoom > 0.0) {
+        return Err(format!("zoom must be positive, got {}", value));
+    }
+    Ok(zoom)
+}
+
+fn parse_bailout(value: &str) -> Result<f64, String> {
+    let bailout = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid bailout '{}': {}", value, e))?;
+    // Below 2 points outside the set would never be seen to escape
+    if !(bailout.is_finite() && bailout >= 2.0) {
+        return Err(format!("bailout must be at least 2, got {}", value));
+    }
+    Ok(bailout)
+}
+
+/// Parses an inclusive `MIN..MAX` byte range such as `1000000..3000000`.
+fn parse_byte_range(value: &str) -> Result<(usize, usize), String> {
+    let (min, max) = value
+        .split_once("..")
+        .ok_or_else(|| format!("expected MIN..MAX, got '{}'", value))?;
+    let parse = |bound: &str| {
+        bound
+            .trim()
+            .replace('_', "")
+            .parse::<usize>()
+            .map_err(|e| format!("invalid byte count '{}': {}", bound, e))
+    };
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(format!(
+            "MIN ({}) must not be greater than MAX ({})",
+            min, max
+        ));
+    }
+    Ok((min, max))
+}
+
+#[tokio::main]
+async fn main() {
+    // Printed with Display rather than main's default Debug, so errors read as messages
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Cli::parse();
+
+    // -v/-q override RUST_LOG, which otherwise applies as usual on top of the warn default
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    if let Some(level) = log_level(cli.verbose, cli.quiet) {
+        logger.filter_level(level);
+    }
+    if cli.log_format == LogFormat::Json {
+        logger.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    logger.init();
+    info!("Logger initialized.");
+    let metrics_file = cli.metrics_file;
+    let timings = cli.timings;
+    let shutdown = cancel_on_ctrl_c();
+
+    match cli.command {
+        Commands::Generate {
+            count,
+            data_uri,
+            start_index,
+            width: fixed_width,
+            height: fixed_height,
+            output_dir,
+            pattern,
+            julia_c,
+            preview,
+            seed,
+            rgba,
+            pixel_corner,
+            fsync: _,
+            no_fsync,
+            min_ratio,
+            max_ratio,
+            max_attempts,
+            pad_bytes,
+            pad_strategy,
+            no_pad,
+            auto_iterations,
+            reject_degenerate,
+            dump_view_bounds: dump_bounds,
+            format,
+            quality,
+            palette,
+            palette_file,
+            color_offset,
+            color_offset_by_index,
+            supersample,
+            jobs,
+            coloring,
+            color_clamp,
+            trap,
+            thumbnail,
+            power,
+            bailout,
+            tile_size,
+            deep_zoom,
+            aspect,
+            center_x,
+            center_y,
+            zoom,
+            gen_concurrency,
+            filename_template,
+            watermark,
+            watermark_position,
+            watermark_opacity,
+            and_upload,
+            upload_target,
+            upload_object,
+            public,
+            retries,
+            manifest_dir,
+            manifest_format,
+            sort_manifest,
+            strict_csv,
+        } => {
+            let fsync = !no_fsync;
+            let (palette, palette_stops) = resolve_palette(palette, palette_file.as_deref())?;
+            let palette_stops = Arc::new(palette_stops);
+            // --center-x/--center-y/--zoom pin the view window, any one of them is enough
+            let fixed_view =
+                (center_x.is_some() || center_y.is_some() || zoom.is_some()).then(|| {
+                    (
+                        center_x.unwrap_or(CLASSIC_VIEW_CENTER.0),
+                        center_y.unwrap_or(CLASSIC_VIEW_CENTER.1),
+                        zoom_view_scale(zoom.unwrap_or(1.0)),
+                    )
+                });
+            if data_uri {
+                if deep_zoom {
+                    check_deep_zoom(pattern, power, coloring)?;
+                }
+                // The pattern's default view unless one is pinned; there's no ratio band to
+                // search, since only one image is rendered
+                let (x_pos, y_pos, view_scale, max_iterations, smoothness, color_step) =
+                    default_params(pattern);
+                let (x_pos, y_pos, view_scale) = fixed_view.unwrap_or((x_pos, y_pos, view_scale));
+                let params = GenParams {
+                    pattern,
+                    width: fixed_width.unwrap_or(DATA_URI_SIZE.0),
+                    height: fixed_height.unwrap_or(DATA_URI_SIZE.1),
+                    x_pos,
+                    y_pos,
+                    view_scale,
+                    max_iterations,
+                    smoothness,
+                    color_step,
+                    palette,
+                    palette_stops: palette_stops.to_vec(),
+                    palette_offset: palette_offset(
+                        color_offset,
+                        color_offset_by_index,
+                        start_index,
+                    ),
+                    coloring,
+                    color_clamp,
+                    trap,
+                    supersample,
+                    julia_constant: julia_c,
+                    power,
+                    bailout,
+                    seed,
+                    index: start_index,
+                    deep_zoom,
+                    aspect,
+                    rgba,
+                    pixel_center: !pixel_corner,
+                    tile_size,
+                };
+                let (mut img, _) = render_with_stats(&params)?;
+                if let Some(text) = &watermark {
+                    draw_watermark(&mut img, text, watermark_position, watermark_opacity);
+                }
+                // Never padded: the point is a URI short enough to paste
+                let bytes = encode_generated(&img, &params, OutputFormat::Png, quality)?;
+                println!("data:image/png;base64,{}", base64::encode(bytes));
+                return Ok(());
+            }
+            let jobs = jobs.map(|path| read_jobs(&path)).transpose()?;
+            // A jobs file renders each of its rows once, whatever the count
+            let count = match &jobs {
+                Some(jobs) => jobs.len(),
+                None => count.unwrap_or_default(),
+            };
+            if min_ratio >= max_ratio {
+                return Err(RegenError::InvalidParams(format!(
+                    "--min-ratio ({}) must be less than --max-ratio ({})",
+                    min_ratio, max_ratio
+                ))
+                .into());
+            }
+            let target_ratio = (min_ratio + max_ratio) / 2.0;
+            if deep_zoom {
+                // Jobs can switch the pattern, so each of them has to allow it too
+                let job_patterns = jobs.iter().flatten().filter_map(|job| job.pattern);
+                for pattern in std::iter::once(pattern).chain(job_patterns) {
+                    check_deep_zoom(pattern, power, coloring)?;
+                }
+            }
+            let pad_strategy = if no_pad {
+                PadStrategy::None
+            } else {
+                pad_strategy
+            };
+            if format != OutputFormat::Png && pad_strategy != PadStrategy::None {
+                warn!(
+                    "Padding is only supported for PNG output, {:?} images won't be padded",
+                    format
+                );
+            }
+            // The name is checked here, since the format and seed aren't known to its parser
+            let extension = format!(".{}", format.extension());
+            if !filename_template.ends_with(".{ext}") && !filename_template.ends_with(&extension) {
+                return Err(RegenError::InvalidParams(format!(
+                    "--filename-template must end in .{{ext}} or {}, got '{}'",
+                    extension, filename_template
+                ))
+                .into());
+            }
+            if filename_template.contains("{seed}") && seed.is_none() {
+                return Err(RegenError::InvalidParams(
+                    "--filename-template uses {seed}, which needs --seed".to_string(),
+                )
+                .into());
+            }
+            if and_upload {
+                validate_upload(&upload_target, &upload_object, false)?;
+                let what = format!("{} generated images", count);
+                if !public.confirm(&upload_target, &upload_object, &what)? {
+                    println!("Cancelled");
+                    return Ok(());
+                }
+            }
+            info!(
+                "Generating {} {} images starting at index {}...",
+                count, pattern, start_index
+            );
+            // Renders are CPU-bound and each holds a whole image in memory, so only a few run at
+            // once and the rest wait for a permit
+            let concurrency = gen_concurrency.map_or_else(
+                || std::thread::available_parallelism().map_or(1, |n| n.get()),
+                |n| n as usize,
+            );
+            info!("Rendering up to {} images at a time", concurrency);
+            let permits = Arc::new(Semaphore::new(concurrency));
+            // With --and-upload each written file goes through a bounded channel to the
+            // uploader, so uploads overlap rendering. Once the channel is full, finished renders
+            // wait for room while holding their permit, which holds back the next ones
+            let state_path = &output_dir.join(UPLOAD_STATE_FILE);
+            let (upload_tx, uploader) = if and_upload {
+                let (tx, rx) = mpsc::channel(UPLOAD_QUEUE_LEN);
+                // Finished keys are recorded like `upload` does, so if some fail a later
+                // `regen upload` only sends the rest
+                let checkpoint =
+                    UploadCheckpoint::load(state_path, &upload_target.object_url("", false))?;
+                let store = object_store(
+                    upload_target.region(),
+                    upload_target.endpoint.as_deref(),
+                    upload_target.local_dir(),
+                    &upload_target.credentials,
+                    false,
+                )?;
+                let uploader = tokio::spawn(upload_stream(
+                    rx,
+                    output_dir.clone(),
+                    store,
+                    upload_target.bucket().to_string(),
+                    upload_target.key_prefix(),
+                    upload_object.clone(),
+                    retries,
+                    Arc::new(Mutex::new(checkpoint)),
+                ));
+                (Some(tx), Some(uploader))
+            } else {
+                (None, None)
+            };
+            let batch_start = Instant::now();
+            let tasks: Vec<_> = (start_index..start_index + count)
+                .map(|i| {
+                    let permits = Arc::clone(&permits);
+                    let job = jobs.as_ref().map(|jobs| jobs[i - start_index].clone());
+                    let pattern = job
+                        .as_ref()
+                        .and_then(|job| job.pattern)
+                        .unwrap_or(pattern);
+                    let output_dir = output_dir.clone();
+                    let filename_template = filename_template.clone();
+                    let watermark = watermark.clone();
+                    let shutdown = shutdown.clone();
+                    let upload_tx = upload_tx.clone();
+                    let palette_stops = Arc::clone(&palette_stops);
+                    tokio::spawn(async move {
+                        // Tasks are all spawned up front, so after Ctrl-C the ones that haven't
+                        // started yet skip their image instead of rendering it
+                        let Some(_permit) = start_unless_cancelled(permits, &shutdown).await else {
+                            info!("Interrupted, skipping image {}", i);
+                            return Ok(None);
+                        };
+                        info!("Starting generation for image {}", i);
+                        let task_start = Instant::now();
+                        // Everything an attempt is rendered with, and recorded in the image so
+                        // it can be reproduced later
+                        let gen_params = |width: u32, height: u32, params: MandelbrotParams| {
+                            let (x_pos, y_pos, view_scale, max_iterations, smoothness, color_step) = params;
+                            GenParams {
+                                pattern,
+                                width,
+                                height,
+                                x_pos,
+                                y_pos,
+                                view_scale,
+                                max_iterations,
+                                smoothness,
+                                color_step,
+                                palette,
+                                palette_stops: palette_stops.to_vec(),
+                                palette_offset: palette_offset(color_offset, color_offset_by_index, i),
+                                coloring,
+                                color_clamp,
+                                trap,
+                                supersample,
+                                julia_constant: julia_c,
+                                power,
+                                bailout,
+                                seed,
+                                index: i,
+                                deep_zoom,
+                                aspect,
+                                rgba,
+                                pixel_center: !pixel_corner,
+                                tile_size,
+                            }
+                        };
+                        let mut rng = match seed {
+                            Some(seed) => image_rng(seed, i),
+                            None => StdRng::from_entropy(),
+                        };
+
+                        // Regenerate the image with new random parameters until the fractal
+                        // ratio is within range
+                        let mut render_time = Duration::ZERO;
+                        // Attempts are rendered in memory; only the accepted one is encoded and written
+                        let mut render_attempt = |width: u32, height: u32, params: MandelbrotParams| {
+                            let render_start = Instant::now();
+                            let rendered = render_with_stats(&gen_params(width, height, params));
+                            render_time += render_start.elapsed();
+                            rendered
+                        };
+
+                        let mut fractal_ratio = 0.0;
+                        let mut attempts = 0;
+                        // Render closest to the middle of the band so far, kept in case no attempt
+                        // lands in range
+                        let mut best: Option<(f64, u32, u32, MandelbrotParams, DynamicImage)> = None;
+                        let (mut img, width, height, params) = if let Some(job) = &job {
+                            // Jobs are rendered exactly as listed, without the ratio band
+                            let (width, height, params) = job.resolve(pattern, fixed_width, fixed_height);
+                            info!("Job for image {}: width={}, height={}, params={:?}", i, width, height, params);
+                            if dump_bounds {
+                                dump_view_bounds(i, width, height, params.0, params.1, params.2, aspect);
+                            }
+                            let (img, stats) = render_attempt(width, height, params)?;
+                            fractal_ratio = stats.black_ratio;
+                            if reject_degenerate && is_degenerate(&stats) {
+                                return Err(RegenError::Degenerate(i).into());
+                            }
+                            (img, width, height, params)
+                        } else {
+                            loop {
+                                if attempts > 0 {
+                                    info!("Fractal ratio out of range ({:.4}). Regenerating image {}...", fractal_ratio, i);
+                                }
+                                let width = fixed_width.unwrap_or_else(|| rng.gen_range(3000..=5000));
+                                let height = fixed_height.unwrap_or_else(|| rng.gen_range(2000..=3500));
+                                let (x_pos, y_pos, view_scale) = match fixed_view {
+                                    Some(view) => view,
+                                    None => (rng.gen_range(-0.5..0.5), rng.gen_range(0.6..0.9), rng.gen_range(0.01..0.2)),
+                                };
+                                let mut max_iterations = rng.gen_range(400..1200);
+                                let smoothness = rng.gen_range(1..20);
+                                let color_step = rng.gen_range(1000.0..10000.0);
+
+                                info!("{} for image {}: width={}, height={}, x_pos={}, y_pos={}, view_scale={}, max_iterations={}, smoothness={}, color_step={}", if attempts == 0 { "Params" } else { "Regeneration params" }, i, width, height, x_pos, y_pos, view_scale, max_iterations, smoothness, color_step);
+                                if dump_bounds {
+                                    dump_view_bounds(i, width, height, x_pos, y_pos, view_scale, aspect);
+                                }
+
+                                let (img, stats, params) = loop {
+                                    let params = (x_pos, y_pos, view_scale, max_iterations, smoothness, color_step);
+                                    let (img, stats) = render_attempt(width, height, params)?;
+                                    fractal_ratio = stats.black_ratio;
+
+                                    // Mostly-black renders are often just under-iterated, so retry the
+                                    // same window with more iterations before giving up on it
+                                    if auto_iterations && looks_under_iterated(pattern, fractal_ratio, max_iterations) {
+                                        max_iterations *= 2;
+                                        info!("Image {}: fractal_ratio={:.4} looks under-iterated, retrying with max_iterations={}", i, fractal_ratio, max_iterations);
+                                        continue;
+                                    }
+                                    break (img, stats, params);
+                                };
+
+                                info!("Image {}: attempt {}, fractal_ratio={:.4}, variance={:.1}", i, attempts, fractal_ratio, stats.variance);
+                                attempts += 1;
+                                // Flat renders are never kept, whatever their ratio
+                                let degenerate = reject_degenerate && is_degenerate(&stats);
+                                if degenerate {
+                                    info!("Image {}: render is nearly a single color, discarding it", i);
+                                }
+                                // The random windows and ratio band are tuned for the Mandelbrot set,
+                                // other patterns and pinned views keep their first usable render
+                                if !degenerate
+                                    && (pattern != Pattern::Mandelbrot
+                                        || fixed_view.is_some()
+                                        || (min_ratio..=max_ratio).contains(&fractal_ratio))
+                                {
+                                    break (img, width, height, params);
+                                }
+
+                                let is_best = !degenerate
+                                    && best.as_ref().is_none_or(|(best_ratio, ..)| {
+                                        (fractal_ratio - target_ratio).abs() < (best_ratio - target_ratio).abs()
+                                    });
+                                if is_best {
+                                    best = Some((fractal_ratio, width, height, params, img));
+                                }
+
+                                if attempts >= max_attempts {
+                                    let Some((best_ratio, width, height, params, img)) = best.take() else {
+                                        return Err(RegenError::Degenerate(i).into());
+                                    };
+                                    warn!("Image {}: no render within the target ratio after {} attempts, keeping the closest (fractal_ratio={:.4})", i, attempts, best_ratio);
+                                    fractal_ratio = best_ratio;
+                                    break (img, width, height, params);
+                                }
+                            }
+                        };
+
+                        // Stamped only now, so the text never counts towards the fractal ratio
+                        if let Some(text) = &watermark {
+                            draw_watermark(&mut img, text, watermark_position, watermark_opacity);
+                        }
+
+                        // Named once the size is known, since templates can include it
+                        let filename = match job.as_ref().and_then(|job| job.filename.clone()) {
+                            Some(name) if Path::new(&name).extension().is_some() => name,
+                            Some(name) => format!("{}.{}", name, format.extension()),
+                            None => render_filename_template(&filename_template, pattern, i, width, height, seed, format.extension()),
+                        };
+                        let path = output_dir.join(&filename);
+                        let mut bytes = encode_generated(&img, &gen_params(width, height, params), format, quality)?;
+
+                        // Add random noise to the image file to defeat PNG compression. By default
+                        // it goes in a private ancillary chunk so the file remains a valid PNG
+                        if pad_strategy != PadStrategy::None && format == OutputFormat::Png {
+                            let file_size = bytes.len() as u64;
+                            let noise_bytes = rng.gen_range(pad_bytes.0..=pad_bytes.1);
+                            let mut noise = vec![0u8; noise_bytes];
+                            rng.fill(&mut noise[..]);
+                            pad_png(&mut bytes, pad_strategy, &noise)?;
+                            info!(
+                                "Added {} bytes of noise to {} (original size: {}, new size: {}), fractal ratio: {:.4}",
+                                noise_bytes,
+                                path.display(),
+                                human_readable_size(file_size),
+                                human_readable_size(bytes.len() as u64),
+                                fractal_ratio
+                            );
+                        }
+
+                        save_with_retry(&path, &bytes, fsync)?;
+                        info!("Image saved to {}", path.display());
+
+                        let thumb_path = match thumbnail {
+                            Some(max_dim) => Some(write_thumbnail(&path, &img, max_dim, format, quality)?),
+                            None => None,
+                        };
+
+                        if preview {
+                            info!("Preview flag set, previewing image {}", i);
+                            preview_image(&path)?;
+                        }
+                        info!("Finished generation for image {}", i);
+
+                        if let Some(tx) = &upload_tx {
+                            for file in std::iter::once(path.clone()).chain(thumb_path) {
+                                if tx.send(file).await.is_err() {
+                                    warn!("Uploader stopped, not uploading image {}", i);
+                                }
+                            }
+                        }
+                        // Render time, time for the whole task and bytes written
+                        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Some((
+                            render_time,
+                            task_start.elapsed(),
+                            bytes.len() as u64,
+                        )))
+                    })
+                })
+                .collect();
+
+            // Await all tasks and propagate errors
+            info!("Awaiting all image generation tasks...");
+            let results = try_join_all(tasks).await?;
+            info!("All image generation tasks completed.");
+            // The uploader finishes once the last sender is gone
+            drop(upload_tx);
+
+            let batch_time = batch_start.elapsed();
+
+            let mut metrics = BatchMetrics::default();
+            let mut skipped = 0;
+            let mut durations = Vec::new();
+            let mut bytes_written = 0;
+            for (i, result) in (start_index..).zip(results) {
+                match result {
+                    Ok(Some((render_time, elapsed, bytes))) => {
+                        metrics.images_generated += 1;
+                        metrics.render_seconds += render_time.as_secs_f64();
+                        durations.push(elapsed);
+                        bytes_written += bytes;
+                        if timings {
+                            println!(
+                                "Image {}: {:.2?} ({:.2?} rendering), {}",
+                                i,
+                                elapsed,
+                                render_time,
+                                human_readable_size(bytes)
+                            );
+                        }
+                    }
+                    Ok(None) => skipped += 1,
+                    Err(e) => {
+                        error!("Image generation failed: {}", e);
+                        metrics.failures += 1;
+                    }
+                }
+            }
+            if skipped > 0 {
+                warn!(
+                    "Interrupted: generated {} images, skipped {}",
+                    metrics.images_generated, skipped
+                );
+            }
+            if let Some(summary) = TimingSummary::from_durations(&durations) {
+                println!(
+                    "Generated {} images in {:.2?} ({})",
+                    summary.count,
+                    batch_time,
+                    throughput(summary.count, bytes_written, batch_time, "images")
+                );
+                println!("Per image: {}", summary);
+            }
+            let mut upload_stats = None;
+            if let Some(uploader) = uploader {
+                info!("Waiting for uploads to finish...");
+                let stats = uploader.await?;
+                println!(
+                    "Uploaded {} files ({}), {} failed",
+                    stats.timings.len(),
+                    human_readable_size(stats.uploaded_bytes),
+                    stats.failures
+                );
+                metrics.upload_bytes = stats.uploaded_bytes;
+                metrics.failures += stats.failures;
+                if stats.failures == 0 && !shutdown.is_cancelled() {
+                    UploadCheckpoint::clear(state_path)?;
+                }
+                update_manifest(
+                    &output_dir,
+                    false,
+                    &[],
+                    &stats.uploaded,
+                    &upload_target,
+                    &upload_object,
+                    &resolve_manifest_dir(manifest_dir, &output_dir),
+                    manifest_format,
+                    sort_manifest,
+                    strict_csv,
+                    None,
+                )?;
+                upload_stats = Some(stats);
+            }
+            if let Some(path) = &metrics_file {
+                write_metrics_file(path, &metrics)?;
+            }
+            // Reported last, once the manifest and metrics cover everything that did upload
+            if let Some(stats) = upload_stats
+                && !stats.failed.is_empty()
+            {
+                stats.print_failures();
+                let keys = stats.failed.into_iter().map(|(key, _)| key).collect();
+                return Err(RegenError::UploadFailures(keys).into());
+            }
+        }
+        Commands::Upload {
+            target,
+            output_dir,
+            flatten,
+            only_ext,
+            max_file_size,
+            retries,
+            dry_run,
+            object,
+            manifest_dir,
+            manifest_format,
+            sort_manifest,
+            price_per_gb,
+            flush_every,
+            restart,
+            strict_csv,
+            public,
+        } => {
+            info!("Starting upload process...");
+            let upload_start = Instant::now();
+            let stats = upload(
+                &output_dir,
+                &target,
+                flatten,
+                &only_ext,
+                max_file_size,
+                retries,
+                dry_run,
+                &object,
+                &resolve_manifest_dir(manifest_dir, &output_dir),
+                manifest_format,
+                sort_manifest,
+                price_per_gb,
+                flush_every,
+                strict_csv,
+                &public,
+                &shutdown,
+                restart,
+            )
+            .await?;
+            info!("Upload process finished.");
+            let upload_time = upload_start.elapsed();
+
+            if timings {
+                for (key, elapsed) in &stats.timings {
+                    println!("{}: {:.2?}", key, elapsed);
+                }
+            }
+            let durations: Vec<_> = stats.timings.iter().map(|(_, elapsed)| *elapsed).collect();
+            if let Some(summary) = TimingSummary::from_durations(&durations) {
+                println!(
+                    "Uploaded {} files in {:.2?} ({})",
+                    summary.count,
+                    upload_time,
+                    throughput(summary.count, stats.uploaded_bytes, upload_time, "files")
+                );
+                println!("Per file: {}", summary);
+            }
+
+            if let Some(path) = &metrics_file {
+                let metrics = BatchMetrics {
+                    upload_bytes: stats.uploaded_bytes,
+                    failures: stats.failures,
+                    ..Default::default()
+                };
+                write_metrics_file(path, &metrics)?;
+            }
+            // Reported last, once the manifest and metrics cover everything that did upload
+            if !stats.failed.is_empty() {
+                stats.print_failures();
+                let keys = stats.failed.into_iter().map(|(key, _)| key).collect();
+                return Err(RegenError::UploadFailures(keys).into());
+            }
+        }
+        Commands::Zoom {
+            center_x,
+            center_y,
+            start_radius,
+            end_radius,
+            frames,
+            width,
+            height,
+            max_iterations,
+            output_dir,
+            pattern,
+            julia_c,
+            palette,
+            palette_file,
+            supersample,
+            coloring,
+            color_clamp,
+            trap,
+            power,
+            bailout,
+            tile_size,
+            deep_zoom,
+            aspect,
+            animate,
+            fps,
+        } => {
+            if start_radius <= 0.0 || end_radius <= 0.0 {
+                return Err(RegenError::InvalidParams(format!(
+                    "zoom radii must be positive, got {} and {}",
+                    start_radius, end_radius
+                ))
+                .into());
+            }
+            if deep_zoom {
+                check_deep_zoom(pattern, power, coloring)?;
+            }
+            let (palette, palette_stops) = resolve_palette(palette, palette_file.as_deref())?;
+            // Zero-padded so the frames sort in order, with at least four digits
+            let digits = frames.to_string().len().max(4);
+            let (_, _, _, _, smoothness, color_step) = DEFAULT_MANDELBROT_PARAMS;
+            let mut animation = match animate {
+                Some(kind) => Some((
+                    kind,
+                    AnimationWriter::new(kind, width, height, frames, fps)?,
+                )),
+                None => None,
+            };
+            let mut rendered = 0;
+            let started = Instant::now();
+            for (frame, view_scale) in zoom_radii(start_radius, end_radius, frames)
+                .into_iter()
+                .enumerate()
+            {
+                if shutdown.is_cancelled() {
+                    warn!("Interrupted after {} of {} frames", frame, frames);
+                    break;
+                }
+                let filename = format!("frame_{:0width$}.png", frame + 1, width = digits);
+                info!("Frame {}/{}: view_scale={}", frame + 1, frames, view_scale);
+                let params = GenParams {
+                    pattern,
+                    width,
+                    height,
+                    x_pos: center_x,
+                    y_pos: center_y,
+                    view_scale,
+                    max_iterations,
+                    smoothness,
+                    color_step,
+                    palette,
+                    palette_stops: palette_stops.clone(),
+                    palette_offset: 0.0,
+                    coloring,
+                    color_clamp,
+                    trap,
+                    supersample,
+                    julia_constant: julia_c,
+                    power,
+                    bailout,
+                    seed: None,
+                    index: frame,
+                    deep_zoom,
+                    aspect,
+                    rgba: false,
+                    pixel_center: true,
+                    tile_size,
+                };
+                match &mut animation {
+                    Some((_, writer)) => writer.add_frame(render(&params)?)?,
+                    None => {
+                        generate_mathematical_image(
+                            &params,
+                            &output_dir,
+                            &filename,
+                            true,
+                            OutputFormat::Png,
+                            0,
+                        )?;
+                    }
+                }
+                rendered += 1;
+            }
+            match animation {
+                Some((kind, writer)) if rendered == frames => {
+                    let path = output_dir.join(kind.file_name());
+                    save_with_retry(&path, &writer.finish()?, true)?;
+                    println!(
+                        "Rendered {} frames to {} in {:.1?}",
+                        frames,
+                        path.display(),
+                        started.elapsed()
+                    );
+                }
+                Some(_) => warn!("Interrupted, the animation was not written"),
+                None => println!(
+                    "Rendered {} frames to {} in {:.1?}",
+                    rendered,
+                    output_dir.display(),
+                    started.elapsed()
+                ),
+            }
+
+            if let Some(path) = &metrics_file {
+                let metrics = BatchMetrics {
+                    images_generated: rendered as u64,
+                    render_seconds: started.elapsed().as_secs_f64(),
+                    ..Default::default()
+                };
+                write_metrics_file(path, &metrics)?;
+            }
+        }
+        Commands::Verify {
+            target,
+            manifest_dir,
+            manifest_format,
+            strict_csv,
+        } => {
+            let manifest_dir = resolve_manifest_dir(manifest_dir, Path::new(DEFAULT_OUTPUT_DIR));
+            let stats = verify(&target, &manifest_dir, manifest_format, strict_csv).await?;
+            println!(
+                "{} ok, {} missing, {} mismatched, {} failed",
+                stats.ok, stats.missing, stats.mismatched, stats.failed
+            );
+            let bad = stats.missing + stats.mismatched + stats.failed;
+            if bad > 0 {
+                return Err(RegenError::Verification(bad).into());
+            }
+        }
+        Commands::Doctor { target, output_dir } => {
+            let checks = doctor(&target, &output_dir).await;
+            for (name, check) in &checks {
+                let (label, detail) = match check {
+                    Check::Pass(detail) => ("PASS", detail),
+                    Check::Fail(detail) => ("FAIL", detail),
+                    Check::Skip(detail) => ("SKIP", detail),
+                };
+                println!("{:<5} {:<17} {}", label, name, detail);
+            }
+            let failed = checks
+                .iter()
+                .filter(|(_, check)| matches!(check, Check::Fail(_)))
+                .count();
+            if failed > 0 {
+                return Err(RegenError::ChecksFailed(failed).into());
+            }
+        }
+        Commands::Params { path } => match read_params(&path) {
+            Some(params) => println!("{}", serde_json::to_string_pretty(&params)?),
+            None => {
+                return Err(RegenError::InvalidParams(format!(
+                    "{} has no generation parameters",
+                    path.display()
+                ))
+                .into());
+            }
+        },
+        Commands::Stats { output_dir } => {
+            let stats = dir_stats(&output_dir);
+            let dimensions =
+                |d: Option<(u64, u64)>| d.map_or("-".to_string(), |(w, h)| format!("{}x{}", w, h));
+            let widen = |d: Option<(u32, u32)>| d.map(|(w, h)| (w as u64, h as u64));
+            println!("Images:     {}", stats.count);
+            println!("Total size: {}", human_readable_size(stats.total_bytes));
+            println!(
+                "Dimensions: min {}, avg {}, max {}",
+                dimensions(widen(stats.min_dimensions)),
+                dimensions(stats.avg_dimensions()),
+                dimensions(widen(stats.max_dimensions))
+            );
+            let formats: Vec<_> = stats
+                .formats
+                .iter()
+                .map(|(format, count)| format!("{} {}", format, count))
+                .collect();
+            println!("Formats:    {}", formats.join(", "));
+            if stats.unreadable > 0 {
+                println!("Unreadable: {}", stats.unreadable);
+            }
+        }
+        Commands::Sheet {
+            path,
+            output_dir,
+            columns,
+            tile_size,
+            labels,
+        } => {
+            // An earlier sheet written into the directory isn't one of the images
+            let images: Vec<_> = image_files(&output_dir)
+                .into_iter()
+                .filter(|image| absolute_path(image) != absolute_path(&path))
+                .collect();
+            match contact_sheet(&images, columns, tile_size, labels) {
+                Some((sheet, tiles)) => {
+                    sheet.save(&path)?;
+                    println!(
+                        "Wrote {} images as a {}x{} contact sheet to {}",
+                        tiles,
+                        sheet.width(),
+                        sheet.height(),
+                        path.display()
+                    );
+                }
+                None => println!("No images in {}", output_dir.display()),
+            }
+        }
+        Commands::Clean {
+            output_dir,
+            dry_run,
+            yes,
+        } => {
+            let images = image_files(&output_dir);
+            if images.is_empty() {
+                println!("No images in {}", output_dir.display());
+            } else if dry_run {
+                for path in &images {
+                    println!("Would remove {}", path.display());
+                }
+            } else if yes
+                || confirm(&format!(
+                    "Remove {} images from {}?",
+                    images.len(),
+                    output_dir.display()
+                ))?
+            {
+                for path in &images {
+                    fs::remove_file(path)?;
+                    info!("Removed {}", path.display());
+                }
+                println!(
+                    "Removed {} images from {}",
+                    images.len(),
+                    output_dir.display()
+                );
+            }
+        }
+        Commands::List { what } => match what {
+            ListKind::Patterns => print_choices::<Pattern>(),
+            ListKind::Palettes => print_choices::<Palette>(),
+            ListKind::Regions => {
+                for (name, location) in SPACES_REGIONS {
+                    println!("{:<12} {}", name, location);
+                }
+            }
+        },
+    }
+
+    info!("Program finished.");
+    Ok(())
+}
+
+/// Counters collected over a single generate or upload batch.
+#[derive(Debug, Default, Clone, Copy)]
+struct BatchMetrics {
+    images_generated: u64,
+    render_seconds: f64,
+    upload_bytes: u64,
+    failures: u64,
+}
+
+/// Names of the URL manifest files `upload` writes in `--manifest-dir`.
+const URLS_CSV_FILE: &str = "urls.csv";
+const URLS_JSON_FILE: &str = "urls.json";
+/// Header of the URL CSV, in column order.
+const MANIFEST_COLUMNS: [&str; 6] = [
+    "cdn_url",
+    "origin_url",
+    "file_name",
+    "file_size_kib",
+    "sha256",
+    "thumbnail_url",
+];
+/// Name of the file in `--output-dir` where `upload` keeps the keys finished by a run that
+/// didn't complete. It is never uploaded itself.
+const UPLOAD_STATE_FILE: &str = ".regen-upload-state.json";
+
+/// Directory the URL manifest goes in: `--manifest-dir` if given, otherwise the directory
+/// holding `output_dir`, so the manifest sits next to the images rather than among them.
+fn resolve_manifest_dir(manifest_dir: Option<PathBuf>, output_dir: &Path) -> PathBuf {
+    manifest_dir.unwrap_or_else(|| match output_dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    })
+}
+
+/// Keys uploaded by an unfinished run, so the next run can skip them. Only kept until a run
+/// completes without failures, after which regenerated files under the same names upload again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UploadCheckpoint {
+    /// Base URL of the Space the keys were uploaded to
+    pub target: String,
+    pub keys: BTreeSet<String>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl UploadCheckpoint {
+    /// An empty checkpoint for `target` that will be saved to `path`.
+    pub fn new(path: &Path, target: &str) -> UploadCheckpoint {
+        UploadCheckpoint {
+            target: target.to_string(),
+            keys: BTreeSet::new(),
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Reads the checkpoint at `path`. A missing file, or one left by a run against a
+    /// different Space, gives an empty checkpoint.
+    pub fn load(path: &Path, target: &str) -> Result<UploadCheckpoint, RegenError> {
+        if !path.exists() {
+            return Ok(UploadCheckpoint::new(path, target));
+        }
+        let mut checkpoint: UploadCheckpoint = serde_json::from_reader(fs::File::open(path)?)
+            .map_err(|e| checkpoint_error(path, e))?;
+        if checkpoint.target != target {
+            warn!(
+                "Ignoring upload checkpoint for {}, uploading to {}",
+                checkpoint.target, target
+            );
+            return Ok(UploadCheckpoint::new(path, target));
+        }
+        checkpoint.path = path.to_path_buf();
+        info!(
+            "Resuming upload, {} keys already uploaded",
+            checkpoint.keys.len()
+        );
+        Ok(checkpoint)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Adds `key` and saves the checkpoint. The file is written to a temporary name and then
+    /// renamed over the old one, so an interrupted write never leaves it truncated.
+    pub fn record(&mut self, key: &str) -> Result<(), RegenError> {
+        self.keys.insert(key.to_string());
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = self.path.with_extension("json.tmp");
+        serde_json::to_writer(fs::File::create(&temp_path)?, self)
+            .map_err(|e| checkpoint_error(&self.path, e))?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Deletes the checkpoint file, if there is one.
+    pub fn clear(path: &Path) -> std::io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A JSON error reading or writing the checkpoint at `path`, as the I/O error behind it if
+/// there is one.
+fn checkpoint_error(path: &Path, err: serde_json::Error) -> RegenError {
+    if err.is_io() {
+        RegenError::Io(err.into())
+    } else {
+        RegenError::Checkpoint(path.to_path_buf(), err.to_string())
+    }
+}
+
+/// Formats the URL manifest can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ManifestFormat {
+    Csv,
+    Json,
+    Both,
+}
+
+/// Orders the URL manifest rows can be written in, selected with `--sort-manifest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ManifestSort {
+    /// By file name, so mandelbrot_2.png comes before mandelbrot_10.png
+    Name,
+    /// By file size, smallest first
+    Size,
+    /// By CDN URL
+    Url,
+}
+
+impl ManifestSort {
+    /// Sorts manifest rows in this order. Ties, such as the same file name under two prefixes,
+    /// fall back to the URL, which is unique, so the result never depends on the input order.
+    pub fn sort(self, rows: &mut [UploadRecord]) {
+        // Rows without a recorded size sort last
+        let size = |row: &UploadRecord| row.file_size_kib.parse::<f64>().unwrap_or(f64::INFINITY);
+        rows.sort_by(|a, b| {
+            let order = match self {
+                ManifestSort::Name => natural_cmp(&a.file_name, &b.file_name),
+                ManifestSort::Size => size(a).total_cmp(&size(b)),
+                ManifestSort::Url => Ordering::Equal,
+            };
+            order.then_with(|| natural_cmp(&a.cdn_url, &b.cdn_url))
+        });
+    }
+}
+
+/// One uploaded file in the URL manifest, with the same fields as the CSV columns.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadRecord {
+    pub cdn_url: String,
+    pub origin_url: String,
+    pub file_name: String,
+    pub file_size_kib: String,
+    /// SHA-256 of the uploaded bytes, empty for rows written before hashes were recorded
+    #[serde(default)]
+    pub sha256: String,
+    /// CDN URL of the image's thumbnail, empty if it has none
+    #[serde(default)]
+    pub thumbnail_url: String,
+}
+
+/// Hex-encoded SHA-256 of a file's contents.
+pub fn file_sha256(path: &Path) -> Result<String, RegenError> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Outcome of uploading a folder: bytes that made it to the Space and files that didn't.
+#[derive(Debug, Default, Clone)]
+pub struct UploadStats {
+    pub uploaded_bytes: u64,
+    pub failures: u64,
+    /// Local paths of the files that are in the Space, including ones skipped because an
+    /// earlier run uploaded them
+    pub uploaded: HashSet<PathBuf>,
+    /// Files skipped because an earlier run uploaded them
+    pub skipped: u64,
+    /// Files skipped by `--skip-existing` because their key was already taken
+    pub existing: u64,
+    /// Object key and time taken (including retries) for each uploaded file
+    pub timings: Vec<(String, Duration)>,
+    /// Object key and reason for each failed upload
+    pub failed: Vec<(String, String)>,
+}
+
+impl UploadStats {
+    /// Adds the outcome of the upload to `key`.
+    fn record(&mut self, key: String, result: UploadResult) {
+        match result {
+            // Objects left alone by --skip-existing are in the Space, but weren't uploaded now
+            Ok((path, _, _, _, true)) => {
+                self.existing += 1;
+                self.uploaded.insert(path);
+            }
+            Ok((path, bytes, key, elapsed, false)) => {
+                self.uploaded_bytes += bytes;
+                self.uploaded.insert(path);
+                self.timings.push((key, elapsed));
+            }
+            Err(e) => self.fail(key, e.to_string()),
+        }
+    }
+
+    fn fail(&mut self, key: String, reason: String) {
+        self.failures += 1;
+        self.failed.push((key, reason));
+    }
+
+    /// Prints every failed upload with its reason, so a large run can be retried selectively.
+    fn print_failures(&self) {
+        if self.failed.is_empty() {
+            return;
+        }
+        println!("Failed uploads:");
+        for (key, reason) in &self.failed {
+            println!("  {}: {}", key, reason);
+        }
+    }
+}
+
+const METRIC_IMAGES_GENERATED: &str = "regen_images_generated_total";
+const METRIC_RENDER_SECONDS: &str = "regen_render_seconds_total";
+const METRIC_UPLOAD_BYTES: &str = "regen_upload_bytes_total";
+const METRIC_FAILURES: &str = "regen_failures_total";
+
+/// Reads back the counters from a previously written metrics file, if any.
+fn read_metrics_file(path: &Path) -> BatchMetrics {
+    let mut metrics = BatchMetrics::default();
+    let Ok(contents) = fs::read_to_string(path) else {
+        return metrics;
+    };
+    for line in contents.lines().filter(|l| !l.starts_with('#')) {
+        let mut parts = line.split_whitespace();
+        let (Some(name), Some(value)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        match name {
+            METRIC_IMAGES_GENERATED => metrics.images_generated = value.parse().unwrap_or(0),
+            METRIC_RENDER_SECONDS => metrics.render_seconds = value.parse().unwrap_or(0.0),
+            METRIC_UPLOAD_BYTES => metrics.upload_bytes = value.parse().unwrap_or(0),
+            METRIC_FAILURES => metrics.failures = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    metrics
+}
+
+/// Adds the batch counters to the totals in `path` and rewrites it in Prometheus
+/// exposition format for the node_exporter textfile collector.
+fn write_metrics_file(
+    path: &Path,
+    batch: &BatchMetrics,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Counters must only ever go up, so accumulate on top of the previous batches
+    let mut totals = read_metrics_file(path);
+    totals.images_generated += batch.images_generated;
+    totals.render_seconds += batch.render_seconds;
+    totals.upload_bytes += batch.upload_bytes;
+    totals.failures += batch.failures;
+
+    let contents = format!(
+        "# HELP {images} Images generated.\n\
+         # TYPE {images} counter\n\
+         {images} {}\n\
+         # HELP {render} Seconds spent rendering images.\n\
+         # TYPE {render} counter\n\
+         {render} {}\n\
+         # HELP {upload} Bytes uploaded to the Space.\n\
+         # TYPE {upload} counter\n\
+         {upload} {}\n\
+         # HELP {failures} Failed image generations and uploads.\n\
+         # TYPE {failures} counter\n\
+         {failures} {}\n",
+        totals.images_generated,
+        totals.render_seconds,
+        totals.upload_bytes,
+        totals.failures,
+        images = METRIC_IMAGES_GENERATED,
+        render = METRIC_RENDER_SECONDS,
+        upload = METRIC_UPLOAD_BYTES,
+        failures = METRIC_FAILURES,
+    );
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write to a temp file and rename so the collector never sees a half-written file
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
+    info!("Metrics written to {}", path.display());
+    Ok(())
+}
+
+/// Lists the files under `local_folder_path` that pass `is_upload_candidate`, along with the object name (relative to the
+/// Space prefix) each one is uploaded as. Names keep the relative directory structure unless
+/// `flatten` is set, in which case only the file name is used and clashes get a `_N` suffix.
+pub fn plan_object_names(
+    local_folder_path: &Path,
+    flatten: bool,
+    only_ext: &[String],
+) -> Result<Vec<(PathBuf, String)>, RegenError> {
+    let mut planned = Vec::new();
+    let mut used_names = HashSet::new();
+
+    // Sorted so collision suffixes are stable between runs
+    for entry in WalkDir::new(local_folder_path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file() && is_upload_candidate(e.path(), only_ext))
+        .filter(|e| {
+            !e.file_name()
+                .to_string_lossy()
+                .starts_with(UPLOAD_STATE_FILE)
+        })
+    {
+        let path = entry.path().to_path_buf();
+        let name = if flatten {
+            let file_name = entry.file_name().to_string_lossy();
+            let mut name = file_name.to_string();
+            let mut counter = 1;
+            while used_names.contains(&name) {
+                let file_path = Path::new(file_name.as_ref());
+                let stem = file_path.file_stem().unwrap_or_default().to_string_lossy();
+                name = match file_path.extension() {
+                    Some(ext) => format!("{}_{}.{}", stem, counter, ext.to_string_lossy()),
+                    None => format!("{}_{}", stem, counter),
+                };
+                counter += 1;
+            }
+            name
+        } else {
+            let relative_path = path
+                .strip_prefix(local_folder_path)
+                .map_err(|e| RegenError::PathConversion(e.to_string()))?;
+            relative_path.to_string_lossy().replace("\\", "/") // Ensure forward slashes
+        };
+        used_names.insert(name.clone());
+        planned.push((path, name));
+    }
+    Ok(planned)
+}
+
+/// Whether the walker picks up `path` for upload: any file with one of the `--only-ext`
+/// extensions, or any image file if none were given, so stray files such as `.DS_Store` or
+/// half-written downloads are left behind.
+pub fn is_upload_candidate(path: &Path, only_ext: &[String]) -> bool {
+    if only_ext.is_empty() {
+        return content_type_for(path).starts_with("image/");
+    }
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| only_ext.iter().any(|only| only.eq_ignore_ascii_case(ext)))
+}
+
+/// Normalizes an `--only-ext` entry to a bare lowercase extension, so `.PNG` matches too.
+fn parse_extension(value: &str) -> Result<String, String> {
+    let extension = value.trim().trim_start_matches('.').to_lowercase();
+    if extension.is_empty() {
+        return Err(format!("expected a file extension, got '{}'", value));
+    }
+    Ok(extension)
+}
+
+/// Lists the image files under `dir`, recognized by extension, so `clean` never touches
+/// anything else that happens to live there.
+pub fn image_files(dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .map(|e| e.into_path())
+        .filter(|path| content_type_for(path).starts_with("image/"))
+        .collect()
+}
+
+/// Composites `images` into a grid of `columns` square cells of `tile_size` pixels, in order,
+/// with each image scaled down to fit its cell and centered. Thumbnails written next to their
+/// image and files that can't be decoded are left out. Returns the sheet and the number of
+/// tiles on it, or None if there was nothing to tile.
+pub fn contact_sheet(
+    images: &[PathBuf],
+    columns: u32,
+    tile_size: u32,
+    labels: bool,
+) -> Option<(RgbImage, usize)> {
+    let thumbnails: HashSet<String> = images
+        .iter()
+        .filter_map(|path| {
+            let name = path.to_string_lossy();
+            let thumbnail = thumbnail_name(&name);
+            (thumbnail != name).then_some(thumbnail)
+        })
+        .collect();
+    let tiles: Vec<DynamicImage> = images
+        .par_iter()
+        .filter(|path| !thumbnails.contains(path.to_string_lossy().as_ref()))
+        .filter_map(|path| {
+            let img = match image::open(path) {
+                Ok(img) => img,
+                Err(e) => {
+                    warn!("Could not read {}: {}", path.display(), e);
+                    return None;
+                }
+            };
+            // Integer box sampling: much faster than Lanczos, and sharp enough at this size
+            let mut tile = DynamicImage::ImageRgb8(img.thumbnail(tile_size, tile_size).to_rgb8());
+            if labels && let Some(name) = path.file_name() {
+                let name = name.to_string_lossy();
+                draw_watermark(&mut tile, &name, WatermarkPosition::BottomLeft, 1.0);
+            }
+            Some(tile)
+        })
+        .collect();
+    if tiles.is_empty() {
+        return None;
+    }
+
+    let columns = columns.min(tiles.len() as u32);
+    let rows = (tiles.len() as u32).div_ceil(columns);
+    let cell = tile_size + SHEET_GAP;
+    let mut sheet = RgbImage::from_pixel(
+        columns * cell + SHEET_GAP,
+        rows * cell + SHEET_GAP,
+        SHEET_BACKGROUND,
+    );
+    for (index, tile) in tiles.iter().enumerate() {
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let x = SHEET_GAP + column * cell + (tile_size - tile.width()) / 2;
+        let y = SHEET_GAP + row * cell + (tile_size - tile.height()) / 2;
+        sheet
+            .copy_from(&tile.to_rgb8(), x, y)
+            .expect("tiles fit within their cell");
+    }
+    Some((sheet, tiles.len()))
+}
+
+/// Summary of the images in a directory, as printed by `regen stats`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DirStats {
+    pub count: usize,
+    pub total_bytes: u64,
+    /// Smallest and largest images by pixel count
+    pub min_dimensions: Option<(u32, u32)>,
+    pub max_dimensions: Option<(u32, u32)>,
+    pub width_sum: u64,
+    pub height_sum: u64,
+    /// Number of images per format, keyed by file extension
+    pub formats: BTreeMap<String, usize>,
+    /// Files with an image extension whose header couldn't be read
+    pub unreadable: usize,
+}
+
+impl DirStats {
+    /// Average width and height over the readable images.
+    pub fn avg_dimensions(&self) -> Option<(u64, u64)> {
+        (self.count > 0).then(|| {
+            let count = self.count as u64;
+            (self.width_sum / count, self.height_sum / count)
+        })
+    }
+}
+
+/// Collects `DirStats` for the images under `dir`. Only each file's header is decoded, to
+/// get its format and dimensions.
+pub fn dir_stats(dir: &Path) -> DirStats {
+    let mut stats = DirStats::default();
+    for path in image_files(dir) {
+        let header = image::ImageReader::open(&path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(image::ImageError::from)
+            .and_then(|reader| {
+                let format = reader.format();
+                Ok((format, reader.into_dimensions()?))
+            });
+        let (format, (width, height)) = match header {
+            Ok(header) => header,
+            Err(e) => {
+                warn!("Could not read {}: {}", path.display(), e);
+                stats.unreadable += 1;
+                continue;
+            }
+        };
+        let area = |(w, h): (u32, u32)| w as u64 * h as u64;
+        stats.count += 1;
+        stats.total_bytes += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        stats.width_sum += width as u64;
+        stats.height_sum += height as u64;
+        if stats
+            .min_dimensions
+            .is_none_or(|min| area((width, height)) < area(min))
+        {
+            stats.min_dimensions = Some((width, height));
+        }
+        if stats
+            .max_dimensions
+            .is_none_or(|max| area((width, height)) > area(max))
+        {
+            stats.max_dimensions = Some((width, height));
+        }
+        let format = format
+            .and_then(|f| f.extensions_str().first())
+            .map_or("unknown", |ext| ext);
+        *stats.formats.entry(format.to_string()).or_default() += 1;
+    }
+    stats
+}
+
+/// Log line formats accepted by `--log-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// env_logger's usual `[timestamp LEVEL target] message` lines
+    Text,
+    /// One object per line with timestamp, level, target and message fields
+    Json,
+}
+
+/// Log level selected by `-v`/`-q`, or None to leave it to `RUST_LOG`.
+fn log_level(verbose: u8, quiet: bool) -> Option<LevelFilter> {
+    match (verbose, quiet) {
+        (_, true) => Some(LevelFilter::Error),
+        (0, false) => None,
+        (1, false) => Some(LevelFilter::Info),
+        (2, false) => Some(LevelFilter::Debug),
+        (_, false) => Some(LevelFilter::Trace),
+    }
+}
+
+/// Cancels the returned token on the first Ctrl-C, so commands can stop starting new work and
+/// let what's in flight finish. A second Ctrl-C exits immediately. The listener runs on its own
+/// thread because rendering can keep every runtime worker busy until the batch is done.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    std::thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                warn!("Could not listen for Ctrl-C: {}", e);
+                return;
+            }
+        };
+        runtime.block_on(async {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            eprintln!("Interrupted, finishing in-flight work. Press Ctrl-C again to abort.");
+            cancel.cancel();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+    });
+    token
+}
+
+/// Waits for a permit to start one more task, or returns None if `shutdown` was cancelled by
+/// the time one is free. Tasks already holding a permit are left to finish.
+async fn start_unless_cancelled(
+    permits: Arc<Semaphore>,
+    shutdown: &CancellationToken,
+) -> Option<OwnedSemaphorePermit> {
+    let permit = permits
+        .acquire_owned()
+        .await
+        .expect("the semaphore is never closed");
+    (!shutdown.is_cancelled()).then_some(permit)
+}
+
+/// Asks a yes/no question on stdin, defaulting to no.
+fn confirm(question: &str) -> Result<bool, RegenError> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_folder_to_do_space(
+    local_folder_path: &Path,
+    bucket_name: &str,
+    do_region_name: &str,
+    space_folder_prefix: Option<&str>,
+    endpoint_override: Option<&str>,
+    local_dir: Option<&Path>,
+    flatten: bool,
+    only_ext: &[String],
+    max_retries: u32,
+    dry_run: bool,
+    object: &ObjectSettings,
+    credentials: &Credentials,
+    shutdown: &CancellationToken,
+    checkpoint: &Arc<Mutex<UploadCheckpoint>>,
+    flush_every: Option<u64>,
+    flush: &mut dyn FnMut(&HashSet<PathBuf>),
+) -> Result<UploadStats, RegenError> {
+    // 1. Initialize S3 Client with DigitalOcean (or the overridden) Endpoint
+    let store = object_store(
+        do_region_name,
+        endpoint_override,
+        local_dir,
+        credentials,
+        dry_run,
+    )?;
+
+    info!("Starting upload of folder: {}", local_folder_path.display());
+    if let Some(dir) = local_dir {
+        info!("To local directory: {}", dir.display());
+    } else {
+        info!("To Space: {} in region: {}", bucket_name, do_region_name);
+    }
+    if dry_run && let Some(dir) = local_dir {
+        println!(
+            "Dry run: local directory={} prefix={}",
+            dir.display(),
+            space_folder_prefix.unwrap_or("")
+        );
+    } else if dry_run {
+        println!(
+            "Dry run: bucket={} region={} prefix={} endpoint={} acl={}",
+            bucket_name,
+            do_region_name,
+            space_folder_prefix.unwrap_or(""),
+            endpoint_override.unwrap_or("(DigitalOcean Spaces)"),
+            object.acl.as_str()
+        );
+    }
+
+    let mut upload_tasks = Vec::new();
+    let mut skipped = Vec::new();
+    let mut unreadable = Vec::new();
+
+    // 2. Traverse the local folder
+    for (path, object_name) in plan_object_names(local_folder_path, flatten, only_ext)? {
+        // After Ctrl-C no new uploads start; the ones already running are left to finish
+        if shutdown.is_cancelled() {
+            warn!("Interrupted, not uploading the remaining files");
+            break;
+        }
+        let mut s3_key_path = PathBuf::new();
+
+        if let Some(prefix) = space_folder_prefix {
+            s3_key_path.push(prefix);
+        }
+        s3_key_path.push(object_name);
+
+        let s3_key = s3_key_path.to_string_lossy().replace("\\", "/"); // Ensure forward slashes
+
+        info!("- Preparing to upload: {} -> {}", path.display(), s3_key);
+        let content_type = content_type_for(&path);
+        // Images are already compressed, and a local directory can't record the encoding
+        let gzip = object.compress && local_dir.is_none() && is_compressible(content_type);
+        let done = checkpoint
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(&s3_key);
+
+        if dry_run {
+            let encoding = if gzip { ", gzip" } else { "" };
+            let note = if done { ", already uploaded" } else { "" };
+            println!(
+                "{} -> {} ({}{}{})",
+                path.display(),
+                s3_key,
+                content_type,
+                encoding,
+                note
+            );
+            continue;
+        }
+        if done {
+            info!("  - Skipping {}: uploaded by an earlier run", s3_key);
+            skipped.push(path);
+            continue;
+        }
+
+        let file_data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("  - Failed to read {}: {}", path.display(), e);
+                unreadable.push((s3_key, format!("could not read {}: {}", path.display(), e)));
+                continue;
+            }
+        };
+        // Create an async task for each file upload
+        upload_tasks.push((
+            s3_key.clone(),
+            tokio::spawn(upload_file(
+                store.clone(),
+                bucket_name.to_string(),
+                path,
+                s3_key,
+                file_data,
+                gzip,
+                object.clone(),
+                max_retries,
+                Some(Arc::clone(checkpoint)),
+            )),
+        ));
+    }
+
+    // 3. Wait for every upload task, so one failure doesn't hide how the others went. Each is
+    // recorded as it finishes, so `flush` sees the files uploaded so far
+    info!("Waiting for all upload tasks to complete...");
+    let mut pending: FuturesUnordered<_> = upload_tasks
+        .into_iter()
+        .map(|(key, task)| async move { (key, task.await) })
+        .collect();
+
+    let mut stats = UploadStats {
+        skipped: skipped.len() as u64,
+        uploaded: skipped.into_iter().collect(),
+        ..Default::default()
+    };
+    for (key, reason) in unreadable {
+        stats.fail(key, reason);
+    }
+    let mut since_flush = 0;
+    while let Some((key, result)) = pending.next().await {
+        match result {
+            Ok(result) => {
+                let succeeded = result.is_ok();
+                stats.record(key, result);
+                if succeeded && let Some(every) = flush_every {
+                    since_flush += 1;
+                    if since_flush >= every {
+                        flush(&stats.uploaded);
+                        since_flush = 0;
+                    }
+                }
+            }
+            Err(e) => stats.fail(key, format!("upload task failed: {}", e)),
+        }
+    }
+    // Listed by key rather than in the order the uploads happened to finish
+    stats.failed.sort();
+
+    info!(
+        "Folder upload complete! Uploaded {}, {} failed",
+        human_readable_size(stats.uploaded_bytes),
+        stats.failures
+    );
+    Ok(stats)
+}
+
+/// Local path, size, key, time taken and whether the object was already there, for one upload.
+type UploadResult = Result<(PathBuf, u64, String, Duration, bool), RegenError>;
+
+/// Uploads one file's contents to `s3_key`, gzipped first if `gzip` is set. With a checkpoint
+/// the key is recorded once it is in the Space, so an interrupted run can resume.
+#[allow(clippy::too_many_arguments)]
+async fn upload_file(
+    store: ObjectStore,
+    bucket_name: String,
+    path: PathBuf,
+    s3_key: String,
+    file_data: Vec<u8>,
+    gzip: bool,
+    object: ObjectSettings,
+    max_retries: u32,
+    checkpoint: Option<Arc<Mutex<UploadCheckpoint>>>,
+) -> UploadResult {
+    let file_size = file_data.len() as u64;
+    let skip_existing = object.skip_existing;
+    let match_size = object.match_size;
+    let started = Instant::now();
+    info!("Uploading file {} to S3 key {}", path.display(), s3_key);
+    let result = match &store {
+        ObjectStore::S3(client) => {
+            let (body, content_encoding) = if gzip {
+                (gzip_bytes(&file_data)?, Some("gzip".to_string()))
+            } else {
+                (file_data.clone(), None)
+            };
+            let size = match_size.then_some(body.len() as u64);
+            if skip_existing && s3_object_exists(client, &bucket_name, &s3_key, size).await {
+                info!("  - Skipping {}: already in the Space", s3_key);
+                return Ok((path, file_size, s3_key, started.elapsed(), true));
+            }
+            let headers = ObjectHeaders {
+                acl: object.acl.as_str().to_string(),
+                content_type: content_type_for(&path).to_string(),
+                content_encoding,
+                cache_control: object.cache_control().map(str::to_string),
+                content_disposition: object.content_disposition.clone(),
+                tagging: object.tagging(),
+            };
+            if body.len() as u64 > object.multipart_threshold {
+                multipart_upload(client, &bucket_name, &s3_key, &headers, &body, max_retries).await
+            } else {
+                // The body is consumed by each attempt, so the request is rebuilt for every retry
+                let md5 = content_md5(&body);
+                let put = || {
+                    let headers = headers.clone();
+                    client.put_object(PutObjectRequest {
+                        bucket: bucket_name.clone(),
+                        key: s3_key.clone(),
+                        body: Some(body.clone().into()),
+                        acl: Some(headers.acl),
+                        content_type: Some(headers.content_type),
+                        content_encoding: headers.content_encoding,
+                        content_md5: Some(md5.clone()),
+                        cache_control: headers.cache_control,
+                        content_disposition: headers.content_disposition,
+                        tagging: headers.tagging,
+                        ..Default::default()
+                    })
+                };
+                with_retry(&s3_key, max_retries, is_transient_error, put)
+                    .await
+                    .map(drop)
+                    .map_err(RegenError::from)
+            }
+        }
+        ObjectStore::Local(dir) => {
+            let size = match_size.then_some(file_size);
+            if skip_existing && local_object_exists(dir, &s3_key, size) {
+                info!("  - Skipping {}: already in the Space", s3_key);
+                return Ok((path, file_size, s3_key, started.elapsed(), true));
+            }
+            write_local_object(dir, &s3_key, &file_data)
+                .await
+                .map_err(RegenError::from)
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            info!(
+                "  - Successfully uploaded: {} ({})",
+                s3_key,
+                human_readable_size(file_size)
+            );
+            // A failed save only means the file is uploaded again on a resumed run
+            if let Some(checkpoint) = checkpoint
+                && let Err(e) = checkpoint
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .record(&s3_key)
+            {
+                warn!("Could not update upload checkpoint: {}", e);
+            }
+            Ok((path, file_size, s3_key, started.elapsed(), false))
+        }
+        Err(e) => {
+            error!("  - Failed to upload {}: {:?}", s3_key, e);
+            Err(e)
+        }
+    }
+}
+
+/// Finished files waiting for an upload with `generate --and-upload`.
+const UPLOAD_QUEUE_LEN: usize = 16;
+/// Uploads running at once with `generate --and-upload`.
+const STREAMED_UPLOADS: usize = 8;
+
+/// Uploads files as `generate --and-upload` writes them, keyed by their path under
+/// `output_dir`, until every sender is dropped. Files are only read once an upload slot is
+/// free, so at most `STREAMED_UPLOADS` bodies are held in memory. Each uploaded key is added
+/// to `checkpoint`; keys already in it are uploaded again, since the files were just rendered.
+#[allow(clippy::too_many_arguments)]
+async fn upload_stream(
+    mut files: mpsc::Receiver<PathBuf>,
+    output_dir: PathBuf,
+    store: ObjectStore,
+    bucket_name: String,
+    prefix: String,
+    object: ObjectSettings,
+    max_retries: u32,
+    checkpoint: Arc<Mutex<UploadCheckpoint>>,
+) -> UploadStats {
+    let permits = Arc::new(Semaphore::new(STREAMED_UPLOADS));
+    let mut stats = UploadStats::default();
+    let mut upload_tasks = Vec::new();
+    while let Some(path) = files.recv().await {
+        let permit = Arc::clone(&permits)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let name = path.strip_prefix(&output_dir).unwrap_or(&path);
+        let s3_key = format!("{}{}", prefix, name.to_string_lossy().replace("\\", "/"));
+        let file_data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("  - Failed to read {}: {}", path.display(), e);
+                stats.fail(s3_key, format!("could not read {}: {}", path.display(), e));
+                continue;
+            }
+        };
+        let gzip = object.compress
+            && matches!(store, ObjectStore::S3(_))
+            && is_compressible(content_type_for(&path));
+        let key = s3_key.clone();
+        let upload = upload_file(
+            store.clone(),
+            bucket_name.clone(),
+            path,
+            s3_key,
+            file_data,
+            gzip,
+            object.clone(),
+            max_retries,
+            Some(Arc::clone(&checkpoint)),
+        );
+        upload_tasks.push((
+            key,
+            tokio::spawn(async move {
+                let _permit = permit;
+                upload.await
+            }),
+        ));
+    }
+    let (keys, tasks): (Vec<_>, Vec<_>) = upload_tasks.into_iter().unzip();
+    for (key, result) in keys.into_iter().zip(join_all(tasks).await) {
+        match result {
+            Ok(result) => stats.record(key, result),
+            Err(e) => {
+                error!("  - Upload task failed: {}", e);
+                stats.fail(key, format!("upload task failed: {}", e));
+            }
+        }
+    }
+    stats
+}
+
+/// The Space client, or the directory standing in for it with the local backend. A dry run
+/// never sends a request, and the local backend never talks to a Space, so neither needs
+/// credentials.
+fn object_store(
+    region: &str,
+    endpoint: Option<&str>,
+    local_dir: Option<&Path>,
+    credentials: &Credentials,
+    dry_run: bool,
+) -> Result<ObjectStore, RegenError> {
+    Ok(match local_dir {
+        Some(dir) => ObjectStore::Local(dir.to_path_buf()),
+        None => {
+            let credentials = match credentials.provider() {
+                Ok(provider) => provider,
+                Err(_) if dry_run => CredentialProvider::Static(StaticProvider::new_minimal(
+                    String::new(),
+                    String::new(),
+                )),
+                Err(e) => return Err(e),
+            };
+            ObjectStore::S3(s3_client(region, endpoint, credentials))
+        }
+    })
+}
+
+/// Checks an upload's settings before any file is read, so a missing key fails here with a
+/// clear message rather than on the first PUT.
+fn validate_upload(
+    target: &UploadTarget,
+    object: &ObjectSettings,
+    dry_run: bool,
+) -> Result<(), RegenError> {
+    target.validate()?;
+    if object.tags.len() > MAX_OBJECT_TAGS {
+        return Err(RegenError::InvalidParams(format!(
+            "at most {} tags can be set on an object, got {}",
+            MAX_OBJECT_TAGS,
+            object.tags.len()
+        )));
+    }
+    if !dry_run && target.backend == Backend::S3 {
+        target.credentials.provider()?;
+    }
+    Ok(())
+}
+
+/// Makes `path` absolute and drops `.` and `..` components without touching the filesystem,
+/// so the result is the same whether or not the directory exists yet.
+fn absolute_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Whether `key` already holds an object, for `--skip-existing`; with `size`, only one of that
+/// many bytes counts. A failed check counts as absent, so the file is uploaded rather than
+/// silently left out.
+async fn s3_object_exists(client: &S3Client, bucket: &str, key: &str, size: Option<u64>) -> bool {
+    let request = HeadObjectRequest {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        ..Default::default()
+    };
+    match client.head_object(request).await {
+        Ok(head) => size.is_none_or(|size| head.content_length == Some(size as i64)),
+        Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => false,
+        Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => false,
+        Err(e) => {
+            warn!(
+                "Could not check whether {} exists, uploading it: {:?}",
+                key, e
+            );
+            false
+        }
+    }
+}
+
+/// `s3_object_exists` for the local backend.
+fn local_object_exists(dir: &Path, key: &str, size: Option<u64>) -> bool {
+    fs::metadata(dir.join(key)).is_ok_and(|meta| size.is_none_or(|size| meta.len() == size))
+}
+
+/// Destination of an upload run, picked by `--backend`.
+#[derive(Clone)]
+enum ObjectStore {
+    S3(S3Client),
+    Local(PathBuf),
+}
+
+/// Stores an object for the local backend at `dir/key`, creating the key's directories. The
+/// file is written under a temporary name and renamed, so a copy is never seen half-written.
+async fn write_local_object(dir: &Path, key: &str, data: &[u8]) -> std::io::Result<()> {
+    let path = dir.join(key);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".part");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, &path).await
+}
+
+/// Compresses an upload body for `--compress`.
+fn gzip_bytes(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Base64 MD5 of the exact bytes sent, so the Space rejects a body corrupted in transit.
+pub fn content_md5(body: &[u8]) -> String {
+    base64::encode(Md5::digest(body))
+}
+
+/// Headers an uploaded object is stored with, whether it goes up in one PUT or in parts.
+#[derive(Debug, Clone)]
+struct ObjectHeaders {
+    acl: String,
+    content_type: String,
+    content_encoding: Option<String>,
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    tagging: Option<String>,
+}
+
+/// Sends `body` to `key` as a multipart upload: the upload is created with `headers`, the parts go up `MULTIPART_UPLOADS` at a time with their
+/// own Content-MD5 and retries, and the parts are joined once all have arrived. If anything
+/// fails for good the upload is aborted, so the Space doesn't keep (and bill for) the parts.
+async fn multipart_upload(
+    client: &S3Client,
+    bucket: &str,
+    key: &str,
+    headers: &ObjectHeaders,
+    body: &[u8],
+    max_retries: u32,
+) -> Result<(), RegenError> {
+    let create = || {
+        let headers = headers.clone();
+        client.create_multipart_upload(CreateMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            acl: Some(headers.acl),
+            content_type: Some(headers.content_type),
+            content_encoding: headers.content_encoding,
+            cache_control: headers.cache_control,
+            content_disposition: headers.content_disposition,
+            tagging: headers.tagging,
+            ..Default::default()
+        })
+    };
+    let upload_id = with_retry(key, max_retries, is_transient_error, create)
+        .await
+        .map_err(|e| multipart_error("starting", key, e))?
+        .upload_id
+        .ok_or_else(|| RegenError::MultipartUpload(format!("no upload ID returned for {}", key)))?;
+
+    let part_size = MULTIPART_PART_SIZE.max(body.len().div_ceil(MAX_MULTIPART_PARTS));
+    info!(
+        "  - Uploading {} in {} parts",
+        key,
+        body.len().div_ceil(part_size)
+    );
+    let permits = Semaphore::new(MULTIPART_UPLOADS);
+    let parts = body.chunks(part_size).zip(1..).map(|(chunk, part_number)| {
+        let permits = &permits;
+        let upload_id = &upload_id;
+        async move {
+            let _permit = permits
+                .acquire()
+                .await
+                .expect("the semaphore is never closed");
+            let md5 = content_md5(chunk);
+            let upload = || {
+                client.upload_part(UploadPartRequest {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                    upload_id: upload_id.clone(),
+                    part_number,
+                    body: Some(chunk.to_vec().into()),
+                    content_length: Some(chunk.len() as i64),
+                    content_md5: Some(md5.clone()),
+                    ..Default::default()
+                })
+            };
+            let output = with_retry(key, max_retries, is_transient_error, upload)
+                .await
+                .map_err(|e| multipart_error(&format!("part {}", part_number), key, e))?;
+            Ok::<_, RegenError>(CompletedPart {
+                e_tag: output.e_tag,
+                part_number: Some(part_number),
+            })
+        }
+    });
+    let result = match try_join_all(parts).await {
+        Ok(parts) => {
+            let complete = || {
+                client.complete_multipart_upload(CompleteMultipartUploadRequest {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                    upload_id: upload_id.clone(),
+                    multipart_upload: Some(CompletedMultipartUpload {
+                        parts: Some(parts.clone()),
+                    }),
+                    ..Default::default()
+                })
+            };
+            with_retry(key, max_retries, is_transient_error, complete)
+                .await
+                .map(drop)
+                .map_err(|e| multipart_error("completing", key, e))
+        }
+        Err(e) => Err(e),
+    };
+
+    if result.is_err() {
+        let abort = client.abort_multipart_upload(AbortMultipartUploadRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            upload_id: upload_id.clone(),
+            ..Default::default()
+        });
+        if let Err(e) = abort.await {
+            warn!(
+                "Could not abort multipart upload {} of {}, its parts may stay in the Space: {}",
+                upload_id, key, e
+            );
+        }
+    }
+    result
+}
+
+fn multipart_error<E: std::error::Error + 'static>(
+    stage: &str,
+    key: &str,
+    err: RusotoError<E>,
+) -> RegenError {
+    RegenError::MultipartUpload(format!("{} {}: {}", stage, key, err))
+}
+
+/// Whether a failed S3 request is worth retrying: dispatch failures (timeouts, dropped
+/// connections) and 5xx responses are, client errors such as AccessDenied are not.
+pub fn is_transient_error<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) => response.status.is_server_error(),
+        _ => false,
+    }
+}
+
+/// Wait before retry number `attempt` (from 1): `RETRY_BASE_DELAY` doubled for each earlier
+/// retry, plus `jitter` (0 to 1) of that again, capped at `MAX_RETRY_DELAY`. Doubling
+/// saturates, so any number of retries is safe.
+fn retry_delay(attempt: u32, jitter: f64) -> Duration {
+    let factor = 2u32
+        .checked_pow(attempt.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    let backoff = RETRY_BASE_DELAY.saturating_mul(factor).min(MAX_RETRY_DELAY);
+    (backoff + backoff.mul_f64(jitter)).min(MAX_RETRY_DELAY)
+}
+
+/// Runs `op`, retrying up to `max_retries` times while `is_transient` holds for its error.
+/// Waits grow exponentially from `RETRY_BASE_DELAY` with random jitter, see `retry_delay`.
+pub async fn with_retry<T, E, F, Fut>(
+    key: &str,
+    max_retries: u32,
+    is_transient: impl Fn(&E) -> bool,
+    mut op: F,
+) -> Result<T, E>
+where
+    E: std::fmt::Debug,
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < max_retries && is_transient(&e) => {
+                attempt += 1;
+                let delay = retry_delay(attempt, rand::thread_rng().r#gen::<f64>());
+                warn!(
+                    "  - Upload of {} failed ({:?}), retry {}/{} in {:?}",
+                    key, e, attempt, max_retries, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Reads the URL manifest, from the CSV unless only JSON is written. A missing file is an
+/// empty manifest, an unreadable one is replaced by its backup if that can be read. With
+/// `strict_csv` a malformed CSV is an error instead, and the backup isn't tried.
+fn read_manifest(
+    manifest_dir: &Path,
+    manifest_format: ManifestFormat,
+    strict_csv: bool,
+) -> Result<Vec<UploadRecord>, RegenError> {
+    let csv_path = &manifest_dir.join(URLS_CSV_FILE);
+    let json_path = &manifest_dir.join(URLS_JSON_FILE);
+    let mut rows = Vec::new();
+    if manifest_format != ManifestFormat::Json && csv_path.exists() {
+        info!("Reading existing CSV file: {}", csv_path.display());
+        rows = if strict_csv {
+            read_manifest_csv_strict(csv_path)?
+        } else {
+            read_with_backup(csv_path, read_manifest_csv)?
+        };
+        info!("Loaded {} existing rows from CSV.", rows.len());
+    } else if manifest_format == ManifestFormat::Json && json_path.exists() {
+        info!("Reading existing JSON file: {}", json_path.display());
+        rows = read_with_backup(json_path, read_manifest_json)?;
+        info!("Loaded {} existing rows from JSON.", rows.len());
+    }
+    Ok(rows)
+}
+
+fn read_manifest_csv(path: &Path) -> Result<Vec<UploadRecord>, RegenError> {
+    let mut rows = Vec::new();
+    // Flexible, since rows appended by different versions can have different lengths
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| csv_error(path, e))?;
+    for result in rdr.records() {
+        let record = result.map_err(|e| csv_error(path, e))?;
+        // Older manifests had fewer columns, the missing ones are left empty
+        if matches!(record.len(), 1 | 2 | 4 | 5 | 6) {
+            rows.push(upload_record(&record));
+        }
+    }
+    Ok(rows)
+}
+
+/// `read_manifest_csv` for `--strict-csv`: the header must be exactly `MANIFEST_COLUMNS` and
+/// every row must have all of them, otherwise the first offending line is reported.
+fn read_manifest_csv_strict(path: &Path) -> Result<Vec<UploadRecord>, RegenError> {
+    let malformed =
+        |line: u64, reason: String| RegenError::Manifest(path.to_path_buf(), line, reason);
+    // Flexible, so a short or long row is reported here with its line rather than as a
+    // generic length mismatch against the header
+    let mut rdr = ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| csv_error(path, e))?;
+    let header = rdr.headers().map_err(|e| csv_error(path, e))?.clone();
+    if header.iter().ne(MANIFEST_COLUMNS) {
+        return Err(malformed(
+            1,
+            format!(
+                "expected header {}, found {}",
+                MANIFEST_COLUMNS.join(","),
+                header.iter().collect::<Vec<_>>().join(",")
+            ),
+        ));
+    }
+    let mut rows = Vec::new();
+    for result in rdr.records() {
+        let record = result.map_err(|e| csv_error(path, e))?;
+        if record.len() != MANIFEST_COLUMNS.len() {
+            let line = record.position().map_or(0, |position| position.line());
+            return Err(malformed(
+                line,
+                format!(
+                    "expected {} columns, found {}",
+                    MANIFEST_COLUMNS.len(),
+                    record.len()
+                ),
+            ));
+        }
+        rows.push(upload_record(&record));
+    }
+    Ok(rows)
+}
+
+/// Maps a CSV row onto the manifest columns, leaving any missing ones empty.
+fn upload_record(record: &csv::StringRecord) -> UploadRecord {
+    let column = |i: usize| record.get(i).unwrap_or_default().to_string();
+    UploadRecord {
+        cdn_url: column(0),
+        origin_url: column(1),
+        file_name: column(2),
+        file_size_kib: column(3),
+        sha256: column(4),
+        thumbnail_url: column(5),
+    }
+}
+
+fn read_manifest_json(path: &Path) -> Result<Vec<UploadRecord>, RegenError> {
+    serde_json::from_reader(fs::File::open(path)?).map_err(|e| {
+        if e.is_io() {
+            RegenError::Io(e.into())
+        } else {
+            RegenError::Manifest(path.to_path_buf(), e.line() as u64, e.to_string())
+        }
+    })
+}
+
+/// Turns a CSV error into the I/O error behind it, or a `Manifest` error on the line it was
+/// found on.
+fn csv_error(path: &Path, err: csv::Error) -> RegenError {
+    let line = err.position().map_or(0, |position| position.line());
+    let message = err.to_string();
+    match err.into_kind() {
+        csv::ErrorKind::Io(e) => RegenError::Io(e),
+        _ => RegenError::Manifest(path.to_path_buf(), line, message),
+    }
+}
+
+/// Parser for one manifest file format.
+type ManifestReader = fn(&Path) -> Result<Vec<UploadRecord>, RegenError>;
+
+/// Where the previous version of a manifest file is kept, e.g. `urls.csv.bak`.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+/// Reads a manifest file with `read`, falling back to its backup if the file itself can't be
+/// read. The file's own error is returned when the backup doesn't help either.
+fn read_with_backup(path: &Path, read: ManifestReader) -> Result<Vec<UploadRecord>, RegenError> {
+    let err = match read(path) {
+        Ok(rows) => return Ok(rows),
+        Err(e) => e,
+    };
+    let backup = backup_path(path);
+    if backup.exists()
+        && let Ok(rows) = read(&backup)
+    {
+        warn!(
+            "Could not read {} ({}), using the backup {} instead",
+            path.display(),
+            err,
+            backup.display()
+        );
+        return Ok(rows);
+    }
+    Err(err)
+}
+
+/// Rewrites a manifest file through a temporary file that is renamed over it, so a crash never
+/// leaves it half-written. The current version is first copied to its backup, unless `read`
+/// can't make sense of it, so a good backup isn't replaced by a broken file.
+fn replace_manifest(
+    path: &Path,
+    read: ManifestReader,
+    write: impl FnOnce(&Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    write(&temp_path)?;
+    if path.exists() && read(path).is_ok() {
+        fs::copy(path, backup_path(path))?;
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Result of checking one manifest row against the Space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectCheck {
+    Ok,
+    Missing,
+    SizeMismatch,
+    Failed,
+}
+
+/// Counts of each `ObjectCheck` outcome over a manifest.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VerifyStats {
+    pub ok: u64,
+    pub missing: u64,
+    pub mismatched: u64,
+    pub failed: u64,
+}
+
+/// HEADs every object in the manifest that belongs to `target` and compares its size with the
+/// recorded one. Rows for other Spaces are skipped.
+async fn verify(
+    target: &UploadTarget,
+    manifest_dir: &Path,
+    manifest_format: ManifestFormat,
+    strict_csv: bool,
+) -> Result<VerifyStats, Box<dyn std::error::Error + Send + Sync>> {
+    target.validate()?;
+    let rows = read_manifest(manifest_dir, manifest_format, strict_csv)?;
+    if let Some(dir) = target.local_dir() {
+        return Ok(verify_local(dir, &target.object_url("", false), &rows));
+    }
+    let credentials = target.credentials.provider()?;
+    let s3_client = s3_client(target.region(), target.endpoint.as_deref(), credentials);
+    let base_url = target.object_url("", false);
+
+    let checks = rows.iter().filter_map(|row| {
+        let Some(key) = row.origin_url.strip_prefix(&base_url) else {
+            info!("Skipping {}: not in {}", row.origin_url, base_url);
+            return None;
+        };
+        let request = HeadObjectRequest {
+            bucket: target.bucket().to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        let s3_client = &s3_client;
+        Some(async move {
+            let check = match s3_client.head_object(request).await {
+                Ok(head) => {
+                    let size_kib = head.content_length.map(|bytes| format_kib(bytes as u64));
+                    // The manifest records the local size, a gzipped object is stored smaller
+                    let compressed = head.content_encoding.as_deref() == Some("gzip");
+                    if row.file_size_kib.is_empty()
+                        || compressed
+                        || size_kib.as_ref() == Some(&row.file_size_kib)
+                    {
+                        ObjectCheck::Ok
+                    } else {
+                        warn!(
+                            "Size mismatch for {}: manifest has {} KiB, Space has {} KiB",
+                            key,
+                            row.file_size_kib,
+                            size_kib.unwrap_or_default()
+                        );
+                        ObjectCheck::SizeMismatch
+                    }
+                }
+                // HEAD responses have no body, so a missing key usually arrives as a bare 404
+                Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => ObjectCheck::Missing,
+                Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => {
+                    ObjectCheck::Missing
+                }
+                Err(e) => {
+                    error!("  - Failed to check {}: {:?}", key, e);
+                    ObjectCheck::Failed
+                }
+            };
+            if check == ObjectCheck::Missing {
+                warn!("Missing object: {}", key);
+            }
+            check
+        })
+    });
+
+    let mut stats = VerifyStats::default();
+    for check in join_all(checks).await {
+        match check {
+            ObjectCheck::Ok => stats.ok += 1,
+            ObjectCheck::Missing => stats.missing += 1,
+            ObjectCheck::SizeMismatch => stats.mismatched += 1,
+            ObjectCheck::Failed => stats.failed += 1,
+        }
+    }
+    Ok(stats)
+}
+
+/// `verify` for the local backend: compares the manifest against the files under `dir`.
+fn verify_local(dir: &Path, base_url: &str, rows: &[UploadRecord]) -> VerifyStats {
+    let mut stats = VerifyStats::default();
+    for row in rows {
+        let Some(key) = row.origin_url.strip_prefix(base_url) else {
+            info!("Skipping {}: not in {}", row.origin_url, base_url);
+            continue;
+        };
+        match fs::metadata(dir.join(key)) {
+            Ok(meta)
+                if row.file_size_kib.is_empty() || format_kib(meta.len()) == row.file_size_kib =>
+            {
+                stats.ok += 1
+            }
+            Ok(meta) => {
+                warn!(
+                    "Size mismatch for {}: manifest has {} KiB, directory has {} KiB",
+                    key,
+                    row.file_size_kib,
+                    format_kib(meta.len())
+                );
+                stats.mismatched += 1;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("Missing object: {}", key);
+                stats.missing += 1;
+            }
+            Err(e) => {
+                error!("  - Failed to check {}: {}", key, e);
+                stats.failed += 1;
+            }
+        }
+    }
+    stats
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload(
+    output_dir: &Path,
+    target: &UploadTarget,
+    flatten: bool,
+    only_ext: &[String],
+    max_file_size: Option<u64>,
+    retries: u32,
+    dry_run: bool,
+    object: &ObjectSettings,
+    manifest_dir: &Path,
+    manifest_format: ManifestFormat,
+    sort: ManifestSort,
+    price_per_gb: Option<f64>,
+    flush_every: Option<u64>,
+    strict_csv: bool,
+    public: &PublicUpload,
+    shutdown: &CancellationToken,
+    restart: bool,
+) -> Result<UploadStats, Box<dyn std::error::Error + Send + Sync>> {
+    // Upload all files from the output folder
+    let test_folder = output_dir;
+    if !test_folder.exists() {
+        warn!(
+            "No images to upload: {} folder does not exist.",
+            test_folder.display()
+        );
+        return Ok(UploadStats::default());
+    }
+
+    validate_upload(target, object, dry_run)?;
+    // All or nothing, so a run never leaves the Space with only part of a batch
+    if let Some(limit) = max_file_size {
+        let oversized: Vec<_> = plan_object_names(test_folder, flatten, only_ext)?
+            .into_iter()
+            .filter_map(|(path, _)| {
+                let size = fs::metadata(&path).ok()?.len();
+                (size > limit)
+                    .then(|| format!("{} ({})", path.display(), human_readable_size(size)))
+            })
+            .collect();
+        if !oversized.is_empty() {
+            return Err(RegenError::Oversized(limit, oversized).into());
+        }
+    }
+    // Shown for dry runs too, so the size of a batch can be checked before committing to it
+    let planned = plan_object_names(test_folder, flatten, only_ext)?;
+    let total = planned_size(&planned);
+    let what = format!("{} files ({})", planned.len(), human_readable_size(total));
+    println!("Planned upload: {}", what);
+    if let Some(price) = price_per_gb {
+        println!(
+            "Estimated storage cost: ${:.2} per month at ${}/GB",
+            storage_cost(total, price),
+            price
+        );
+    }
+    if !dry_run && !public.confirm(target, object, &what)? {
+        println!("Upload cancelled");
+        return Ok(UploadStats::default());
+    }
+
+    let bucket = target.bucket();
+    let region = target.region();
+    let space_prefix = target.key_prefix();
+
+    // Resume where an unfinished run stopped, unless asked to start over
+    let state_path = &output_dir.join(UPLOAD_STATE_FILE);
+    let base_url = target.object_url("", false);
+    let checkpoint = if restart {
+        UploadCheckpoint::new(state_path, &base_url)
+    } else {
+        UploadCheckpoint::load(state_path, &base_url)?
+    };
+    let checkpoint = Arc::new(Mutex::new(checkpoint));
+
+    // With --flush-every the manifest gets the rows for files uploaded since the last flush
+    // along the way, so a crash doesn't lose the record of the whole run
+    let flush_every = flush_every.filter(|_| !dry_run && object.acl.is_public());
+    let mut flushed = HashSet::new();
+    let mut flush = |uploaded: &HashSet<PathBuf>| {
+        let fresh: HashSet<PathBuf> = uploaded.difference(&flushed).cloned().collect();
+        match update_manifest(
+            test_folder,
+            flatten,
+            only_ext,
+            uploaded,
+            target,
+            object,
+            manifest_dir,
+            manifest_format,
+            sort,
+            strict_csv,
+            Some(&fresh),
+        ) {
+            Ok(()) => {
+                info!("Flushed the URL manifest after {} files", uploaded.len());
+                flushed.extend(fresh);
+            }
+            Err(e) => warn!(
+                "Could not flush the URL manifest, it is written again at the end: {}",
+                e
+            ),
+        }
+    };
+
+    info!(
+        "Uploading folder {} to {} with prefix {}",
+        test_folder.display(),
+        base_url,
+        space_prefix
+    );
+
+    let stats = match upload_folder_to_do_space(
+        test_folder,
+        bucket,
+        region,
+        Some(&space_prefix),
+        target.endpoint.as_deref(),
+        target.local_dir(),
+        flatten,
+        only_ext,
+        retries,
+        dry_run,
+        object,
+        &target.credentials,
+        shutdown,
+        &checkpoint,
+        flush_every,
+        &mut flush,
+    )
+    .await
+    {
+        Ok(stats) => {
+            info!("\nFolder upload to DigitalOcean Spaces succeeded!");
+            stats
+        }
+        Err(e) => {
+            error!("\nFolder upload failed: {}", e);
+            let mut stats = UploadStats::default();
+            stats.fail(test_folder.display().to_string(), e.to_string());
+            stats
+        }
+    };
+    if dry_run {
+        info!("Dry run: not touching the URL CSV.");
+        return Ok(stats);
+    }
+    if stats.skipped > 0 {
+        println!("Skipped {} files uploaded by an earlier run", stats.skipped);
+    }
+    if stats.existing > 0 {
+        println!("Skipped {} files already in the Space", stats.existing);
+    }
+    // Keep the checkpoint only while there's something left to resume
+    if stats.failures == 0 && !shutdown.is_cancelled() {
+        UploadCheckpoint::clear(state_path)?;
+    }
+    let fresh: HashSet<PathBuf> = stats.uploaded.difference(&flushed).cloned().collect();
+    update_manifest(
+        test_folder,
+        flatten,
+        only_ext,
+        &stats.uploaded,
+        target,
+        object,
+        manifest_dir,
+        manifest_format,
+        sort,
+        strict_csv,
+        Some(&fresh),
+    )?;
+    Ok(stats)
+}
+
+/// Total size of the planned files, from their metadata. Files that vanished since the walk
+/// count as empty.
+pub fn planned_size(planned: &[(PathBuf, String)]) -> u64 {
+    planned
+        .iter()
+        .filter_map(|(path, _)| fs::metadata(path).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Monthly cost of storing `bytes` at `price_per_gb` per GB-month, with GB as 1024³ bytes like
+/// `human_readable_size`.
+pub fn storage_cost(bytes: u64, price_per_gb: f64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0 * 1024.0) * price_per_gb
+}
+
+/// Adds rows for the uploaded files among those planned from `output_dir` to the URL
+/// manifest. Thumbnails are recorded on their image's row rather than getting one of their
+/// own. Nothing is added for a private ACL, whose URLs wouldn't be readable.
+///
+/// With `fresh`, only rows whose image or thumbnail is in it are added or updated, so a
+/// manifest flushed part way through a run isn't rebuilt (and rehashed) from scratch each time.
+#[allow(clippy::too_many_arguments)]
+fn update_manifest(
+    output_dir: &Path,
+    flatten: bool,
+    only_ext: &[String],
+    uploaded: &HashSet<PathBuf>,
+    target: &UploadTarget,
+    object: &ObjectSettings,
+    manifest_dir: &Path,
+    manifest_format: ManifestFormat,
+    sort: ManifestSort,
+    strict_csv: bool,
+    fresh: Option<&HashSet<PathBuf>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !object.acl.is_public() {
+        warn!(
+            "Objects were uploaded with the {} ACL, their URLs aren't publicly readable. Not adding them to the URL CSV.",
+            object.acl.as_str()
+        );
+        return Ok(());
+    }
+    let space_prefix = target.key_prefix();
+
+    // After upload, append URLs to the manifest
+
+    // Paths to your manifest files
+    let csv_path = &manifest_dir.join(URLS_CSV_FILE);
+    let json_path = &manifest_dir.join(URLS_JSON_FILE);
+
+    // Read all files that made it to the Space. Thumbnails are recorded on their image's row
+    // rather than getting one of their own
+    let planned: Vec<_> = plan_object_names(output_dir, flatten, only_ext)?
+        .into_iter()
+        .filter(|(path, _)| uploaded.contains(path))
+        .collect();
+    let names: HashSet<&str> = planned.iter().map(|(_, name)| name.as_str()).collect();
+    let thumbnails: HashSet<String> = names
+        .iter()
+        .filter_map(|name| {
+            let thumbnail = thumbnail_name(name);
+            (thumbnail != *name).then_some(thumbnail)
+        })
+        .collect();
+    let fresh_names: Option<HashSet<&str>> = fresh.map(|fresh| {
+        planned
+            .iter()
+            .filter(|(path, _)| fresh.contains(path))
+            .map(|(_, name)| name.as_str())
+            .collect()
+    });
+    let mut urls = Vec::new();
+    for (file_path, file_name) in planned.iter().cloned() {
+        if thumbnails.contains(&file_name) {
+            continue;
+        }
+        if let Some(fresh) = &fresh_names
+            && !fresh.contains(file_name.as_str())
+            && !fresh.contains(thumbnail_name(&file_name).as_str())
+        {
+            continue;
+        }
+        let url = target.object_url(&format!("{}{}", space_prefix, file_name), true);
+        info!("Generated CDN URL for file {}: {}", file_name, url);
+        urls.push((file_path, file_name, url));
+    }
+
+    // Read the existing manifest (if any), preferring the CSV when it is being written
+    let mut existing_rows = read_manifest(manifest_dir, manifest_format, strict_csv)?;
+
+    // Append new URLs, avoiding duplicates
+    for (file_path, file, _cdn_url) in &urls {
+        let key = format!("{}{}", space_prefix, file);
+        let origin_url = target.object_url(&key, false);
+        let cdn_url = target.object_url(&key, true);
+        // File name
+        let file_name = Path::new(file)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(file);
+
+        // File size in KiB
+        let file_size_kib = match fs::metadata(file_path) {
+            Ok(meta) => format_kib(meta.len()),
+            Err(_) => {
+                warn!("Could not get metadata for file: {}", file_path.display());
+                String::from("")
+            }
+        };
+
+        let sha256 = file_sha256(file_path)?;
+        let thumbnail = thumbnail_name(file);
+        let thumbnail_url = if names.contains(thumbnail.as_str()) {
+            target.object_url(&format!("{}{}", space_prefix, thumbnail), true)
+        } else {
+            String::new()
+        };
+        let record = UploadRecord {
+            cdn_url,
+            origin_url,
+            file_name: file_name.to_string(),
+            file_size_kib,
+            sha256,
+            thumbnail_url,
+        };
+
+        // Rows are matched by URL; unchanged content is skipped, changed content replaces the row
+        match existing_rows
+            .iter_mut()
+            .find(|row| row.cdn_url == record.cdn_url)
+        {
+            Some(row) if *row == record => {
+                info!("Skipping unchanged file in manifest: {}", file);
+            }
+            Some(row) => {
+                info!(
+                    "Updating changed file in manifest: cdn_url={}, file_size_kib={}, sha256={}",
+                    record.cdn_url, record.file_size_kib, record.sha256
+                );
+                *row = record;
+            }
+            None => {
+                info!(
+                    "Appending new row to manifest: cdn_url={}, origin_url={}, file_name={}, file_size_kib={}, sha256={}",
+                    record.cdn_url,
+                    record.origin_url,
+                    record.file_name,
+                    record.file_size_kib,
+                    record.sha256
+                );
+                existing_rows.push(record);
+            }
+        }
+    }
+
+    // Rebuilt in a fixed order, so the files diff cleanly whatever order the walk found them in
+    sort.sort(&mut existing_rows);
+
+    if manifest_format != ManifestFormat::Json {
+        // Write back to CSV (cdn_url, origin_url columns)
+        info!(
+            "Writing {} rows to CSV file: {}",
+            existing_rows.len(),
+            csv_path.display()
+        );
+        replace_manifest(csv_path, read_manifest_csv, |path| {
+            let mut wtr = WriterBuilder::new().has_headers(true).from_path(path)?;
+            wtr.write_record(MANIFEST_COLUMNS)?;
+            for row in &existing_rows {
+                wtr.write_record([
+                    &row.cdn_url,
+                    &row.origin_url,
+                    &row.file_name,
+                    &row.file_size_kib,
+                    &row.sha256,
+                    &row.thumbnail_url,
+                ])?;
+            }
+            wtr.flush()?;
+            Ok(())
+        })?;
+        info!("CSV file write complete.");
+    }
+
+    if manifest_format != ManifestFormat::Csv {
+        info!(
+            "Writing {} rows to JSON file: {}",
+            existing_rows.len(),
+            json_path.display()
+        );
+        replace_manifest(json_path, read_manifest_json, |path| {
+            let mut file = fs::File::create(path)?;
+            serde_json::to_writer_pretty(&mut file, &existing_rows)?;
+            file.write_all(b"\n")?;
+            Ok(())
+        })?;
+        info!("JSON file write complete.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh, empty directory under the system temp dir for one test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("regen-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn metrics_file_is_prometheus_exposition_format() {
+        let path = scratch_dir("metrics").join("regen.prom");
+        let batch = BatchMetrics {
+            images_generated: 3,
+            render_seconds: 1.5,
+            upload_bytes: 2048,
+            failures: 1,
+        };
+        write_metrics_file(&path, &batch).unwrap();
+        write_metrics_file(&path, &batch).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let mut samples = Vec::new();
+        for line in contents.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut parts = comment.splitn(3, ' ');
+                let keyword = parts.next().unwrap();
+                let name = parts.next().unwrap();
+                assert!(keyword == "HELP" || keyword == "TYPE", "{}", line);
+                assert!(name.starts_with("regen_"), "{}", line);
+                if keyword == "TYPE" {
+                    assert_eq!(parts.next(), Some("counter"));
+                }
+                continue;
+            }
+            let (name, value) = line.split_once(' ').unwrap();
+            assert!(
+                name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "{}",
+                line
+            );
+            samples.push((name.to_string(), value.parse::<f64>().unwrap()));
+        }
+        assert_eq!(
+            samples,
+            [
+                (METRIC_IMAGES_GENERATED.to_string(), 6.0),
+                (METRIC_RENDER_SECONDS.to_string(), 3.0),
+                (METRIC_UPLOAD_BYTES.to_string(), 4096.0),
+                (METRIC_FAILURES.to_string(), 2.0),
+            ]
+        );
+        assert!(!path.with_extension("prom.tmp").exists());
+    }
+
+    #[test]
+    fn dumped_view_bounds_match_the_formula() {
+        let line = view_bounds_json(7, 400, 200, -0.5, 0.25, 0.5, Aspect::Pixel);
+        let dumped: serde_json::Value = serde_json::from_str(&line).unwrap();
+        // 4 × view_scale wide, and half as tall as that for a 2:1 image
+        let expected = [
+            ("image", 7.0),
+            ("width", 400.0),
+            ("height", 200.0),
+            ("x_pos", -0.5),
+            ("y_pos", 0.25),
+            ("view_scale", 0.5),
+            ("x_min", -1.5),
+            ("x_max", 0.5),
+            ("y_min", -0.25),
+            ("y_max", 0.75),
+        ];
+        for (key, value) in expected {
+            assert_eq!(dumped[key].as_f64(), Some(value), "{}", key);
+        }
+    }
+
+    #[test]
+    fn saves_with_and_without_fsync() {
+        let dir = scratch_dir("fsync");
+        for fsync in [true, false] {
+            let path = dir.join(format!("nested/fsync_{}.bin", fsync));
+            save_with_retry(&path, b"fractal", fsync).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), b"fractal");
+        }
+    }
+
+    #[test]
+    fn auto_iterations_only_bumps_under_iterated_windows() {
+        // Seahorse valley: with few iterations nearly every point still looks in-set
+        let black_ratio = |max_iterations| {
+            let (_, stats) = render_with_stats(&GenParams {
+                x_pos: -0.745,
+                y_pos: 0.113,
+                view_scale: 0.005,
+                max_iterations,
+                ..small_params()
+            })
+            .unwrap();
+            stats.black_ratio
+        };
+        assert!(looks_under_iterated(
+            Pattern::Mandelbrot,
+            black_ratio(20),
+            20
+        ));
+        assert!(!looks_under_iterated(
+            Pattern::Mandelbrot,
+            black_ratio(2000),
+            2000
+        ));
+        // Never past the cap, and never for patterns without an in-set region
+        assert!(!looks_under_iterated(
+            Pattern::Mandelbrot,
+            1.0,
+            AUTO_ITERATIONS_CAP
+        ));
+        assert!(!looks_under_iterated(Pattern::Noise, 1.0, 20));
+    }
+
+    #[test]
+    fn flatten_makes_keys_flat_and_disambiguates_collisions() {
+        let dir = scratch_dir("flatten");
+        for file in [
+            "a.png",
+            "nested/a.png",
+            "nested/deeper/a.png",
+            "nested/b.png",
+        ] {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"png").unwrap();
+        }
+        let names = |flatten| -> Vec<String> {
+            plan_object_names(&dir, flatten, &[])
+                .unwrap()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect()
+        };
+        assert_eq!(
+            names(false),
+            [
+                "a.png",
+                "nested/a.png",
+                "nested/b.png",
+                "nested/deeper/a.png"
+            ]
+        );
+        assert_eq!(names(true), ["a.png", "a_1.png", "b.png", "a_2.png"]);
+    }
+
+    #[test]
+    fn generate_takes_size_and_pattern_flags() {
+        let args = [
+            "regen",
+            "generate",
+            "--count",
+            "1",
+            "--width",
+            "256",
+            "--height",
+            "256",
+            "--pattern",
+            "mandelbrot",
+        ];
+        let Commands::Generate {
+            count,
+            width,
+            height,
+            pattern,
+            ..
+        } = Cli::try_parse_from(args).unwrap().command
+        else {
+            panic!("expected the generate command");
+        };
+        assert_eq!(count, Some(1));
+        assert_eq!((width, height), (Some(256), Some(256)));
+        assert_eq!(pattern, Pattern::Mandelbrot);
+
+        for zero in [["--width", "0"], ["--height", "0"]] {
+            assert!(
+                Cli::try_parse_from(["regen", "generate", "--count", "1", zero[0], zero[1]])
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn padding_chunk_keeps_the_png_decodable() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(7, 5, Rgb([10, 20, 30])));
+        let mut bytes = encode_image(&img, OutputFormat::Png, 0).unwrap();
+        let unpadded = bytes.len();
+        let noise: Vec<u8> = (0..5000).map(|i| (i * 31 % 251) as u8).collect();
+        insert_png_chunk(&mut bytes, PADDING_CHUNK_TYPE, &noise).unwrap();
+
+        // Length, type and CRC around the payload
+        assert_eq!(bytes.len(), unpadded + noise.len() + 12);
+        assert!(bytes.ends_with(&PNG_IEND));
+        let decoded = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (7, 5));
+        assert_eq!(decoded.to_rgb8(), img.to_rgb8());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
+    #[test]
+    fn viewer_command_opens_the_given_path() {
+        let command = viewer_command("out/a b.png").unwrap();
+        assert_eq!(command.get_args().last(), Some("out/a b.png".as_ref()));
+        if cfg!(target_os = "windows") {
+            assert_eq!(command.get_program(), "cmd");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preview_rejects_a_path_that_is_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"image-\xff.png"));
+        match preview_image(path) {
+            Err(RegenError::PathConversion(message)) => {
+                assert_eq!(message, "Invalid path for preview")
+            }
+            other => panic!("expected a path conversion error, got {:?}", other),
+        }
+    }
+
+    /// Small Mandelbrot render of the default view, as `generate` records it.
+    fn small_params() -> GenParams {
+        let (x_pos, y_pos, view_scale, max_iterations, smoothness, color_step) =
+            DEFAULT_MANDELBROT_PARAMS;
+        GenParams {
+            pattern: Pattern::Mandelbrot,
+            width: 32,
+            height: 24,
+            x_pos,
+            y_pos,
+            view_scale,
+            max_iterations,
+            smoothness,
+            color_step,
+            palette: Palette::Grayscale,
+            palette_stops: Vec::new(),
+            palette_offset: 0.0,
+            coloring: Coloring::Escape,
+            color_clamp: ColorClamp::Clamp,
+            trap: Trap::Point,
+            supersample: 1,
+            julia_constant: None,
+            power: DEFAULT_POWER,
+            bailout: DEFAULT_BAILOUT,
+            seed: Some(0),
+            index: 0,
+            deep_zoom: false,
+            aspect: Aspect::Pixel,
+            rgba: false,
+            pixel_center: true,
+            tile_size: DEFAULT_TILE_SIZE,
+        }
+    }
+
+    #[test]
+    fn failures_come_back_as_their_own_variants() {
+        let dir = scratch_dir("error-variants");
+        let params = small_params();
+        let save = |output_dir: &Path, tile_size| {
+            generate_mathematical_image(
+                &GenParams {
+                    tile_size,
+                    ..params.clone()
+                },
+                output_dir,
+                "a.png",
+                false,
+                OutputFormat::Png,
+                0,
+            )
+        };
+        assert!(save(&dir, DEFAULT_TILE_SIZE).is_ok());
+        // A file where the output directory should be
+        let blocked = dir.join("a.png");
+        assert!(matches!(
+            save(&blocked, DEFAULT_TILE_SIZE),
+            Err(RegenError::Io(_))
+        ));
+        assert!(matches!(save(&dir, 0), Err(RegenError::InvalidParams(_))));
+
+        let empty = DynamicImage::new_rgb8(0, 0);
+        assert!(matches!(
+            encode_image(&empty, OutputFormat::Png, 0),
+            Err(RegenError::ImageDecode(_))
+        ));
+
+        let no_keys = Credentials {
+            source: CredentialSource::Keys,
+            access_key: None,
+            secret_key: None,
+        };
+        assert!(matches!(
+            no_keys.provider(),
+            Err(RegenError::MissingCredentials)
+        ));
+    }
+
+    #[test]
+    fn upload_parses_bucket_region_and_prefix() {
+        let args = [
+            "regen", "upload", "--bucket", "b", "--region", "nyc3", "--prefix", "imgs/",
+        ];
+        let Commands::Upload { target, .. } = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected the upload command");
+        };
+        assert_eq!(target.backend, Backend::S3);
+        assert_eq!(target.bucket(), "b");
+        assert_eq!(target.region(), "nyc3");
+        assert_eq!(target.key_prefix(), "imgs/");
+        assert!(target.validate().is_ok());
+
+        let empty = ["regen", "upload", "--bucket", "", "--region", "nyc3"];
+        assert!(Cli::try_parse_from(empty).is_err());
+    }
+
+    #[test]
+    fn custom_endpoint_replaces_spaces_in_the_region_and_urls() {
+        assert_eq!(
+            s3_region("nyc3", None),
+            Region::Custom {
+                name: "nyc3".to_string(),
+                endpoint: "https://nyc3.digitaloceanspaces.com".to_string(),
+            }
+        );
+        assert_eq!(
+            s3_region("us-east-1", Some("http://localhost:9000")),
+            Region::Custom {
+                name: "us-east-1".to_string(),
+                endpoint: "http://localhost:9000".to_string(),
+            }
+        );
+
+        let args = [
+            "regen",
+            "upload",
+            "--bucket",
+            "b",
+            "--region",
+            "us-east-1",
+            "--endpoint",
+            "http://localhost:9000/",
+        ];
+        let Commands::Upload { target, .. } = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected the upload command");
+        };
+        for cdn in [true, false] {
+            assert_eq!(
+                target.object_url("fractals/a.png", cdn),
+                "http://localhost:9000/b/fractals/a.png"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_failures_are_retried_until_the_upload_lands() {
+        use rusoto_core::request::HttpDispatchError;
+        use rusoto_s3::PutObjectError;
+
+        let uploaded = Mutex::new(Vec::new());
+        let mut attempts = 0;
+        let result = with_retry("a.png", DEFAULT_UPLOAD_RETRIES, is_transient_error, || {
+            attempts += 1;
+            let attempt = attempts;
+            let uploaded = &uploaded;
+            async move {
+                if attempt <= 2 {
+                    return Err(RusotoError::<PutObjectError>::HttpDispatch(
+                        HttpDispatchError::new("connection reset".to_string()),
+                    ));
+                }
+                uploaded.lock().unwrap().push("a.png");
+                Ok(())
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+        assert_eq!(*uploaded.lock().unwrap(), ["a.png"]);
+
+        // Client errors like AccessDenied are not worth repeating
+        let mut attempts = 0;
+        let result: Result<(), _> =
+            with_retry("b.png", DEFAULT_UPLOAD_RETRIES, is_transient_error, || {
+                attempts += 1;
+                async {
+                    Err(RusotoError::<PutObjectError>::Validation(
+                        "AccessDenied".to_string(),
+                    ))
+                }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn zoom_radii_shrink_by_a_constant_factor() {
+        let radii = zoom_radii(1.0, 0.001, 4);
+        assert_eq!(radii.len(), 4);
+        assert_eq!(radii[0], 1.0);
+        assert!((radii[3] - 0.001).abs() < 1e-12);
+        for pair in radii.windows(2) {
+            assert!(pair[1] < pair[0]);
+            assert!((pair[1] / pair[0] - 0.1).abs() < 1e-9);
+        }
+        assert_eq!(zoom_radii(2.0, 0.5, 1), [2.0]);
+    }
+
+    #[test]
+    fn thumbnail_fits_the_bound_and_keeps_the_aspect_ratio() {
+        let dir = scratch_dir("thumbnail");
+        let source = DynamicImage::new_rgb8(2000, 1000);
+        let path = dir.join("wide.png");
+        let thumb = write_thumbnail(&path, &source, 300, OutputFormat::Png, 0).unwrap();
+        assert_eq!(thumb, dir.join("wide_thumb.png"));
+        let thumb = image::open(thumb).unwrap();
+        assert_eq!((thumb.width(), thumb.height()), (300, 150));
+
+        let tall = write_thumbnail(
+            &dir.join("tall.jpg"),
+            &DynamicImage::new_rgb8(500, 1000),
+            64,
+            OutputFormat::Jpeg,
+            80,
+        )
+        .unwrap();
+        let tall = image::open(tall).unwrap();
+        assert_eq!((tall.width(), tall.height()), (32, 64));
+    }
+
+    #[test]
+    fn params_round_trip_through_the_saved_png() {
+        let dir = scratch_dir("params-round-trip");
+        let params = GenParams {
+            julia_constant: Some((-0.4, 0.6)),
+            pattern: Pattern::Julia,
+            ..small_params()
+        };
+        let png = generate_mathematical_image(&params, &dir, "a.png", false, OutputFormat::Png, 0)
+            .unwrap();
+        assert_eq!(read_params(&png), Some(params.clone()));
+
+        let jpeg =
+            generate_mathematical_image(&params, &dir, "a.jpg", false, OutputFormat::Jpeg, 90)
+                .unwrap();
+        assert_eq!(read_params(&jpeg), None);
+    }
+
+    #[test]
+    fn dir_stats_totals_two_known_images() {
+        let dir = scratch_dir("stats");
+        DynamicImage::new_rgb8(10, 20)
+            .save(dir.join("a.png"))
+            .unwrap();
+        fs::create_dir(dir.join("nested")).unwrap();
+        DynamicImage::new_rgb8(30, 40)
+            .save(dir.join("nested/b.jpg"))
+            .unwrap();
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+        fs::write(dir.join("broken.png"), "not a png").unwrap();
+
+        let stats = dir_stats(&dir);
+        let size = |name: &str| fs::metadata(dir.join(name)).unwrap().len();
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.unreadable, 1);
+        assert_eq!(stats.total_bytes, size("a.png") + size("nested/b.jpg"));
+        assert_eq!(stats.min_dimensions, Some((10, 20)));
+        assert_eq!(stats.max_dimensions, Some((30, 40)));
+        assert_eq!(stats.avg_dimensions(), Some((20, 30)));
+        assert_eq!(
+            stats.formats,
+            BTreeMap::from([("jpg".to_string(), 1), ("png".to_string(), 1)])
+        );
+        assert_eq!(dir_stats(&dir.join("nested")).count, 1);
+    }
+
+    #[tokio::test]
+    async fn no_task_starts_after_cancellation_but_running_ones_finish() {
+        let permits = Arc::new(Semaphore::new(1));
+        let shutdown = CancellationToken::new();
+        let running = start_unless_cancelled(Arc::clone(&permits), &shutdown)
+            .await
+            .expect("nothing has been cancelled yet");
+
+        let (finished_tx, finished_rx) = tokio::sync::oneshot::channel();
+        let in_flight = tokio::spawn(async move {
+            let _permit = running;
+            finished_rx.await.unwrap();
+            "finished"
+        });
+        let waiting = tokio::spawn({
+            let (permits, shutdown) = (Arc::clone(&permits), shutdown.clone());
+            async move { start_unless_cancelled(permits, &shutdown).await.is_some() }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        shutdown.cancel();
+        finished_tx.send(()).unwrap();
+        assert_eq!(in_flight.await.unwrap(), "finished");
+        assert!(!waiting.await.unwrap(), "a task started after cancellation");
+        assert!(start_unless_cancelled(permits, &shutdown).await.is_none());
+    }
+
+    #[test]
+    fn verbose_and_quiet_flags_pick_the_log_level() {
+        let level = |args: &[&str]| {
+            let cli =
+                Cli::try_parse_from([&["regen"], args, &["list", "patterns"]].concat()).unwrap();
+            log_level(cli.verbose, cli.quiet)
+        };
+        assert_eq!(level(&[]), None);
+        assert_eq!(level(&["-v"]), Some(LevelFilter::Info));
+        assert_eq!(level(&["-vv"]), Some(LevelFilter::Debug));
+        assert_eq!(level(&["-v", "--verbose", "-v"]), Some(LevelFilter::Trace));
+        assert_eq!(level(&["-vvvvv"]), Some(LevelFilter::Trace));
+        assert_eq!(level(&["--quiet"]), Some(LevelFilter::Error));
+        assert!(Cli::try_parse_from(["regen", "-v", "-q", "list", "patterns"]).is_err());
+        // The flags are global, so they also work after the subcommand
+        let cli = Cli::try_parse_from(["regen", "list", "patterns", "-vv"]).unwrap();
+        assert_eq!(log_level(cli.verbose, cli.quiet), Some(LevelFilter::Debug));
+    }
+
+    #[test]
+    fn accepted_render_is_encoded_and_padded_in_memory() {
+        let params = small_params();
+        let img = DynamicImage::ImageRgb8(regen::render::render(&params).unwrap());
+        let encoded = encode_generated(&img, &params, OutputFormat::Png, 0).unwrap();
+        let noise = [7u8; 64];
+
+        for strategy in [PadStrategy::Chunk, PadStrategy::Append, PadStrategy::None] {
+            let mut bytes = encoded.clone();
+            pad_png(&mut bytes, strategy, &noise).unwrap();
+            match strategy {
+                PadStrategy::Chunk => assert_eq!(bytes.len(), encoded.len() + noise.len() + 12),
+                PadStrategy::Append => assert_eq!(bytes, [&encoded[..], &noise[..]].concat()),
+                PadStrategy::None => assert_eq!(bytes, encoded),
+            }
+            if strategy != PadStrategy::Append {
+                let decoded = image::load_from_memory(&bytes).unwrap();
+                assert_eq!(decoded.to_rgb8(), img.to_rgb8(), "{:?}", strategy);
+            }
+        }
+    }
+
+    #[test]
+    fn zoom_two_halves_the_view_width_around_the_center() {
+        let bounds = |center: (f64, f64), zoom: f64| {
+            view_bounds(
+                400,
+                300,
+                center.0,
+                center.1,
+                zoom_view_scale(zoom),
+                Aspect::Pixel,
+            )
+        };
+        let classic = bounds(CLASSIC_VIEW_CENTER, 1.0);
+        assert!((classic.x_min + 2.5).abs() < 1e-12 && (classic.x_max - 1.0).abs() < 1e-12);
+
+        let center = (-0.1, 0.65);
+        let (one, two) = (bounds(center, 1.0), bounds(center, 2.0));
+        let width = |b: &regen::render::ViewBounds| b.x_max - b.x_min;
+        let height = |b: &regen::render::ViewBounds| b.y_max - b.y_min;
+        assert!((width(&two) - width(&one) / 2.0).abs() < 1e-12);
+        assert!((height(&two) - height(&one) / 2.0).abs() < 1e-12);
+        for b in [&one, &two] {
+            assert!(((b.x_min + b.x_max) / 2.0 - center.0).abs() < 1e-12);
+            assert!(((b.y_min + b.y_max) / 2.0 - center.1).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn corrupt_manifest_falls_back_to_its_backup() {
+        let dir = scratch_dir("manifest-backup");
+        let path = dir.join("urls.csv");
+        let write = |name: &str| {
+            let contents = format!(
+                "{}\ncdn/{name},origin/{name},{name},1.00\n",
+                MANIFEST_COLUMNS[..4].join(",")
+            );
+            replace_manifest(&path, read_manifest_csv, |temp| {
+                Ok(fs::write(temp, contents)?)
+            })
+            .unwrap();
+        };
+        let names = |rows: Vec<UploadRecord>| -> Vec<String> {
+            rows.into_iter().map(|row| row.file_name).collect()
+        };
+
+        write("first.png");
+        assert!(!backup_path(&path).exists());
+        write("second.png");
+        assert_eq!(
+            names(read_manifest_csv(&backup_path(&path)).unwrap()),
+            ["first.png"]
+        );
+        assert!(!dir.join("urls.csv.tmp").exists());
+
+        // Not UTF-8, so the CSV reader gives up on it
+        fs::write(&path, b"cdn_url\n\xff\xfe\n").unwrap();
+        assert!(read_manifest_csv(&path).is_err());
+        assert_eq!(
+            names(read_with_backup(&path, read_manifest_csv).unwrap()),
+            ["first.png"]
+        );
+
+        // The broken file is replaced without overwriting the good backup
+        write("third.png");
+        assert_eq!(names(read_manifest_csv(&path).unwrap()), ["third.png"]);
+        assert_eq!(
+            names(read_manifest_csv(&backup_path(&path)).unwrap()),
+            ["first.png"]
+        );
+
+        fs::remove_file(backup_path(&path)).unwrap();
+        fs::write(&path, b"cdn_url\n\xff\xfe\n").unwrap();
+        assert!(read_with_backup(&path, read_manifest_csv).is_err());
+    }
+
+    #[test]
+    fn strict_csv_accepts_full_rows_and_reports_the_first_bad_line() {
+        let dir = scratch_dir("strict-csv");
+        let header = MANIFEST_COLUMNS.join(",");
+        let row = |name: &str| format!("cdn/{name},origin/{name},{name},1.00,abc,thumb/{name}");
+        let read = |name: &str, contents: String| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            (read_manifest_csv_strict(&path), read_manifest_csv(&path))
+        };
+        let line_of = |result: Result<Vec<UploadRecord>, RegenError>| -> (u64, String) {
+            match result {
+                Err(RegenError::Manifest(_, line, reason)) => (line, reason),
+                other => panic!("expected a manifest error, got {:?}", other),
+            }
+        };
+
+        let (strict, lenient) = read(
+            "good.csv",
+            format!("{}\n{}\n{}\n", header, row("a"), row("b")),
+        );
+        let strict = strict.unwrap();
+        assert_eq!(strict, lenient.unwrap());
+        assert_eq!(strict[1].file_name, "b");
+        assert_eq!(strict[1].thumbnail_url, "thumb/b");
+
+        let (strict, lenient) = read(
+            "short.csv",
+            format!("{}\n{}\ncdn/c,origin/c,c,1.00\n", header, row("a")),
+        );
+        assert_eq!(
+            line_of(strict),
+            (3, "expected 6 columns, found 4".to_string())
+        );
+        // Lenient mode keeps reading old four-column rows
+        assert_eq!(lenient.unwrap()[1].file_size_kib, "1.00");
+
+        let (strict, lenient) = read("header.csv", format!("url,name\n{}\n", row("a")));
+        let (line, reason) = line_of(strict);
+        assert_eq!(line, 1);
+        assert!(
+            reason.starts_with(&format!("expected header {}", header)),
+            "{}",
+            reason
+        );
+        assert!(lenient.is_ok());
+
+        // JSON errors carry their line too, and a missing file stays an I/O error
+        let json = dir.join("urls.json");
+        fs::write(&json, "[\n  {\"cdn_url\": }\n]\n").unwrap();
+        assert_eq!(line_of(read_manifest_json(&json)).0, 2);
+        assert!(matches!(
+            read_manifest_json(&dir.join("missing.json")),
+            Err(RegenError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn tags_serialize_into_an_encoded_tag_set() {
+        let parse = |args: &[&str]| {
+            let args = [
+                &["regen", "upload", "--bucket", "b", "--region", "nyc3"],
+                args,
+            ]
+            .concat();
+            Cli::try_parse_from(args).map(|cli| match cli.command {
+                Commands::Upload { object, .. } => object,
+                _ => panic!("expected the upload command"),
+            })
+        };
+        let object = parse(&[
+            "--tag",
+            "team=fractal art",
+            "--tag",
+            "path=a/b&c=d",
+            "--tag",
+            "ünï=x~y.z_-",
+        ])
+        .unwrap();
+        assert_eq!(
+            object.tagging().as_deref(),
+            Some("team=fractal%20art&path=a%2Fb%26c%3Dd&%C3%BCn%C3%AF=x~y.z_-")
+        );
+        assert_eq!(parse(&[]).unwrap().tagging(), None);
+
+        for bad in [
+            "novalue",
+            "=value",
+            "key=",
+            &format!("{}=v", "k".repeat(129)),
+        ] {
+            assert!(parse(&["--tag", bad]).is_err(), "{}", bad);
+        }
+        assert!(parse(&["--tag", &format!("{}=v", "k".repeat(128))]).is_ok());
+    }
+
+    #[test]
+    fn url_templates_render_with_the_bucket_region_prefix_and_file() {
+        let target = |extra: &[&str]| {
+            let args = [
+                &[
+                    "regen", "upload", "--bucket", "art", "--region", "ams3", "--prefix", "imgs",
+                ],
+                extra,
+            ]
+            .concat();
+            let Commands::Upload { target, .. } = Cli::try_parse_from(args).unwrap().command else {
+                panic!("expected the upload command");
+            };
+            target
+        };
+
+        let default = target(&[]);
+        assert_eq!(
+            default.object_url("imgs/a.png", true),
+            "https://art.ams3.cdn.digitaloceanspaces.com/imgs/a.png"
+        );
+        assert_eq!(
+            default.object_url("imgs/a.png", false),
+            "https://art.ams3.digitaloceanspaces.com/imgs/a.png"
+        );
+
+        let custom = target(&[
+            "--cdn-base-url",
+            "https://cdn.example.com/{prefix}{file}",
+            "--origin-base-url",
+            "https://storage.example.com/{bucket}/{region}/{prefix}{file}",
+        ]);
+        assert_eq!(
+            custom.object_url("imgs/a.png", true),
+            "https://cdn.example.com/imgs/a.png"
+        );
+        assert_eq!(
+            custom.object_url("imgs/a.png", false),
+            "https://storage.example.com/art/ams3/imgs/a.png"
+        );
+        // A key outside the prefix keeps its whole path in {file}
+        assert_eq!(
+            custom.object_url("other/b.png", true),
+            "https://cdn.example.com/other/b.png"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn permits_bound_how_many_tasks_run_at_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let permits = Arc::new(Semaphore::new(2));
+        let shutdown = CancellationToken::new();
+        let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let (permits, shutdown) = (Arc::clone(&permits), shutdown.clone());
+                let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+                tokio::spawn(async move {
+                    let _permit = start_unless_cancelled(permits, &shutdown).await.unwrap();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn only_ext_leaves_other_files_out_of_the_plan() {
+        let dir = scratch_dir("only-ext");
+        fs::create_dir(dir.join("nested")).unwrap();
+        for name in [
+            "a.png",
+            "b.JPG",
+            "c.webp",
+            "notes.csv",
+            ".DS_Store",
+            "partial.png.part",
+            "nested/d.png",
+            "nested/e.svg",
+        ] {
+            fs::write(dir.join(name), b"x").unwrap();
+        }
+        let planned = |only_ext: &[&str]| -> Vec<String> {
+            let only_ext: Vec<String> = only_ext
+                .iter()
+                .map(|ext| parse_extension(ext).unwrap())
+                .collect();
+            plan_object_names(&dir, false, &only_ext)
+                .unwrap()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect()
+        };
+
+        assert_eq!(
+            planned(&[]),
+            ["a.png", "b.JPG", "c.webp", "nested/d.png", "nested/e.svg"]
+        );
+        assert_eq!(
+            planned(&["png", ".jpg"]),
+            ["a.png", "b.JPG", "nested/d.png"]
+        );
+        assert_eq!(planned(&["CSV"]), ["notes.csv"]);
+        assert!(parse_extension(" . ").is_err());
+    }
+
+    #[test]
+    fn save_retries_transient_errors_only() {
+        use std::io::{Error, ErrorKind};
+        let dir = scratch_dir("save-retry");
+        let path = dir.join("nested/a.png");
+
+        // Fails once the way a busy network share might, then writes the file
+        let mut calls = 0;
+        retry_save(&path, || {
+            calls += 1;
+            if calls == 1 {
+                return Err(Error::from(ErrorKind::TimedOut));
+            }
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, b"fractal")
+        })
+        .unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(fs::read(&path).unwrap(), b"fractal");
+
+        let mut calls = 0;
+        let err = retry_save(&path, || {
+            calls += 1;
+            Err(Error::from(ErrorKind::StorageFull))
+        })
+        .unwrap_err();
+        assert_eq!((calls, err.kind()), (1, ErrorKind::StorageFull));
+
+        let mut calls = 0;
+        let err = retry_save(&path, || {
+            calls += 1;
+            Err(Error::from(ErrorKind::ResourceBusy))
+        })
+        .unwrap_err();
+        assert_eq!(
+            (calls, err.kind()),
+            (SAVE_ATTEMPTS, ErrorKind::ResourceBusy)
+        );
+    }
+
+    #[test]
+    fn small_render_encodes_as_well_formed_svg() {
+        use xml::reader::{EventReader, XmlEvent};
+
+        let img = DynamicImage::ImageRgb8(regen::render::render(&small_params()).unwrap());
+        let svg = encode_svg(&img).unwrap();
+        let mut root = None;
+        let mut rects = 0;
+        let mut covered = 0;
+        for event in EventReader::new(svg.as_slice()) {
+            if let XmlEvent::StartElement {
+                name, attributes, ..
+            } = event.unwrap()
+            {
+                let attr = |key: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                        .unwrap()
+                };
+                match name.local_name.as_str() {
+                    "svg" => root = Some((attr("width"), attr("height"))),
+                    "rect" => {
+                        rects += 1;
+                        covered += attr("width").parse::<u32>().unwrap();
+                    }
+                    other => panic!("unexpected element {}", other),
+                }
+            }
+        }
+        assert_eq!(root, Some(("32".to_string(), "24".to_string())));
+        // Runs of one color band share a rectangle, and together they cover every pixel
+        assert!(rects > 0 && rects < 32 * 24, "{}", rects);
+        assert_eq!(covered, 32 * 24);
+
+        // Every pixel alternating in color needs one rectangle each, past the limit
+        let noisy = RgbImage::from_fn(1000, 300, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        });
+        assert!(matches!(
+            encode_svg(&DynamicImage::ImageRgb8(noisy)),
+            Err(RegenError::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn filename_templates_give_distinct_names_with_the_format_extension() {
+        let render = |template: &str, index, extension| {
+            render_filename_template(
+                template,
+                Pattern::Julia,
+                index,
+                320,
+                200,
+                Some(7),
+                extension,
+            )
+        };
+        let names: Vec<String> = (0..3)
+            .map(|i| render(DEFAULT_FILENAME_TEMPLATE, i, "webp"))
+            .collect();
+        assert_eq!(names, ["julia_0.webp", "julia_1.webp", "julia_2.webp"]);
+
+        let template =
+            parse_filename_template("{pattern}-{seed}-{width}x{height}-{index}.{ext}").unwrap();
+        let names: HashSet<String> = [1, 12, 123].map(|i| render(&template, i, "png")).into();
+        assert_eq!(names.len(), 3);
+        assert!(names.contains("julia-7-320x200-12.png"));
+        assert!(names.iter().all(|name| name.ends_with(".png")));
+
+        for bad in [
+            "{pattern}.{ext}",
+            "{index}{width}.{ext}",
+            "{index}_{colour}.{ext}",
+            "out/{index}.{ext}",
+        ] {
+            assert!(parse_filename_template(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn uniform_buffers_are_rejected_as_degenerate() {
+        let black = RgbImage::from_pixel(64, 48, Rgb([0, 0, 0]));
+        let white = RgbImage::from_pixel(64, 48, Rgb([255, 255, 255]));
+        let mut speck = white.clone();
+        speck.put_pixel(10, 5, Rgb([0, 0, 0]));
+        let mut gray = RgbImage::from_pixel(64, 48, Rgb([120, 120, 120]));
+        gray.put_pixel(0, 0, Rgb([126, 126, 126]));
+        for (name, img) in [
+            ("black", &black),
+            ("white", &white),
+            ("speck", &speck),
+            ("gray", &gray),
+        ] {
+            assert!(is_degenerate(&RenderStats::of(img)), "{}", name);
+        }
+
+        let rendered = regen::render::render(&small_params()).unwrap();
+        assert!(!is_degenerate(&RenderStats::of(&rendered)));
+    }
+
+    #[test]
+    fn manifest_sorts_generated_names_by_their_numbers() {
+        let row = |name: &str, kib: &str| UploadRecord {
+            cdn_url: format!("https://cdn.example.com/{}", name),
+            origin_url: format!("https://origin.example.com/{}", name),
+            file_name: name.to_string(),
+            file_size_kib: kib.to_string(),
+            sha256: String::new(),
+            thumbnail_url: String::new(),
+        };
+        let mut rows = vec![
+            row("mandelbrot_10.png", "3.00"),
+            row("mandelbrot_2.png", "12.50"),
+            row("julia_1.png", "7.25"),
+            row("mandelbrot_100.png", ""),
+            row("mandelbrot_1.png", "0.50"),
+        ];
+        let names = |rows: &[UploadRecord]| {
+            rows.iter()
+                .map(|row| row.file_name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        ManifestSort::Name.sort(&mut rows);
+        assert_eq!(
+            names(&rows),
+            [
+                "julia_1.png",
+                "mandelbrot_1.png",
+                "mandelbrot_2.png",
+                "mandelbrot_10.png",
+                "mandelbrot_100.png"
+            ]
+        );
+        // The result doesn't depend on the order the rows came in
+        let mut reversed: Vec<UploadRecord> = rows.iter().rev().cloned().collect();
+        ManifestSort::Name.sort(&mut reversed);
+        assert_eq!(reversed, rows);
+
+        ManifestSort::Size.sort(&mut rows);
+        assert_eq!(
+            names(&rows),
+            [
+                "mandelbrot_1.png",
+                "mandelbrot_10.png",
+                "julia_1.png",
+                "mandelbrot_2.png",
+                "mandelbrot_100.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn planned_upload_totals_known_sizes_and_prices_them() {
+        let dir = scratch_dir("upload-summary");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("a.png"), vec![0u8; 1024 * 1024]).unwrap();
+        fs::write(dir.join("nested/b.png"), vec![0u8; 512 * 1024]).unwrap();
+        fs::write(dir.join("empty.png"), b"").unwrap();
+
+        let planned = plan_object_names(&dir, false, &[]).unwrap();
+        assert_eq!(planned.len(), 3);
+        let total = planned_size(&planned);
+        assert_eq!(total, 1536 * 1024);
+        assert_eq!(human_readable_size(total), "1.50 MB");
+        // 1.5 MiB is 1.5/1024 GB
+        assert!((storage_cost(total, 20.0) - 1.5 / 1024.0 * 20.0).abs() < 1e-12);
+        assert_eq!(storage_cost(1024 * 1024 * 1024, 0.02), 0.02);
+    }
+
+    #[tokio::test]
+    async fn credentials_flag_selects_the_provider_the_client_signs_with() {
+        let target = |source: &str| {
+            let args = [
+                "regen",
+                "upload",
+                "--bucket",
+                "b",
+                "--region",
+                "nyc3",
+                "--credentials",
+                source,
+                "--access-key",
+                "AKID",
+                "--secret-key",
+                "SECRET",
+            ];
+            let Commands::Upload { target, .. } = Cli::try_parse_from(args).unwrap().command else {
+                panic!("expected the upload command");
+            };
+            target
+        };
+
+        let keys = target("keys").credentials.provider().unwrap();
+        let CredentialProvider::Static(ref provider) = keys else {
+            panic!("keys should give a static provider");
+        };
+        let signed_with = provider.credentials().await.unwrap();
+        assert_eq!(
+            (
+                signed_with.aws_access_key_id(),
+                signed_with.aws_secret_access_key()
+            ),
+            ("AKID", "SECRET")
+        );
+        // None of the other sources look for keys until a request is signed
+        assert!(matches!(
+            target("env").credentials.provider().unwrap(),
+            CredentialProvider::Environment(_)
+        ));
+        assert!(matches!(
+            target("profile").credentials.provider().unwrap(),
+            CredentialProvider::Profile(_)
+        ));
+        assert!(matches!(
+            target("instance").credentials.provider().unwrap(),
+            CredentialProvider::Instance(_)
+        ));
+        // Every variant builds a client, without any network access
+        for source in ["keys", "env", "profile", "instance"] {
+            let target = target(source);
+            s3_client("nyc3", None, target.credentials.provider().unwrap());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_delays_double_then_stop_at_the_cap() {
+        assert_eq!(retry_delay(1, 0.0), RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2, 0.0), RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(3, 0.5), RETRY_BASE_DELAY * 6);
+        // Far past where 2^(attempt - 1) overflows a u32
+        for attempt in [7, 8, 32, 33, 64, u32::MAX] {
+            for jitter in [0.0, 0.99] {
+                assert!(retry_delay(attempt, jitter) <= MAX_RETRY_DELAY);
+            }
+        }
+        assert_eq!(retry_delay(40, 0.0), MAX_RETRY_DELAY);
+
+        // Many retries run to the end without overflowing, each wait at most the cap
+        let start = tokio::time::Instant::now();
+        let mut attempts = 0;
+        let result: Result<(), &str> = with_retry(
+            "a.png",
+            40,
+            |_| true,
+            || {
+                attempts += 1;
+                async { Err("connection reset") }
+            },
+        )
+        .await;
+        assert_eq!(result, Err("connection reset"));
+        assert_eq!(attempts, 41);
+        assert!(start.elapsed() <= MAX_RETRY_DELAY * 40);
+    }
+
+    #[test]
+    fn unknown_region_is_rejected_with_a_suggestion() {
+        let target = |extra: &[&str]| {
+            let mut args = vec!["regen", "upload", "--bucket", "b"];
+            args.extend(extra);
+            let Commands::Upload { target, .. } = Cli::try_parse_from(args).unwrap().command else {
+                panic!("expected the upload command");
+            };
+            target
+        };
+        let Err(RegenError::InvalidParams(message)) = target(&["--region", "lon"]).validate()
+        else {
+            panic!("lon should be rejected");
+        };
+        assert!(message.contains("did you mean 'lon1'?"), "{}", message);
+        let Err(RegenError::InvalidParams(message)) = target(&["--region", "mars"]).validate()
+        else {
+            panic!("mars should be rejected");
+        };
+        assert!(!message.contains("did you mean"), "{}", message);
+
+        assert!(target(&["--region", "lon1"]).validate().is_ok());
+        assert!(
+            target(&["--region", "lon", "--allow-unknown-region"])
+                .validate()
+                .is_ok()
+        );
+        // Another S3-compatible service names its own regions
+        assert!(
+            target(&["--region", "lon", "--endpoint", "http://localhost:9000"])
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn contact_sheet_tiles_every_image_in_a_grid() {
+        let dir = scratch_dir("sheet");
+        let colors: Vec<Rgb<u8>> = (0..7u8).map(|i| Rgb([i * 30, 200 - i * 20, 90])).collect();
+        let images: Vec<PathBuf> = colors
+            .iter()
+            .enumerate()
+            .map(|(i, color)| {
+                let path = dir.join(format!("mandelbrot_{}.png", i));
+                RgbImage::from_pixel(40, 20, *color).save(&path).unwrap();
+                path
+            })
+            .collect();
+
+        let (sheet, tiles) = contact_sheet(&images, 3, 16, false).unwrap();
+        assert_eq!(tiles, 7);
+        // Three columns and three rows of 16 pixel cells with a gap around each
+        let cell = 16 + SHEET_GAP;
+        assert_eq!(
+            sheet.dimensions(),
+            (3 * cell + SHEET_GAP, 3 * cell + SHEET_GAP)
+        );
+        for (index, color) in colors.iter().enumerate() {
+            let (column, row) = (index as u32 % 3, index as u32 / 3);
+            let center = (SHEET_GAP + column * cell + 8, SHEET_GAP + row * cell + 8);
+            assert_eq!(sheet.get_pixel(center.0, center.1), color, "tile {}", index);
+        }
+        // The two cells after the last image stay empty
+        assert_eq!(
+            *sheet.get_pixel(SHEET_GAP + 2 * cell + 8, SHEET_GAP + 2 * cell + 8),
+            SHEET_BACKGROUND
+        );
+
+        // Fewer images than columns gives a single row just wide enough
+        let (sheet, tiles) = contact_sheet(&images[..2], 5, 16, true).unwrap();
+        assert_eq!(tiles, 2);
+        assert_eq!(sheet.dimensions(), (2 * cell + SHEET_GAP, cell + SHEET_GAP));
+        assert!(contact_sheet(&[], 3, 16, false).is_none());
+    }
 }
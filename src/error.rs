@@ -0,0 +1,52 @@
+//! Error type shared by rendering, saving and uploading.
+
+use crate::util::human_readable_size;
+use rusoto_core::RusotoError;
+use rusoto_s3::PutObjectError;
+use std::path::PathBuf;
+
+/// Errors returned by the image generation and upload functions.
+#[derive(Debug, thiserror::Error)]
+pub enum RegenError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Image encoding or decoding failed: {0}")]
+    ImageDecode(#[from] image::ImageError),
+    #[error("S3 upload failed: {0}")]
+    S3Upload(Box<RusotoError<PutObjectError>>),
+    #[error("S3 multipart upload failed: {0}")]
+    MultipartUpload(String),
+    #[error("Invalid parameters: {0}")]
+    InvalidParams(String),
+    #[error("Path conversion failed: {0}")]
+    PathConversion(String),
+    #[error("Background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("{0} objects in the manifest failed verification")]
+    Verification(u64),
+    #[error(
+        "No Spaces credentials: set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or pass --access-key and --secret-key"
+    )]
+    MissingCredentials,
+    #[error("Could not load credentials: {0}")]
+    Credentials(String),
+    #[error("Malformed manifest {}, line {}: {}", .0.display(), .1, .2)]
+    Manifest(PathBuf, u64, String),
+    #[error("Unreadable upload checkpoint {}: {} (rerun with --restart)", .0.display(), .1)]
+    Checkpoint(PathBuf, String),
+    #[error("Files larger than --max-file-size ({}): {}", human_readable_size(*.0), .1.join(", "))]
+    Oversized(u64, Vec<String>),
+    #[error("{0} doctor checks failed")]
+    ChecksFailed(usize),
+    #[error("{} uploads failed: {}", .0.len(), .0.join(", "))]
+    UploadFailures(Vec<String>),
+    #[error("Image {0}: every render came out nearly a single color")]
+    Degenerate(usize),
+}
+
+// Boxed because rusoto errors carry the whole HTTP response and would bloat every Result
+impl From<RusotoError<PutObjectError>> for RegenError {
+    fn from(err: RusotoError<PutObjectError>) -> Self {
+        RegenError::S3Upload(Box::new(err))
+    }
+}
@@ -0,0 +1,6 @@
+pub mod error;
+pub mod mime;
+pub mod render;
+pub mod timings;
+pub mod util;
+pub mod watermark;
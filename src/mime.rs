@@ -0,0 +1,77 @@
+//! Content types for uploaded files.
+
+use std::path::Path;
+
+/// Content type stored with an uploaded file, picked from its extension. Unknown or missing
+/// extensions fall back to `application/octet-stream`.
+pub fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|s| s.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "bmp" => "image/bmp",
+        "tiff" | "tif" => "image/tiff",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        // Metadata written alongside the images
+        "json" => "application/json",
+        "csv" => "text/csv",
+        _ => "application/octet-stream", // Default to download if unknown
+    }
+}
+
+/// Whether a body of this content type shrinks enough under gzip to be worth compressing.
+/// Text formats do; PNG, JPEG and the other image formats are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || matches!(
+            content_type,
+            "image/svg+xml" | "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_extension_maps_to_its_content_type() {
+        for (file, content_type) in [
+            ("a.png", "image/png"),
+            ("a.jpg", "image/jpeg"),
+            ("a.jpeg", "image/jpeg"),
+            ("a.gif", "image/gif"),
+            ("a.webp", "image/webp"),
+            ("a.svg", "image/svg+xml"),
+            ("a.bmp", "image/bmp"),
+            ("a.tiff", "image/tiff"),
+            ("a.tif", "image/tiff"),
+            ("a.avif", "image/avif"),
+            ("a.ico", "image/x-icon"),
+            ("a.json", "application/json"),
+            ("a.csv", "text/csv"),
+            ("dir/A.PNG", "image/png"),
+        ] {
+            assert_eq!(content_type_for(Path::new(file)), content_type, "{}", file);
+        }
+    }
+
+    #[test]
+    fn unknown_or_missing_extensions_are_octet_streams() {
+        for file in ["a.xyz", "a", "archive.tar.gz", ".png"] {
+            assert_eq!(
+                content_type_for(Path::new(file)),
+                "application/octet-stream",
+                "{}",
+                file
+            );
+        }
+    }
+}
@@ -0,0 +1,2155 @@
+//! Pixel computation for every pattern, free of file I/O so other crates can render into their
+//! own buffers. The binary adds the ratio checks, encoding, padding and saving on top.
+
+use crate::error::RegenError;
+use clap::ValueEnum;
+use image::{DynamicImage, ImageBuffer, Rgb, RgbImage, Rgba, RgbaImage};
+use log::info;
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Patterns understood by `render`, selected with `--pattern`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    /// Escape-time render of the Mandelbrot set
+    Mandelbrot,
+    /// Escape-time render of the Julia set for a fixed constant c
+    Julia,
+    /// Escape-time render of the Burning Ship fractal
+    #[value(name = "burningship")]
+    BurningShip,
+    /// Basins of Newton's method for z^3 - 1, colored by the root each point reaches
+    Newton,
+    /// Random RGB noise
+    Noise,
+}
+
+impl Pattern {
+    /// Returns true for patterns rendered by escape-time iteration, i.e. that have in-set pixels.
+    pub fn is_escape_time(self) -> bool {
+        matches!(
+            self,
+            Pattern::Mandelbrot | Pattern::Julia | Pattern::BurningShip
+        )
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.write_str(value.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// (x_pos, y_pos, view_scale, max_iterations, smoothness, color_step) for escape-time patterns.
+pub type MandelbrotParams = (f64, f64, f64, u32, u32, f64);
+
+/// Parameters used when none are given.
+pub const DEFAULT_MANDELBROT_PARAMS: MandelbrotParams =
+    (-0.00275, 0.78912, 0.125689, 800, 8, 6000.0);
+/// The three roots of z^3 - 1 lie on the unit circle, so this shows all their basins.
+const DEFAULT_NEWTON_PARAMS: MandelbrotParams = (0.0, 0.0, 0.75, 100, 8, 6000.0);
+/// Julia sets sit around the origin, so the default view is centered there.
+const DEFAULT_JULIA_PARAMS: MandelbrotParams = (0.0, 0.0, 0.75, 800, 8, 6000.0);
+/// Whole Burning Ship, with the ship itself in the lower left.
+const DEFAULT_BURNING_SHIP_PARAMS: MandelbrotParams = (-0.45, -0.5, 0.6, 800, 8, 6000.0);
+
+/// Constant c used for Julia renders when none is given.
+pub const DEFAULT_JULIA_CONSTANT: (f64, f64) = (-0.8, 0.156);
+/// Exponent d in z^d + c; 2 is the classic Mandelbrot, Julia and Burning Ship iteration.
+pub const DEFAULT_POWER: u32 = 2;
+/// |z| past which an escape-time orbit counts as escaped. Anything above 2 gives the same set,
+/// but a large bailout makes the smoothed iteration count accurate and the gradients seamless.
+pub const DEFAULT_BAILOUT: f64 = 256.0;
+/// Side of the square tiles images are rendered in. 64 RGBA pixels is a 256-byte row segment,
+/// so a tile's rows stay in cache while it is rendered.
+pub const DEFAULT_TILE_SIZE: u32 = 64;
+
+/// Region of the complex plane mapped onto the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewBounds {
+    pub x_min: f64,
+    pub x_max: f64,
+    pub y_min: f64,
+    pub y_max: f64,
+}
+
+/// How the height of the view window follows from its width, selected with `--aspect`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aspect {
+    /// Follow the image's width:height, so every pixel covers a square region
+    #[default]
+    Pixel,
+    /// As tall as it is wide, whatever the image's shape
+    Square,
+    /// A fixed width:height ratio; pixels are stretched when it differs from the image's
+    Ratio(f64, f64),
+}
+
+impl Aspect {
+    /// View height as a multiple of the view width.
+    fn height_factor(self, width: u32, height: u32) -> f64 {
+        match self {
+            Aspect::Pixel => height as f64 / width as f64,
+            Aspect::Square => 1.0,
+            Aspect::Ratio(w, h) => h / w,
+        }
+    }
+}
+
+impl std::fmt::Display for Aspect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Aspect::Pixel => write!(f, "pixel"),
+            Aspect::Square => write!(f, "square"),
+            Aspect::Ratio(w, h) => write!(f, "{}:{}", w, h),
+        }
+    }
+}
+
+impl std::str::FromStr for Aspect {
+    type Err = String;
+
+    /// Parses `pixel`, `square` or a `w:h` ratio of two positive numbers, such as `16:9`.
+    fn from_str(value: &str) -> Result<Aspect, String> {
+        match value {
+            "pixel" => return Ok(Aspect::Pixel),
+            "square" => return Ok(Aspect::Square),
+            _ => {}
+        }
+        let invalid = || {
+            format!(
+                "invalid aspect '{}': expected pixel, square or a ratio like 16:9",
+                value
+            )
+        };
+        let (w, h) = value.split_once(':').ok_or_else(invalid)?;
+        let side = |part: &str| {
+            part.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite() && *v > 0.0)
+        };
+        match (side(w), side(h)) {
+            (Some(w), Some(h)) => Ok(Aspect::Ratio(w, h)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Width and height of the view window. The width is derived from view_scale (4 × view_scale
+/// wide) and the height from `aspect`.
+pub fn view_size(width: u32, height: u32, view_scale: f64, aspect: Aspect) -> (f64, f64) {
+    let view_width = 4.0 * view_scale;
+    (view_width, view_width * aspect.height_factor(width, height))
+}
+
+/// Calculates the view window of `view_size` centered on (x_pos, y_pos).
+pub fn view_bounds(
+    width: u32,
+    height: u32,
+    x_pos: f64,
+    y_pos: f64,
+    view_scale: f64,
+    aspect: Aspect,
+) -> ViewBounds {
+    let (view_width, view_height) = view_size(width, height, view_scale, aspect);
+
+    ViewBounds {
+        x_min: x_pos - view_width / 2.0,
+        x_max: x_pos + view_width / 2.0,
+        y_min: y_pos - view_height / 2.0,
+        y_max: y_pos + view_height / 2.0,
+    }
+}
+
+/// Iterates z = z^2 + c from the starting z until |z| exceeds `bailout` or max_iterations is
+/// reached. Returns the iteration count and the final |z|^2.
+#[allow(clippy::too_many_arguments)]
+fn escape_time(
+    mut z_real: f64,
+    mut z_imag: f64,
+    c_real: f64,
+    c_imag: f64,
+    max_iterations: u32,
+    burning_ship: bool,
+    power: u32,
+    bailout: f64,
+) -> (u32, f64) {
+    let bailout_sq = bailout * bailout;
+    let mut iterations = 0;
+    let mut magnitude_sq = z_real * z_real + z_imag * z_imag;
+
+    while magnitude_sq < bailout_sq && iterations < max_iterations {
+        // The Burning Ship folds z into the first quadrant before raising it to the power
+        let (base_real, base_imag) = if burning_ship {
+            (z_real.abs(), z_imag.abs())
+        } else {
+            (z_real, z_imag)
+        };
+        let (pow_real, pow_imag) = complex_pow(base_real, base_imag, power);
+        z_real = pow_real + c_real;
+        z_imag = pow_imag + c_imag;
+        magnitude_sq = z_real * z_real + z_imag * z_imag;
+        iterations += 1;
+    }
+    (iterations, magnitude_sq)
+}
+
+/// Bits kept beyond the pixel size in the `--deep-zoom` reference orbit, so its rounding stays
+/// far below a pixel over thousands of iterations.
+const REFERENCE_GUARD_BITS: u32 = 64;
+
+/// Orbit of the view center under z² + c for `--deep-zoom`. It is computed in fixed point with
+/// enough bits for the zoom, then stored as f64 so every sample only iterates its small offset
+/// from it, which f64 holds precisely however deep the view is.
+struct ReferenceOrbit {
+    orbit: Vec<(f64, f64)>,
+}
+
+impl ReferenceOrbit {
+    fn new(center: (f64, f64), pixel_size: f64, max_iterations: u32, bailout: f64) -> Self {
+        let bits = REFERENCE_GUARD_BITS + (-pixel_size.log2()).max(0.0).ceil() as u32;
+        let (c_real, c_imag) = (to_fixed(center.0, bits), to_fixed(center.1, bits));
+        let (mut z_real, mut z_imag) = (BigInt::zero(), BigInt::zero());
+        let mut orbit = vec![(0.0, 0.0)];
+        for _ in 0..max_iterations {
+            let real_sq = (&z_real * &z_real) >> bits;
+            let imag_sq = (&z_imag * &z_imag) >> bits;
+            z_imag = ((&z_real * &z_imag) >> (bits - 1)) + &c_imag;
+            z_real = real_sq - imag_sq + &c_real;
+            let z = (from_fixed(&z_real, bits), from_fixed(&z_imag, bits));
+            orbit.push(z);
+            // Samples that outlast the reference are rebased, so it can stop here
+            if z.0 * z.0 + z.1 * z.1 >= bailout * bailout {
+                break;
+            }
+        }
+        ReferenceOrbit { orbit }
+    }
+
+    /// Same result as `escape_time` from z = 0 with c at `delta_c` from the center, iterating
+    /// only the offset δ from the reference: δ' = 2Zδ + δ² + Δc. Once the sample's z is smaller
+    /// than its offset, or the reference runs out, the offset is rebased onto the start of the
+    /// reference, which avoids the glitches of plain perturbation.
+    fn escape_time(&self, delta_c: (f64, f64), max_iterations: u32, bailout: f64) -> (u32, f64) {
+        let bailout_sq = bailout * bailout;
+        let (mut d_real, mut d_imag) = (0.0, 0.0);
+        let mut step = 0;
+        for iterations in 1..=max_iterations {
+            let (ref_real, ref_imag) = self.orbit[step];
+            (d_real, d_imag) = (
+                2.0 * (ref_real * d_real - ref_imag * d_imag) + d_real * d_real - d_imag * d_imag
+                    + delta_c.0,
+                2.0 * (ref_real * d_imag + ref_imag * d_real) + 2.0 * d_real * d_imag + delta_c.1,
+            );
+            step += 1;
+            let (ref_real, ref_imag) = self.orbit[step];
+            let (z_real, z_imag) = (ref_real + d_real, ref_imag + d_imag);
+            let magnitude_sq = z_real * z_real + z_imag * z_imag;
+            if magnitude_sq >= bailout_sq {
+                return (iterations, magnitude_sq);
+            }
+            if magnitude_sq < d_real * d_real + d_imag * d_imag || step == self.orbit.len() - 1 {
+                (d_real, d_imag) = (z_real, z_imag);
+                step = 0;
+            }
+        }
+        (max_iterations, 0.0)
+    }
+}
+
+/// Converts an f64 to fixed point with `bits` fractional bits. Every finite f64 is an integer
+/// times a power of two, so this is exact.
+fn to_fixed(value: f64, bits: u32) -> BigInt {
+    let raw = value.to_bits();
+    let exponent = ((raw >> 52) & 0x7ff) as i64;
+    let fraction = raw & ((1 << 52) - 1);
+    let (mantissa, exponent) = if exponent == 0 {
+        (fraction, -1074)
+    } else {
+        (fraction | 1 << 52, exponent - 1075)
+    };
+    let shift = exponent + bits as i64;
+    let fixed = if shift >= 0 {
+        BigInt::from(mantissa) << shift
+    } else {
+        BigInt::from(mantissa) >> -shift
+    };
+    if value.is_sign_negative() {
+        -fixed
+    } else {
+        fixed
+    }
+}
+
+/// Converts fixed point with `bits` fractional bits back to the nearest f64.
+fn from_fixed(value: &BigInt, bits: u32) -> f64 {
+    // 128 fractional bits are plenty for an f64 and keep the integer from overflowing it
+    let shift = bits.saturating_sub(128);
+    let value = (value >> shift).to_f64().unwrap_or(f64::NAN);
+    value * 2.0_f64.powi(-((bits - shift) as i32))
+}
+
+/// `--deep-zoom` perturbs the z² + c orbit from z = 0, so it needs the plain Mandelbrot
+/// iteration and a coloring that only uses the escape time.
+pub fn check_deep_zoom(pattern: Pattern, power: u32, coloring: Coloring) -> Result<(), RegenError> {
+    if pattern != Pattern::Mandelbrot || power != 2 {
+        return Err(RegenError::InvalidParams(format!(
+            "--deep-zoom only supports the mandelbrot pattern with power 2, got {} with power {}",
+            pattern, power
+        )));
+    }
+    if !matches!(coloring, Coloring::Escape | Coloring::Histogram) {
+        return Err(RegenError::InvalidParams(format!(
+            "--deep-zoom only supports escape and histogram coloring, got {}",
+            format!("{:?}", coloring).to_lowercase()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether c lies inside the Mandelbrot set's main cardioid or its period-2 bulb, whose orbits
+/// settle on an attracting cycle and never escape. Comparisons are strict so points on the
+/// boundaries are still iterated.
+fn in_main_cardioid_or_bulb(c_real: f64, c_imag: f64) -> bool {
+    let imag_sq = c_imag * c_imag;
+    let shifted = c_real - 0.25;
+    let q = shifted * shifted + imag_sq;
+    let in_cardioid = q * (q + shifted) < 0.25 * imag_sq;
+    let in_bulb = (c_real + 1.0) * (c_real + 1.0) + imag_sq < 0.0625;
+    in_cardioid || in_bulb
+}
+
+/// Raises a complex number to a positive integer power by repeated multiplication, which is
+/// both faster and more precise than going through polar form. For power 2 this gives exactly
+/// the same bits as the usual `(x² - y², 2xy)`.
+fn complex_pow(real: f64, imag: f64, power: u32) -> (f64, f64) {
+    let (mut result_real, mut result_imag) = (real, imag);
+    for _ in 1..power {
+        (result_real, result_imag) = (
+            result_real * real - result_imag * imag,
+            result_real * imag + result_imag * real,
+        );
+    }
+    (result_real, result_imag)
+}
+
+/// Colors a pixel from its escape-time result. Shared by all escape-time patterns so they
+/// look consistent.
+#[allow(clippy::too_many_arguments)]
+fn escape_time_color(
+    iterations: u32,
+    max_iterations: u32,
+    magnitude_sq: f64,
+    smoothness: u32,
+    bailout: f64,
+    power: u32,
+    color_step: f64,
+    color_clamp: ColorClamp,
+    background: Rgba<u8>,
+    colors: ColorMap,
+) -> Rgba<u8> {
+    if iterations == max_iterations {
+        // Point is in the set (black)
+        return Rgba([0, 0, 0, 255]);
+    }
+
+    // Point escaped, color based on iteration count with smoothing
+    let escape_value = escape_value(iterations, magnitude_sq, smoothness, bailout, power);
+    let color_val = (escape_value / color_step) * 255.0;
+    intensity_color(color_clamp.intensity(color_val), background, colors)
+}
+
+/// Iteration count of an escaped point. A smoothness of 0 keeps the raw (banded) count,
+/// otherwise it is smoothed and scaled by how quickly the gradient darkens towards the set.
+///
+/// The smoothed count is always finite and never decreases as `iterations` grows: the
+/// fractional part is held to one band even when |z| is at or below the bailout (a point
+/// counted as escaped too early), overflowed to infinity, or the power is above 2.
+fn escape_value(
+    iterations: u32,
+    magnitude_sq: f64,
+    smoothness: u32,
+    bailout: f64,
+    power: u32,
+) -> f64 {
+    if smoothness == 0 {
+        return iterations as f64;
+    }
+    let log_zn = magnitude_sq.ln() / 2.0;
+    let nu = (log_zn / 2.0_f64.ln()).ln() / 2.0_f64.ln();
+    // nu starts at log2(log2 bailout) right past the bailout and grows by log2(power) until the
+    // next iteration would have escaped instead; that range is scaled onto [floor, floor + 1]
+    let floor = bailout.log2().max(1.0).log2();
+    let band = (power.max(2) as f64).log2();
+    let nu = if nu.is_nan() {
+        floor
+    } else if band == 1.0 {
+        nu.clamp(floor, floor + 1.0)
+    } else {
+        floor + ((nu - floor) / band).clamp(0.0, 1.0)
+    };
+    let smoothed_iterations = iterations as f64 + 1.0 - nu;
+    smoothed_iterations * smoothness as f64
+}
+
+/// Palette color for an escaped point's intensity in 0..=254, with 0 left as background.
+fn intensity_color(intensity: u8, background: Rgba<u8>, colors: ColorMap) -> Rgba<u8> {
+    if intensity == 0 {
+        background
+    } else {
+        let Rgb([r, g, b]) = colors.color(intensity as f64 / 254.0);
+        Rgba([r, g, b, 255])
+    }
+}
+
+/// Colors an escape value by its rank among all escaped samples of the image, `sorted`
+/// ascending, so every palette color covers about the same area whatever the zoom. NaN marks
+/// an in-set sample.
+fn histogram_color(value: f64, sorted: &[f64], background: Rgba<u8>, colors: ColorMap) -> Rgba<u8> {
+    if value.is_nan() {
+        return Rgba([0, 0, 0, 255]);
+    }
+    let rank = sorted.partition_point(|&v| v <= value) as f64 / sorted.len() as f64;
+    // Far from the set is the low end, as with escape coloring
+    intensity_color((rank * 254.0) as u8, background, colors)
+}
+
+/// How escaped points are shaded, selected with `--coloring`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Coloring {
+    /// Smoothed iteration count, giving soft gradients around the set
+    Escape,
+    /// Estimated distance to the set, giving thin sharp boundary lines
+    Distance,
+    /// Closest approach of the orbit to a shape chosen with `--trap`
+    #[value(name = "orbittrap")]
+    OrbitTrap,
+    /// Smoothed iteration count equalized over the image, spreading the palette evenly
+    Histogram,
+}
+
+/// What escape coloring does with values past the end of the palette, selected with
+/// `--color-clamp`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorClamp {
+    /// Hold them at the last palette color
+    #[default]
+    Clamp,
+    /// Start the palette over, giving repeating bands
+    Wrap,
+    /// Stretch the palette so the image's largest value just reaches its end
+    Scale,
+}
+
+impl ColorClamp {
+    /// Palette intensity for an escape coloring value, where color_step maps to 255. Capped
+    /// below 255 so escaped points never turn pure black and get counted as in-set by the
+    /// fractal ratio.
+    fn intensity(self, color_val: f64) -> u8 {
+        match self {
+            // 0 is the background, so the palette restarts at 1
+            ColorClamp::Wrap if color_val > 254.0 => (1.0 + (color_val - 1.0) % 254.0) as u8,
+            _ => color_val.clamp(0.0, 254.0) as u8,
+        }
+    }
+}
+
+/// Shape the orbit is measured against by `--coloring orbittrap`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trap {
+    /// The origin
+    #[default]
+    Point,
+    /// The real and imaginary axes
+    Cross,
+    /// The unit circle
+    Circle,
+}
+
+impl Trap {
+    /// Distance from `z` to the trap shape.
+    fn distance(self, z_real: f64, z_imag: f64) -> f64 {
+        match self {
+            Trap::Point => (z_real * z_real + z_imag * z_imag).sqrt(),
+            Trap::Cross => z_real.abs().min(z_imag.abs()),
+            Trap::Circle => ((z_real * z_real + z_imag * z_imag).sqrt() - 1.0).abs(),
+        }
+    }
+}
+
+/// How quickly orbit-trap shading fades with distance from the trap; higher gives thinner,
+/// more defined trap shapes.
+const TRAP_SHARPNESS: f64 = 4.0;
+
+/// Squared bailout for distance estimation. Much larger than the escape-time bailout because
+/// the estimate is only accurate once |z| is large.
+const DISTANCE_BAILOUT_SQ: f64 = 1e10;
+
+/// Estimates the distance from the pixel to the set from the derivative of the iteration
+/// (with respect to c, or to the starting z for Julia sets). Returns None for points that
+/// don't escape within `max_iterations`. The Burning Ship's folds are ignored in the
+/// derivative, which is close enough for coloring.
+#[allow(clippy::too_many_arguments)]
+fn distance_estimate(
+    mut z_real: f64,
+    mut z_imag: f64,
+    c_real: f64,
+    c_imag: f64,
+    max_iterations: u32,
+    burning_ship: bool,
+    julia: bool,
+    power: u32,
+) -> Option<f64> {
+    let (mut dz_real, mut dz_imag): (f64, f64) = if julia { (1.0, 0.0) } else { (0.0, 0.0) };
+    for _ in 0..max_iterations {
+        let magnitude_sq = z_real * z_real + z_imag * z_imag;
+        if magnitude_sq > DISTANCE_BAILOUT_SQ {
+            let magnitude = magnitude_sq.sqrt();
+            let dz_magnitude = (dz_real * dz_real + dz_imag * dz_imag).sqrt();
+            return Some(0.5 * magnitude * magnitude.ln() / dz_magnitude);
+        }
+
+        // dz = d * z^(d-1) * dz, plus 1 when differentiating with respect to c
+        let degree = power as f64;
+        let (lower_real, lower_imag) = complex_pow(z_real, z_imag, power - 1);
+        let next_dz_real =
+            degree * (lower_real * dz_real - lower_imag * dz_imag) + if julia { 0.0 } else { 1.0 };
+        dz_imag = degree * (lower_real * dz_imag + lower_imag * dz_real);
+        dz_real = next_dz_real;
+
+        let (base_real, base_imag) = if burning_ship {
+            (z_real.abs(), z_imag.abs())
+        } else {
+            (z_real, z_imag)
+        };
+        let (pow_real, pow_imag) = complex_pow(base_real, base_imag, power);
+        z_real = pow_real + c_real;
+        z_imag = pow_imag + c_imag;
+    }
+    None
+}
+
+/// Colors a pixel from its estimated distance to the set: black inside, darkest right at the
+/// boundary and fading to the background two pixels away.
+fn distance_color(
+    distance: Option<f64>,
+    pixel_size: f64,
+    background: Rgba<u8>,
+    colors: ColorMap,
+) -> Rgba<u8> {
+    let Some(distance) = distance else {
+        return Rgba([0, 0, 0, 255]);
+    };
+    // The square root keeps only pixels very close to the boundary dark, so lines stay thin
+    let t = 1.0 - (distance / (2.0 * pixel_size)).sqrt();
+    if t <= 0.0 {
+        background
+    } else {
+        let Rgb([r, g, b]) = colors.color(t);
+        Rgba([r, g, b, 255])
+    }
+}
+
+/// Runs the escape-time iteration until |z| exceeds `bailout` and returns the closest any
+/// iterate came to `trap`, or None for points that don't escape within `max_iterations`.
+#[allow(clippy::too_many_arguments)]
+fn orbit_trap(
+    mut z_real: f64,
+    mut z_imag: f64,
+    c_real: f64,
+    c_imag: f64,
+    max_iterations: u32,
+    burning_ship: bool,
+    power: u32,
+    bailout: f64,
+    trap: Trap,
+) -> Option<f64> {
+    let bailout_sq = bailout * bailout;
+    let mut min_distance = f64::INFINITY;
+    let mut iterations = 0;
+    let mut magnitude_sq = z_real * z_real + z_imag * z_imag;
+
+    while magnitude_sq < bailout_sq && iterations < max_iterations {
+        let (base_real, base_imag) = if burning_ship {
+            (z_real.abs(), z_imag.abs())
+        } else {
+            (z_real, z_imag)
+        };
+        let (pow_real, pow_imag) = complex_pow(base_real, base_imag, power);
+        z_real = pow_real + c_real;
+        z_imag = pow_imag + c_imag;
+        magnitude_sq = z_real * z_real + z_imag * z_imag;
+        min_distance = min_distance.min(trap.distance(z_real, z_imag));
+        iterations += 1;
+    }
+    (iterations < max_iterations).then_some(min_distance)
+}
+
+/// Colors a pixel from its orbit's closest approach to the trap: black inside, darkest where
+/// the orbit passed right through the trap.
+fn orbit_trap_color(distance: Option<f64>, colors: ColorMap) -> Rgba<u8> {
+    let Some(distance) = distance else {
+        return Rgba([0, 0, 0, 255]);
+    };
+    let Rgb([r, g, b]) = colors.color((-distance * TRAP_SHARPNESS).exp());
+    Rgba([r, g, b, 255])
+}
+
+/// Roots of z^3 - 1 and the color of each one's basin.
+const NEWTON_ROOTS: [((f64, f64), [u8; 3]); 3] = [
+    ((1.0, 0.0), [220, 60, 60]),
+    ((-0.5, 0.866_025_403_784_438_6), [60, 180, 90]),
+    ((-0.5, -0.866_025_403_784_438_6), [60, 100, 220]),
+];
+/// Squared distance to a root at which Newton's method counts as converged.
+const NEWTON_TOLERANCE_SQ: f64 = 1e-12;
+/// Iterations after which a basin color reaches its darkest shade.
+const NEWTON_SHADE_ITERATIONS: f64 = 40.0;
+
+/// Runs Newton's method for z^3 - 1 from `z` and colors the point by the root it converges
+/// to, darker the more iterations it took. Points that don't converge within
+/// `max_iterations` (or hit the critical point at 0) are black.
+fn newton_color(mut z_real: f64, mut z_imag: f64, max_iterations: u32) -> Rgba<u8> {
+    for iterations in 0..max_iterations {
+        for ((root_real, root_imag), color) in NEWTON_ROOTS {
+            let (d_real, d_imag) = (z_real - root_real, z_imag - root_imag);
+            if d_real * d_real + d_imag * d_imag < NEWTON_TOLERANCE_SQ {
+                let shade = 1.0 - (iterations as f64 / NEWTON_SHADE_ITERATIONS).min(0.85);
+                let [r, g, b] = color.map(|channel| (channel as f64 * shade).round() as u8);
+                return Rgba([r, g, b, 255]);
+            }
+        }
+
+        // z -= (z^3 - 1) / (3z^2)
+        let (sq_real, sq_imag) = complex_pow(z_real, z_imag, 2);
+        let (cube_real, cube_imag) = complex_pow(z_real, z_imag, 3);
+        let (num_real, num_imag) = (cube_real - 1.0, cube_imag);
+        let (den_real, den_imag) = (3.0 * sq_real, 3.0 * sq_imag);
+        let den_norm = den_real * den_real + den_imag * den_imag;
+        if den_norm == 0.0 {
+            break;
+        }
+        z_real -= (num_real * den_real + num_imag * den_imag) / den_norm;
+        z_imag -= (num_imag * den_real - num_real * den_imag) / den_norm;
+    }
+    Rgba([0, 0, 0, 255])
+}
+
+/// Color schemes for escaped points, selected with `--palette`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Palette {
+    /// White fading to near-black at the set boundary
+    Grayscale,
+    /// Pale yellow through orange and red to dark red
+    Fire,
+    /// Near-white through light blue to deep navy
+    Ice,
+    /// Hue sweep from red through green and blue to magenta
+    Rainbow,
+    /// Gradient loaded with `--palette-file`
+    #[value(skip)]
+    Custom,
+}
+
+impl Palette {
+    /// Maps a normalized escape value `t` in [0, 1] to a color, where 0 is far from the set
+    /// and 1 is right at its edge. No palette returns pure black, which is reserved for
+    /// in-set points. `stops` are the `--palette-file` gradient, which only `Custom` reads.
+    fn color(self, stops: &[(f64, [u8; 3])], t: f64) -> Rgb<u8> {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Palette::Grayscale => grayscale(t),
+            Palette::Fire => fire(t),
+            Palette::Ice => ice(t),
+            Palette::Rainbow => rainbow(t),
+            Palette::Custom => custom(stops, t),
+        }
+    }
+}
+
+/// A palette as applied to one image: `offset` turns it by that fraction of its length, so a
+/// batch can step through the palette from image to image. `stops` are the `--palette-file`
+/// gradient for `Palette::Custom` and are ignored by the other palettes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorMap<'a> {
+    pub palette: Palette,
+    pub offset: f64,
+    pub stops: &'a [(f64, [u8; 3])],
+}
+
+impl ColorMap<'_> {
+    /// Maps a normalized escape value `t` in [0, 1] to a color, where 0 is far from the set
+    /// and 1 is right at its edge. With an offset the palette wraps around, so colors pushed past its end come back in at the start.
+    pub fn color(self, t: f64) -> Rgb<u8> {
+        let t = t.clamp(0.0, 1.0);
+        // Without an offset t = 1 has to stay at the end of the palette rather than wrap to 0
+        let t = if self.offset == 0.0 {
+            t
+        } else {
+            (t + self.offset).rem_euclid(1.0)
+        };
+        self.palette.color(self.stops, t)
+    }
+}
+
+/// Palette offset of image `index` under `--color-offset-by-index`: `base` for the first image,
+/// then `step` more for each one after it.
+pub fn palette_offset(base: f64, step: f64, index: usize) -> f64 {
+    base + index as f64 * step
+}
+
+/// Color stops of a `--palette-file` gradient as (position, rgb) pairs, in position order.
+pub type PaletteStops = Vec<(f64, [u8; 3])>;
+
+/// One color stop in a `--palette-file`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PaletteStop {
+    /// Where the color sits, from 0 (far from the set) to 1 (at its edge)
+    pub position: f64,
+    pub rgb: [u8; 3],
+}
+
+/// Reads a `--palette-file`: a JSON list of at least two stops whose positions are in [0, 1]
+/// and strictly increasing.
+pub fn read_palette_file(path: &Path) -> Result<PaletteStops, RegenError> {
+    let invalid = |message: String| {
+        RegenError::InvalidParams(format!("palette file {}: {}", path.display(), message))
+    };
+    let stops: Vec<PaletteStop> =
+        serde_json::from_reader(fs::File::open(path)?).map_err(|e| invalid(e.to_string()))?;
+    if stops.len() < 2 {
+        return Err(invalid(format!(
+            "needs at least 2 stops, got {}",
+            stops.len()
+        )));
+    }
+    if let Some(stop) = stops.iter().find(|s| !(0.0..=1.0).contains(&s.position)) {
+        return Err(invalid(format!(
+            "position {} is outside [0, 1]",
+            stop.position
+        )));
+    }
+    if let Some(pair) = stops.windows(2).find(|w| w[0].position >= w[1].position) {
+        return Err(invalid(format!(
+            "positions must increase, got {} then {}",
+            pair[0].position, pair[1].position
+        )));
+    }
+    Ok(stops.into_iter().map(|s| (s.position, s.rgb)).collect())
+}
+
+/// The palette to render with and its stops: the `--palette-file` gradient if one is given,
+/// otherwise `palette` with no stops.
+pub fn resolve_palette(
+    palette: Palette,
+    palette_file: Option<&Path>,
+) -> Result<(Palette, PaletteStops), RegenError> {
+    let Some(path) = palette_file else {
+        return Ok((palette, Vec::new()));
+    };
+    let stops = read_palette_file(path)?;
+    info!(
+        "Loaded {} palette stops from {}",
+        stops.len(),
+        path.display()
+    );
+    Ok((Palette::Custom, stops))
+}
+
+fn custom(stops: &[(f64, [u8; 3])], t: f64) -> Rgb<u8> {
+    // A hand-built ColorMap may carry fewer stops than a palette file is allowed to
+    let color = match stops {
+        [] => return grayscale(t),
+        [(_, only)] => Rgb(*only),
+        _ => gradient(stops, t),
+    };
+    // Pure black is reserved for in-set points, so a black stop comes out one shade lighter
+    match color {
+        Rgb([0, 0, 0]) => Rgb([1, 1, 1]),
+        color => color,
+    }
+}
+
+fn grayscale(t: f64) -> Rgb<u8> {
+    let shade = 255 - (t * 254.0).round() as u8;
+    Rgb([shade, shade, shade])
+}
+
+fn fire(t: f64) -> Rgb<u8> {
+    gradient(
+        &[
+            (0.0, [255, 255, 210]),
+            (0.3, [255, 200, 40]),
+            (0.6, [230, 80, 0]),
+            (1.0, [70, 0, 0]),
+        ],
+        t,
+    )
+}
+
+fn ice(t: f64) -> Rgb<u8> {
+    gradient(
+        &[
+            (0.0, [245, 250, 255]),
+            (0.4, [140, 200, 240]),
+            (0.7, [30, 100, 200]),
+            (1.0, [0, 15, 70]),
+        ],
+        t,
+    )
+}
+
+fn rainbow(t: f64) -> Rgb<u8> {
+    // Full saturation and value, hue from 0 (red) to 300 (magenta) degrees
+    let hue = t * 5.0;
+    let fraction = hue.fract();
+    let rising = (fraction * 255.0).round() as u8;
+    let falling = 255 - rising;
+    match hue as u32 {
+        0 => Rgb([255, rising, 0]),
+        1 => Rgb([falling, 255, 0]),
+        2 => Rgb([0, 255, rising]),
+        3 => Rgb([0, falling, 255]),
+        4 => Rgb([rising, 0, 255]),
+        _ => Rgb([255, 0, 255]),
+    }
+}
+
+/// Linear interpolation between color stops, which must be sorted and span [0, 1].
+fn gradient(stops: &[(f64, [u8; 3])], t: f64) -> Rgb<u8> {
+    let upper = stops
+        .iter()
+        .position(|&(at, _)| at >= t)
+        .unwrap_or(stops.len() - 1)
+        .max(1);
+    let (from_at, from) = stops[upper - 1];
+    let (to_at, to) = stops[upper];
+    // Clamped so a custom palette holds its end colors outside its first and last stops
+    let mix = ((t - from_at) / (to_at - from_at)).clamp(0.0, 1.0);
+    let channel = |i: usize| (from[i] as f64 + (to[i] as f64 - from[i] as f64) * mix).round() as u8;
+    Rgb([channel(0), channel(1), channel(2)])
+}
+
+/// Pixel statistics gathered while rendering, so callers can judge an image without scanning it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderStats {
+    /// Share of black (in-set) pixels.
+    pub black_ratio: f64,
+    /// Variance of the pixels' luma, near 0 for a render that is a single flat color.
+    pub variance: f64,
+}
+
+impl RenderStats {
+    /// Statistics of an existing RGB image, the same as a render of it would report.
+    pub fn of(img: &RgbImage) -> RenderStats {
+        let mut tally = PixelTally::default();
+        for pixel in img.pixels() {
+            tally.add(pixel.0);
+        }
+        tally.stats()
+    }
+}
+
+/// Running pixel count, black count and luma sums of part of an image, merged across bands.
+#[derive(Debug, Default, Clone, Copy)]
+struct PixelTally {
+    pixels: u64,
+    black: u64,
+    luma_sum: f64,
+    luma_sq_sum: f64,
+}
+
+impl PixelTally {
+    fn add(&mut self, rgb: [u8; 3]) {
+        // Rec. 601 luma, so the variance follows perceived brightness
+        let luma = 0.299 * rgb[0] as f64 + 0.587 * rgb[1] as f64 + 0.114 * rgb[2] as f64;
+        self.pixels += 1;
+        self.black += (rgb == [0, 0, 0]) as u64;
+        self.luma_sum += luma;
+        self.luma_sq_sum += luma * luma;
+    }
+
+    fn merge(self, other: PixelTally) -> PixelTally {
+        PixelTally {
+            pixels: self.pixels + other.pixels,
+            black: self.black + other.black,
+            luma_sum: self.luma_sum + other.luma_sum,
+            luma_sq_sum: self.luma_sq_sum + other.luma_sq_sum,
+        }
+    }
+
+    fn stats(self) -> RenderStats {
+        let pixels = self.pixels.max(1) as f64;
+        let mean = self.luma_sum / pixels;
+        RenderStats {
+            black_ratio: self.black as f64 / pixels,
+            // Clamped since rounding can leave a flat image slightly negative
+            variance: (self.luma_sq_sum / pixels - mean * mean).max(0.0),
+        }
+    }
+}
+
+/// Renders one image in memory, returning it with its pixel statistics. The image is RGB
+/// unless `rgba` is set. The cardioid and bulb check is left to `skip_interior` so tests can
+/// compare against full iteration.
+#[allow(clippy::too_many_arguments)]
+fn render_image(
+    width: u32,
+    height: u32,
+    pattern_type: Pattern,
+    mandelbrot_params: Option<MandelbrotParams>,
+    julia_constant: Option<(f64, f64)>,
+    rng: &mut impl Rng,
+    rgba: bool,
+    pixel_center: bool,
+    colors: ColorMap,
+    supersample: u32,
+    coloring: Coloring,
+    color_clamp: ColorClamp,
+    trap: Trap,
+    power: u32,
+    bailout: f64,
+    tile_size: u32,
+    deep_zoom: bool,
+    aspect: Aspect,
+    skip_interior: bool,
+) -> Result<(DynamicImage, RenderStats), RegenError> {
+    // White background, fully transparent in RGBA mode; opaque output is converted back to
+    // RGB on save
+    let background = Rgba([255, 255, 255, if rgba { 0 } else { 255 }]);
+    let mut img: RgbaImage = ImageBuffer::from_pixel(width, height, background);
+    // Counted while rendering, so callers don't need to decode the saved file again
+    let mut tally = PixelTally::default();
+
+    match pattern_type {
+        Pattern::Mandelbrot | Pattern::Julia | Pattern::BurningShip | Pattern::Newton => {
+            info!(
+                "Generating {} pattern with params: {:?}",
+                pattern_type, mandelbrot_params
+            );
+            // Mandelbrot and Burning Ship iterate from z = 0 with c at the pixel, Julia starts
+            // z at the pixel and keeps c fixed
+            let julia_c = (pattern_type == Pattern::Julia)
+                .then(|| julia_constant.unwrap_or(DEFAULT_JULIA_CONSTANT));
+            let burning_ship = pattern_type == Pattern::BurningShip;
+            // Newton basins share the view window and sampling but have their own coloring
+            let newton = pattern_type == Pattern::Newton;
+            // Mandelbrot points in the main cardioid or period-2 bulb never escape, so they are
+            // painted in-set without iterating. The shapes are only known for z² + c from z = 0
+            let skip_interior = skip_interior && pattern_type == Pattern::Mandelbrot && power == 2;
+
+            // Default parameters, can be overridden by `mandelbrot_params`
+            let (x_pos, y_pos, view_scale, max_iterations, smoothness, color_step) =
+                mandelbrot_params.unwrap_or(default_params(pattern_type));
+
+            let ViewBounds {
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+            } = view_bounds(width, height, x_pos, y_pos, view_scale, aspect);
+
+            // Sample each pixel at its center rather than its top-left corner, so the
+            // middle of the image lands exactly on (x_pos, y_pos)
+            let offset = if pixel_center { 0.5 } else { 0.0 };
+            let (view_width, view_height) = view_size(width, height, view_scale, aspect);
+
+            // Each pixel averages a supersample x supersample grid of samples, spread evenly
+            // over the pixel (and placed by `offset` within each cell)
+            let samples = supersample * supersample;
+            let sample_at =
+                |pixel: usize, sub: u32| pixel as f64 + (sub as f64 + offset) / supersample as f64;
+            let point_at = |x: usize, y: usize, sub_x: u32, sub_y: u32| {
+                (
+                    x_min + (sample_at(x, sub_x) / width as f64) * (x_max - x_min),
+                    y_min + (sample_at(y, sub_y) / height as f64) * (y_max - y_min),
+                )
+            };
+            // Mandelbrot-type sets start z at 0 with c at the pixel, Julia sets the other way
+            // around
+            let orbit_start = |point: (f64, f64)| match julia_c {
+                Some(c) => (point, c),
+                None => ((0.0, 0.0), point),
+            };
+            // With --deep-zoom samples are placed by their offset from the center, which keeps
+            // its precision where x_min + fraction × width would round neighbours together
+            let reference = deep_zoom.then(|| {
+                let sample_size = view_width / width as f64 / supersample as f64;
+                ReferenceOrbit::new((x_pos, y_pos), sample_size, max_iterations, bailout)
+            });
+            let escape_at = |x: usize, y: usize, sub_x: u32, sub_y: u32| match &reference {
+                Some(reference) => {
+                    let delta_c = (
+                        (sample_at(x, sub_x) / width as f64 - 0.5) * view_width,
+                        (sample_at(y, sub_y) / height as f64 - 0.5) * view_height,
+                    );
+                    reference.escape_time(delta_c, max_iterations, bailout)
+                }
+                None => {
+                    let ((z_real, z_imag), (c_real, c_imag)) =
+                        orbit_start(point_at(x, y, sub_x, sub_y));
+                    escape_time(
+                        z_real,
+                        z_imag,
+                        c_real,
+                        c_imag,
+                        max_iterations,
+                        burning_ship,
+                        power,
+                        bailout,
+                    )
+                }
+            };
+
+            // Histogram coloring ranks each sample against the whole image, and --color-clamp
+            // scale needs the image's largest value, so every escape value is computed up front,
+            // in row-major sample order. In-set samples are NaN
+            let scale = coloring == Coloring::Escape && color_clamp == ColorClamp::Scale;
+            let escape_values =
+                ((coloring == Coloring::Histogram || scale) && !newton).then(|| {
+                    (0..height as usize)
+                        .into_par_iter()
+                        .flat_map_iter(|y| {
+                            (0..width as usize).flat_map(move |x| {
+                                (0..samples).map(move |sub| {
+                                    let (sub_x, sub_y) = (sub % supersample, sub / supersample);
+                                    let (_, (c_real, c_imag)) =
+                                        orbit_start(point_at(x, y, sub_x, sub_y));
+                                    if skip_interior && in_main_cardioid_or_bulb(c_real, c_imag) {
+                                        return f64::NAN;
+                                    }
+                                    let (iterations, magnitude_sq) = escape_at(x, y, sub_x, sub_y);
+                                    if iterations == max_iterations {
+                                        f64::NAN
+                                    } else {
+                                        escape_value(
+                                            iterations,
+                                            magnitude_sq,
+                                            smoothness,
+                                            bailout,
+                                            power,
+                                        )
+                                    }
+                                })
+                            })
+                        })
+                        .collect::<Vec<f64>>()
+                });
+            // Scaling divides by the largest value instead of color_step, so it maps to 255
+            let color_step = match &escape_values {
+                Some(values) if scale => values
+                    .iter()
+                    .copied()
+                    .filter(|v| !v.is_nan())
+                    .reduce(f64::max)
+                    .filter(|max| *max > 0.0)
+                    .unwrap_or(color_step),
+                _ => color_step,
+            };
+            let histogram = escape_values
+                .filter(|_| coloring == Coloring::Histogram)
+                .map(|values| {
+                    let mut sorted: Vec<f64> =
+                        values.iter().copied().filter(|v| !v.is_nan()).collect();
+                    sorted.par_sort_unstable_by(f64::total_cmp);
+                    (values, sorted)
+                });
+
+            // Pixels are independent, so bands of tile_size rows are rendered in parallel
+            // straight into their slice of the pixel buffer. Within a band the pixels go one
+            // tile at a time, row-major inside the tile, so the output is the same for any
+            // tile size
+            let row_len = width as usize * 4;
+            let tile = tile_size as usize;
+            // Distance coloring draws boundaries about two pixels wide at any zoom
+            let pixel_size = (x_max - x_min) / width as f64;
+            tally = img
+                .par_chunks_mut(row_len * tile)
+                .enumerate()
+                .map(|(band, rows)| {
+                    let mut band_tally = PixelTally::default();
+                    for tile_x in (0..width as usize).step_by(tile) {
+                        let tile_end = (tile_x + tile).min(width as usize);
+                        for (row, pixels) in rows.chunks_exact_mut(row_len).enumerate() {
+                            let y = band * tile + row;
+                            for (x, pixel) in pixels[tile_x * 4..tile_end * 4]
+                                .chunks_exact_mut(4)
+                                .enumerate()
+                                .map(|(i, pixel)| (tile_x + i, pixel))
+                            {
+                                let mut sum = [0u32; 4];
+                                for sub_y in 0..supersample {
+                                    for sub_x in 0..supersample {
+                                        let (point_real, point_imag) = point_at(x, y, sub_x, sub_y);
+                                        let ((z_real, z_imag), (c_real, c_imag)) =
+                                            orbit_start((point_real, point_imag));
+
+                                        let color = if newton {
+                                            newton_color(point_real, point_imag, max_iterations)
+                                        } else if skip_interior
+                                            && in_main_cardioid_or_bulb(c_real, c_imag)
+                                        {
+                                            // Every coloring paints in-set points black
+                                            Rgba([0, 0, 0, 255])
+                                        } else {
+                                            match coloring {
+                                                Coloring::Escape => {
+                                                    let (iterations, magnitude_sq) =
+                                                        escape_at(x, y, sub_x, sub_y);
+                                                    escape_time_color(
+                                                        iterations,
+                                                        max_iterations,
+                                                        magnitude_sq,
+                                                        smoothness,
+                                                        bailout,
+                                                        power,
+                                                        color_step,
+                                                        color_clamp,
+                                                        background,
+                                                        colors,
+                                                    )
+                                                }
+                                                Coloring::Distance => distance_color(
+                                                    distance_estimate(
+                                                        z_real,
+                                                        z_imag,
+                                                        c_real,
+                                                        c_imag,
+                                                        max_iterations,
+                                                        burning_ship,
+                                                        julia_c.is_some(),
+                                                        power,
+                                                    ),
+                                                    pixel_size,
+                                                    background,
+                                                    colors,
+                                                ),
+                                                Coloring::OrbitTrap => orbit_trap_color(
+                                                    orbit_trap(
+                                                        z_real,
+                                                        z_imag,
+                                                        c_real,
+                                                        c_imag,
+                                                        max_iterations,
+                                                        burning_ship,
+                                                        power,
+                                                        bailout,
+                                                        trap,
+                                                    ),
+                                                    colors,
+                                                ),
+                                                Coloring::Histogram => {
+                                                    let (values, sorted) =
+                                                        histogram.as_ref().expect(
+                                                            "escape values computed for histogram",
+                                                        );
+                                                    let sample = ((y * width as usize + x)
+                                                        * samples as usize)
+                                                        + (sub_y * supersample + sub_x) as usize;
+                                                    histogram_color(
+                                                        values[sample],
+                                                        sorted,
+                                                        background,
+                                                        colors,
+                                                    )
+                                                }
+                                            }
+                                        };
+                                        for (total, channel) in sum.iter_mut().zip(color.0) {
+                                            *total += channel as u32;
+                                        }
+                                    }
+                                }
+                                for (channel, total) in pixel.iter_mut().zip(sum) {
+                                    *channel = ((total + samples / 2) / samples) as u8;
+                                }
+                                band_tally.add([pixel[0], pixel[1], pixel[2]]);
+                            }
+                        }
+                    }
+                    band_tally
+                })
+                .reduce(PixelTally::default, PixelTally::merge);
+            info!("Finished {} pattern generation", pattern_type);
+        }
+        Pattern::Noise => {
+            for x in 0..width {
+                for y in 0..height {
+                    let r_val = rng.r#gen();
+                    let g_val = rng.r#gen();
+                    let b_val = rng.r#gen();
+                    tally.add([r_val, g_val, b_val]);
+                    img.put_pixel(x, y, Rgba([r_val, g_val, b_val, 255]));
+                }
+            }
+            info!("Random noise image generated");
+        }
+    }
+
+    let img = if rgba {
+        DynamicImage::ImageRgba8(img)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).into_rgb8())
+    };
+    Ok((img, tally.stats()))
+}
+
+/// Parameters used for a pattern when none are given.
+pub fn default_params(pattern_type: Pattern) -> MandelbrotParams {
+    match pattern_type {
+        Pattern::Julia => DEFAULT_JULIA_PARAMS,
+        Pattern::BurningShip => DEFAULT_BURNING_SHIP_PARAMS,
+        Pattern::Newton => DEFAULT_NEWTON_PARAMS,
+        Pattern::Mandelbrot | Pattern::Noise => DEFAULT_MANDELBROT_PARAMS,
+    }
+}
+
+/// Creates the random generator for one image of a seeded batch. Each image gets its own
+/// stream derived from the seed and its index, so results don't depend on task scheduling
+/// or on which slice of the batch a run covers.
+pub fn image_rng(seed: u64, index: usize) -> StdRng {
+    let mut key = [0u8; 32];
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..16].copy_from_slice(&(index as u64).to_le_bytes());
+    StdRng::from_seed(key)
+}
+
+/// Parameters an image was generated with, embedded in its PNG so it can be reproduced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenParams {
+    pub pattern: Pattern,
+    pub width: u32,
+    pub height: u32,
+    pub x_pos: f64,
+    pub y_pos: f64,
+    #[serde(alias = "escape_radius")]
+    pub view_scale: f64,
+    pub max_iterations: u32,
+    pub smoothness: u32,
+    pub color_step: f64,
+    pub palette: Palette,
+    /// Stops of a `--palette-file` gradient, used when `palette` is `Custom`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub palette_stops: PaletteStops,
+    /// Fraction of the palette it was turned by, from `--color-offset-by-index`
+    #[serde(default)]
+    pub palette_offset: f64,
+    pub coloring: Coloring,
+    #[serde(default)]
+    pub color_clamp: ColorClamp,
+    #[serde(default)]
+    pub trap: Trap,
+    pub supersample: u32,
+    /// Constant c of a Julia render; None means `DEFAULT_JULIA_CONSTANT`
+    #[serde(default)]
+    pub julia_constant: Option<(f64, f64)>,
+    #[serde(default = "default_power")]
+    pub power: u32,
+    /// Images from before `--bailout` existed were rendered with the old fixed bailout of 2
+    #[serde(default = "legacy_bailout")]
+    pub bailout: f64,
+    pub seed: Option<u64>,
+    pub index: usize,
+    /// Rendered with `--deep-zoom`
+    #[serde(default)]
+    pub deep_zoom: bool,
+    /// Images from before `--aspect` existed always followed the pixel aspect ratio
+    #[serde(default)]
+    pub aspect: Aspect,
+    /// Rendered on a transparent background, with `--rgba`
+    #[serde(default)]
+    pub rgba: bool,
+    /// Pixels sampled at their centers rather than their top-left corners (`--pixel-corner`)
+    #[serde(default = "default_pixel_center")]
+    pub pixel_center: bool,
+    /// Side of the square tiles the work is split into; it doesn't change the pixels
+    #[serde(default = "default_tile_size")]
+    pub tile_size: u32,
+}
+
+fn default_power() -> u32 {
+    DEFAULT_POWER
+}
+
+fn legacy_bailout() -> f64 {
+    2.0
+}
+
+fn default_pixel_center() -> bool {
+    true
+}
+
+fn default_tile_size() -> u32 {
+    DEFAULT_TILE_SIZE
+}
+
+/// Renders `params` into a new RGB buffer, dropping the alpha channel of an `rgba` render.
+/// This is only the pixel computation: there's no ratio band, padding or file I/O.
+/// `Pattern::Noise` draws from the seed and index when there is a seed, so the result matches
+/// `generate`.
+pub fn render(params: &GenParams) -> Result<RgbImage, RegenError> {
+    let (img, _) = render_with_stats(params)?;
+    Ok(img.into_rgb8())
+}
+
+/// Same as `render`, but keeps an `rgba` render's transparency and also returns the pixel
+/// statistics gathered along the way.
+pub fn render_with_stats(params: &GenParams) -> Result<(DynamicImage, RenderStats), RegenError> {
+    render_params(params, true)
+}
+
+/// `render` with the cardioid and bulb check turned off, so `benches/render.rs` can time the
+/// full iteration it saves. Not part of the API.
+#[doc(hidden)]
+pub fn render_without_interior_skip(params: &GenParams) -> Result<RgbImage, RegenError> {
+    let (img, _) = render_params(params, false)?;
+    Ok(img.into_rgb8())
+}
+
+fn render_params(
+    params: &GenParams,
+    skip_interior: bool,
+) -> Result<(DynamicImage, RenderStats), RegenError> {
+    check_params(params)?;
+    let mut rng = match params.seed {
+        Some(seed) => image_rng(seed, params.index),
+        None => StdRng::from_entropy(),
+    };
+    render_image(
+        params.width,
+        params.height,
+        params.pattern,
+        Some((
+            params.x_pos,
+            params.y_pos,
+            params.view_scale,
+            params.max_iterations,
+            params.smoothness,
+            params.color_step,
+        )),
+        params.julia_constant,
+        &mut rng,
+        params.rgba,
+        params.pixel_center,
+        ColorMap {
+            palette: params.palette,
+            offset: params.palette_offset,
+            stops: &params.palette_stops,
+        },
+        params.supersample,
+        params.coloring,
+        params.color_clamp,
+        params.trap,
+        params.power,
+        params.bailout,
+        params.tile_size,
+        params.deep_zoom,
+        params.aspect,
+        skip_interior,
+    )
+}
+
+/// Rejects parameters the command line would never let through, since library callers build
+/// `GenParams` by hand.
+fn check_params(params: &GenParams) -> Result<(), RegenError> {
+    let problem = if params.width == 0 || params.height == 0 {
+        format!(
+            "image size must be non-zero, got {}x{}",
+            params.width, params.height
+        )
+    } else if !(1..=16).contains(&params.supersample) {
+        format!(
+            "supersample must be between 1 and 16, got {}",
+            params.supersample
+        )
+    } else if params.tile_size == 0 {
+        "tile size must be at least 1".to_string()
+    } else if params.power < 2 {
+        format!("power must be at least 2, got {}", params.power)
+    } else if !(params.bailout.is_finite() && params.bailout >= 2.0) {
+        format!("bailout must be at least 2, got {}", params.bailout)
+    } else if !(params.view_scale.is_finite() && params.view_scale > 0.0) {
+        format!("view_scale must be positive, got {}", params.view_scale)
+    } else if let Aspect::Ratio(w, h) = params.aspect
+        && !(w.is_finite() && w > 0.0 && h.is_finite() && h > 0.0)
+    {
+        format!("aspect ratio sides must be positive, got {}", params.aspect)
+    } else if !params.palette_offset.is_finite() {
+        format!(
+            "palette offset must be finite, got {}",
+            params.palette_offset
+        )
+    } else if let Some((re, im)) = params.julia_constant
+        && !(re.is_finite() && im.is_finite())
+    {
+        format!("julia constant must be finite, got {},{}", re, im)
+    } else if params.palette == Palette::Custom && params.palette_stops.len() < 2 {
+        format!(
+            "a custom palette needs at least 2 stops, got {}",
+            params.palette_stops.len()
+        )
+    } else if params.deep_zoom {
+        return check_deep_zoom(params.pattern, params.power, params.coloring);
+    } else {
+        return Ok(());
+    };
+    Err(RegenError::InvalidParams(problem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_offset_steps_with_the_index() {
+        let (base, step) = (0.1, 0.05);
+        let offsets: Vec<f64> = (0..5).map(|i| palette_offset(base, step, i)).collect();
+        for (i, offset) in offsets.iter().enumerate() {
+            assert_eq!(*offset, base + i as f64 * step);
+        }
+        for pair in offsets.windows(2) {
+            assert_ne!(pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn color_map_turns_and_wraps_the_palette() {
+        let plain = ColorMap {
+            palette: Palette::Rainbow,
+            offset: 0.0,
+            stops: &[],
+        };
+        for t in [0.0, 0.3, 1.0] {
+            assert_eq!(plain.color(t), Palette::Rainbow.color(&[], t));
+        }
+        let turned = ColorMap {
+            offset: 0.25,
+            ..plain
+        };
+        assert_eq!(turned.color(0.0), Palette::Rainbow.color(&[], 0.25));
+        assert_eq!(turned.color(0.9), Palette::Rainbow.color(&[], 0.15));
+        assert_ne!(turned.color(0.5), plain.color(0.5));
+    }
+
+    /// Grayscale escape-time render with everything else at its default.
+    fn render_plain(
+        width: u32,
+        height: u32,
+        params: MandelbrotParams,
+        rgba: bool,
+        pixel_center: bool,
+        color_clamp: ColorClamp,
+    ) -> DynamicImage {
+        render_plain_with_stats(width, height, params, rgba, pixel_center, color_clamp).0
+    }
+
+    fn render_plain_with_stats(
+        width: u32,
+        height: u32,
+        params: MandelbrotParams,
+        rgba: bool,
+        pixel_center: bool,
+        color_clamp: ColorClamp,
+    ) -> (DynamicImage, RenderStats) {
+        render_image(
+            width,
+            height,
+            Pattern::Mandelbrot,
+            Some(params),
+            None,
+            &mut image_rng(0, 0),
+            rgba,
+            pixel_center,
+            ColorMap {
+                palette: Palette::Grayscale,
+                offset: 0.0,
+                stops: &[],
+            },
+            1,
+            Coloring::Escape,
+            color_clamp,
+            Trap::Point,
+            DEFAULT_POWER,
+            DEFAULT_BAILOUT,
+            DEFAULT_TILE_SIZE,
+            false,
+            Aspect::Pixel,
+            true,
+        )
+        .unwrap()
+    }
+
+    /// 48x32 render of the pattern's default view, as `generate` records it.
+    fn small_params(pattern: Pattern) -> GenParams {
+        let (x_pos, y_pos, view_scale, max_iterations, smoothness, color_step) =
+            default_params(pattern);
+        GenParams {
+            pattern,
+            width: 48,
+            height: 32,
+            x_pos,
+            y_pos,
+            view_scale,
+            max_iterations,
+            smoothness,
+            color_step,
+            palette: Palette::Grayscale,
+            palette_stops: Vec::new(),
+            palette_offset: 0.0,
+            coloring: Coloring::Escape,
+            color_clamp: ColorClamp::Clamp,
+            trap: Trap::Point,
+            supersample: 1,
+            julia_constant: None,
+            power: DEFAULT_POWER,
+            bailout: DEFAULT_BAILOUT,
+            seed: Some(0),
+            index: 0,
+            deep_zoom: false,
+            aspect: Aspect::Pixel,
+            rgba: false,
+            pixel_center: true,
+            tile_size: DEFAULT_TILE_SIZE,
+        }
+    }
+
+    #[test]
+    fn escaped_points_are_colored_by_their_iteration_count() {
+        let params = (-0.75, 0.0, 0.875, 100, 1, 60.0);
+        let img = render_plain(64, 48, params, false, true, ColorClamp::Clamp).into_rgb8();
+        let escaped: Vec<&Rgb<u8>> = img.pixels().filter(|p| **p != Rgb([0, 0, 0])).collect();
+        assert!(!escaped.is_empty());
+        assert!(escaped.iter().any(|p| **p != Rgb([255, 255, 255])));
+
+        let banded = (-0.75, 0.0, 0.875, 100, 0, 60.0);
+        let banded = render_plain(64, 48, banded, false, true, ColorClamp::Clamp).into_rgb8();
+        assert_ne!(banded, img, "smoothness should change the colors");
+    }
+
+    #[test]
+    fn julia_renders_use_the_given_constant() {
+        let params = |julia_constant| GenParams {
+            julia_constant,
+            ..small_params(Pattern::Julia)
+        };
+        let in_set = |julia_constant| {
+            render(&params(julia_constant))
+                .unwrap()
+                .pixels()
+                .filter(|p| **p == Rgb([0, 0, 0]))
+                .count()
+        };
+        // c = -1 is the basilica, a connected set with a solid interior
+        let basilica = in_set(Some((-1.0, 0.0)));
+        assert!(
+            basilica > 0 && basilica < 48 * 32,
+            "{} in-set pixels",
+            basilica
+        );
+        assert_ne!(basilica, in_set(None));
+    }
+
+    #[test]
+    fn rgba_background_is_transparent_and_the_fractal_opaque() {
+        let img = render_plain(
+            64,
+            48,
+            (-0.75, 0.0, 0.875, 100, 1, 1000.0),
+            true,
+            true,
+            ColorClamp::Clamp,
+        );
+        let img = img
+            .as_rgba8()
+            .expect("rgba renders keep their alpha channel");
+        let (mut background, mut in_set, mut escaped) = (0, 0, 0);
+        for Rgba([r, g, b, a]) in img.pixels() {
+            match (r, g, b, a) {
+                (255, 255, 255, 0) => background += 1,
+                (0, 0, 0, 255) => in_set += 1,
+                (_, _, _, 255) => escaped += 1,
+                _ => panic!("unexpected pixel {:?}", [r, g, b, a]),
+            }
+        }
+        assert!(background > 0 && in_set > 0 && escaped > 0);
+    }
+
+    #[test]
+    fn pixel_center_sampling_hits_the_view_center() {
+        // c = -2 is in the set, but everything just left of it or off the real axis escapes,
+        // so a single pixel is black only if it is sampled exactly at the center
+        let params = (-2.0, 0.0, 0.01, 100, 1, 1000.0);
+        let centered = render_plain(1, 1, params, false, true, ColorClamp::Clamp).into_rgb8();
+        assert_eq!(centered.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        let cornered = render_plain(1, 1, params, false, false, ColorClamp::Clamp).into_rgb8();
+        assert_ne!(cornered.get_pixel(0, 0), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn wrap_differs_from_clamp_only_past_the_palette() {
+        for value in [0.0, 1.0, 100.5, 254.0] {
+            assert_eq!(
+                ColorClamp::Wrap.intensity(value),
+                ColorClamp::Clamp.intensity(value)
+            );
+        }
+        for value in [300.0, 1000.0] {
+            let wrapped = ColorClamp::Wrap.intensity(value);
+            assert_ne!(wrapped, ColorClamp::Clamp.intensity(value));
+            assert!((1..=254).contains(&wrapped));
+        }
+    }
+
+    #[test]
+    fn scale_stretches_the_largest_value_to_the_palette_end() {
+        // A huge color_step leaves every escaped point at the background with clamping
+        let params = (-0.75, 0.0, 0.875, 100, 1, 1e9);
+        let darkest = Palette::Grayscale.color(&[], 1.0);
+        let clamped = render_plain(32, 24, params, false, true, ColorClamp::Clamp).into_rgb8();
+        assert!(clamped.pixels().all(|p| *p != darkest));
+        let scaled = render_plain(32, 24, params, false, true, ColorClamp::Scale).into_rgb8();
+        assert!(scaled.pixels().any(|p| *p == darkest));
+    }
+
+    #[test]
+    fn parallel_render_matches_a_single_thread() {
+        let params = (-0.745, 0.113, 0.05, 300, 8, 6000.0);
+        let parallel = render_plain(64, 48, params, false, true, ColorClamp::Clamp);
+        let serial = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| render_plain(64, 48, params, false, true, ColorClamp::Clamp));
+        assert_eq!(parallel.as_bytes(), serial.as_bytes());
+    }
+
+    #[test]
+    fn named_palettes_give_distinct_deterministic_colors() {
+        let palettes = [
+            Palette::Grayscale,
+            Palette::Fire,
+            Palette::Ice,
+            Palette::Rainbow,
+        ];
+        let mut middles = Vec::new();
+        for palette in palettes {
+            let samples: Vec<Rgb<u8>> = [0.0, 0.5, 1.0].map(|t| palette.color(&[], t)).to_vec();
+            assert_eq!(samples, [0.0, 0.5, 1.0].map(|t| palette.color(&[], t)));
+            assert!(
+                samples.iter().all(|c| *c != Rgb([0, 0, 0])),
+                "{:?}",
+                palette
+            );
+            assert!(
+                samples[0] != samples[1] && samples[1] != samples[2] && samples[0] != samples[2],
+                "{:?}: {:?}",
+                palette,
+                samples
+            );
+            middles.push(samples[1]);
+        }
+        for (i, a) in middles.iter().enumerate() {
+            assert!(middles[i + 1..].iter().all(|b| a != b), "{:?}", middles);
+        }
+    }
+
+    #[test]
+    fn supersampling_smooths_edges_but_keeps_the_interior_black() {
+        let whole_set = GenParams {
+            x_pos: -0.75,
+            y_pos: 0.0,
+            view_scale: 0.875,
+            ..small_params(Pattern::Mandelbrot)
+        };
+        let plain = render(&whole_set).unwrap();
+        let smooth = render(&GenParams {
+            supersample: 2,
+            ..whole_set
+        })
+        .unwrap();
+        let black = Rgb([0, 0, 0]);
+        let is_black = |img: &RgbImage, x: u32, y: u32| *img.get_pixel(x, y) == black;
+        let mut changed_edges = 0;
+        for (x, y, pixel) in plain.enumerate_pixels() {
+            // Deep interior: everything within two pixels is in the set too
+            let interior = (x.saturating_sub(2)..=(x + 2).min(47)).all(|nx| {
+                (y.saturating_sub(2)..=(y + 2).min(31)).all(|ny| is_black(&plain, nx, ny))
+            });
+            if interior {
+                assert_eq!(smooth.get_pixel(x, y), &black, "({}, {})", x, y);
+            } else if *pixel == black && smooth.get_pixel(x, y) != pixel {
+                changed_edges += 1;
+            }
+        }
+        assert!(changed_edges > 0);
+        assert_ne!(plain, smooth);
+    }
+
+    #[test]
+    fn black_ratio_matches_a_pixel_scan() {
+        let params = (-0.75, 0.0, 0.875, 100, 1, 60.0);
+        let (img, stats) = render_plain_with_stats(64, 48, params, false, true, ColorClamp::Clamp);
+        let img = img.into_rgb8();
+        let black = img.pixels().filter(|p| **p == Rgb([0, 0, 0])).count();
+        assert!(black > 0);
+        assert_eq!(stats.black_ratio, black as f64 / (64 * 48) as f64);
+    }
+
+    #[test]
+    fn burning_ship_differs_from_mandelbrot_for_the_same_view() {
+        let ship = small_params(Pattern::BurningShip);
+        let in_set = |params: &GenParams| {
+            render(params)
+                .unwrap()
+                .pixels()
+                .filter(|p| **p == Rgb([0, 0, 0]))
+                .count()
+        };
+        let ship_in_set = in_set(&ship);
+        assert!(ship_in_set > 0 && ship_in_set < 48 * 32, "{}", ship_in_set);
+        let mandelbrot = GenParams {
+            pattern: Pattern::Mandelbrot,
+            ..ship.clone()
+        };
+        assert_ne!(render(&ship).unwrap(), render(&mandelbrot).unwrap());
+        assert_ne!(ship_in_set, in_set(&mandelbrot));
+    }
+
+    #[test]
+    fn distance_coloring_is_deterministic_and_differs_from_escape() {
+        let escape = small_params(Pattern::Mandelbrot);
+        let distance = GenParams {
+            coloring: Coloring::Distance,
+            ..escape.clone()
+        };
+        let distance_img = render(&distance).unwrap();
+        assert_eq!(distance_img, render(&distance).unwrap());
+        let escape_img = render(&escape).unwrap();
+        assert_ne!(distance_img, escape_img);
+        // Only the coloring of escaped points changes, not which points are in the set
+        let black = |img: &RgbImage| img.pixels().filter(|p| **p == Rgb([0, 0, 0])).count();
+        assert_eq!(black(&distance_img), black(&escape_img));
+    }
+
+    #[test]
+    fn power_three_multibrot_has_two_fold_symmetry() {
+        let params = GenParams {
+            x_pos: 0.0,
+            y_pos: 0.0,
+            view_scale: 1.5,
+            power: 3,
+            ..small_params(Pattern::Mandelbrot)
+        };
+        let img = render(&params).unwrap();
+        let (width, height) = img.dimensions();
+        let in_set = |x, y| *img.get_pixel(x, y) == Rgb([0, 0, 0]);
+        let mut black = 0;
+        for (x, y, _) in img.enumerate_pixels() {
+            // z^3 + c is unchanged by c -> -c (a half turn) and by conjugation
+            assert_eq!(
+                in_set(x, y),
+                in_set(width - 1 - x, height - 1 - y),
+                "({}, {})",
+                x,
+                y
+            );
+            assert_eq!(in_set(x, y), in_set(x, height - 1 - y), "({}, {})", x, y);
+            black += in_set(x, y) as u32;
+        }
+        assert!(black > 0 && black < width * height);
+
+        // The classic set has no half-turn symmetry
+        let classic = render(&GenParams { power: 2, ..params }).unwrap();
+        let turned = image::imageops::rotate180(&classic);
+        assert_ne!(classic, turned);
+    }
+
+    #[test]
+    fn orbit_trap_coloring_is_deterministic_and_differs_from_escape() {
+        let escape = small_params(Pattern::Mandelbrot);
+        let escape_img = render(&escape).unwrap();
+        let mut trapped = Vec::new();
+        for trap in [Trap::Point, Trap::Cross, Trap::Circle] {
+            let params = GenParams {
+                coloring: Coloring::OrbitTrap,
+                trap,
+                ..escape.clone()
+            };
+            let img = render(&params).unwrap();
+            assert_eq!(img, render(&params).unwrap(), "{:?}", trap);
+            assert_ne!(img, escape_img, "{:?}", trap);
+            trapped.push(img);
+        }
+        assert!(trapped[0] != trapped[1] && trapped[1] != trapped[2]);
+    }
+
+    #[test]
+    fn newton_colors_each_root_basin_differently() {
+        let params = small_params(Pattern::Newton);
+        let img = render(&params).unwrap();
+        assert_eq!(img, render(&params).unwrap());
+
+        // Shading scales all channels together, so the brightest channel names the basin
+        let mut basins = std::collections::HashSet::new();
+        for pixel in img.pixels() {
+            let [r, g, b] = pixel.0;
+            if pixel.0 != [0, 0, 0] {
+                basins.insert(if r >= g && r >= b {
+                    'r'
+                } else if g >= b {
+                    'g'
+                } else {
+                    'b'
+                });
+            }
+        }
+        assert_eq!(basins, std::collections::HashSet::from(['r', 'g', 'b']));
+    }
+
+    #[test]
+    fn bailout_sets_where_iteration_stops() {
+        let (c_real, c_imag) = (0.5, 0.5);
+        let smooth = |bailout: f64| {
+            let (iterations, magnitude_sq) =
+                escape_time(0.0, 0.0, c_real, c_imag, 1000, false, 2, bailout);
+            (
+                iterations,
+                escape_value(iterations, magnitude_sq, 1, bailout, 2),
+            )
+        };
+        let (small_iterations, small) = smooth(2.0);
+        let (large_iterations, large) = smooth(DEFAULT_BAILOUT);
+        assert!(large_iterations > small_iterations);
+        assert!((small - large).abs() > 1e-3, "{} vs {}", small, large);
+        // Past a large bailout the smoothed count barely depends on it any more
+        let (_, larger) = smooth(DEFAULT_BAILOUT * 16.0);
+        assert!((large - larger).abs() < 0.05, "{} vs {}", large, larger);
+
+        // Orbit traps keep following the orbit out to the same bailout
+        let traps = |bailout: f64| -> Vec<Option<f64>> {
+            (0..20)
+                .map(|i| {
+                    let c_real = -2.0 + i as f64 * 0.2;
+                    orbit_trap(0.0, 0.0, c_real, 0.7, 100, false, 2, bailout, Trap::Cross)
+                })
+                .collect()
+        };
+        let (near, far) = (traps(2.0), traps(DEFAULT_BAILOUT));
+        assert_ne!(near, far);
+        for (near, far) in near.iter().zip(&far) {
+            assert_eq!(near.is_some(), far.is_some());
+            assert!(far <= near, "{:?} vs {:?}", far, near);
+        }
+    }
+
+    /// Full view of the Mandelbrot set, where the cardioid and bulb cover much of the image.
+    fn render_full_view(
+        width: u32,
+        height: u32,
+        coloring: Coloring,
+        supersample: u32,
+        skip_interior: bool,
+    ) -> (DynamicImage, RenderStats) {
+        render_image(
+            width,
+            height,
+            Pattern::Mandelbrot,
+            Some((-0.75, 0.0, 0.875, 500, 8, 6000.0)),
+            None,
+            &mut image_rng(0, 0),
+            false,
+            true,
+            ColorMap {
+                palette: Palette::Fire,
+                offset: 0.0,
+                stops: &[],
+            },
+            supersample,
+            coloring,
+            ColorClamp::Clamp,
+            Trap::Point,
+            DEFAULT_POWER,
+            DEFAULT_BAILOUT,
+            DEFAULT_TILE_SIZE,
+            false,
+            Aspect::Pixel,
+            skip_interior,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn cardioid_and_bulb_skip_matches_full_iteration() {
+        for coloring in Coloring::value_variants() {
+            for supersample in [1, 2] {
+                let (fast, fast_stats) = render_full_view(96, 64, *coloring, supersample, true);
+                let (full, full_stats) = render_full_view(96, 64, *coloring, supersample, false);
+                assert!(fast == full, "{:?} x{} differs", coloring, supersample);
+                assert_eq!(fast_stats, full_stats, "{:?} x{}", coloring, supersample);
+            }
+        }
+    }
+
+    #[test]
+    fn histogram_coloring_spreads_values_more_evenly_than_escape() {
+        // Entropy of the escaped pixels' gray levels in 16 bins; higher is flatter
+        let entropy = |coloring: Coloring| {
+            let params = GenParams {
+                width: 96,
+                height: 64,
+                coloring,
+                ..small_params(Pattern::Mandelbrot)
+            };
+            let img = render(&params).unwrap();
+            let mut bins = [0u32; 16];
+            let mut escaped = 0;
+            for pixel in img.pixels() {
+                if pixel.0 != [0, 0, 0] {
+                    bins[pixel.0[0] as usize / 16] += 1;
+                    escaped += 1;
+                }
+            }
+            assert!(escaped > 0);
+            bins.iter()
+                .filter(|count| **count > 0)
+                .map(|count| {
+                    let p = *count as f64 / escaped as f64;
+                    -p * p.log2()
+                })
+                .sum::<f64>()
+        };
+        let (escape, histogram) = (entropy(Coloring::Escape), entropy(Coloring::Histogram));
+        assert!(
+            histogram > escape + 0.5,
+            "histogram {} vs escape {}",
+            histogram,
+            escape
+        );
+        // Close to the 4 bits of a perfectly even spread
+        assert!(histogram > 3.5, "{}", histogram);
+    }
+
+    #[test]
+    fn tiled_render_matches_a_single_untiled_pass() {
+        for coloring in [Coloring::Escape, Coloring::Histogram] {
+            let params = GenParams {
+                width: 70,
+                height: 45,
+                coloring,
+                ..small_params(Pattern::Mandelbrot)
+            };
+            // One tile covering the whole image is a plain row-by-row scan
+            let untiled = render(&GenParams {
+                tile_size: 128,
+                ..params.clone()
+            })
+            .unwrap();
+            for tile_size in [1, 7, 16, DEFAULT_TILE_SIZE] {
+                let tiled = GenParams {
+                    tile_size,
+                    ..params.clone()
+                };
+                assert!(
+                    render(&tiled).unwrap() == untiled,
+                    "{:?} with {}-pixel tiles",
+                    coloring,
+                    tile_size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn three_stop_palette_file_blends_at_the_midpoints() {
+        let dir = std::env::temp_dir().join(format!("regen-palette-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("palette.json");
+        fs::write(
+            &path,
+            r#"[
+                {"position": 0.0, "rgb": [255, 0, 0]},
+                {"position": 0.5, "rgb": [0, 255, 0]},
+                {"position": 1.0, "rgb": [0, 0, 255]}
+            ]"#,
+        )
+        .unwrap();
+        let (palette, stops) = resolve_palette(Palette::Fire, Some(&path)).unwrap();
+        assert_eq!(palette, Palette::Custom);
+        let colors = ColorMap {
+            palette,
+            offset: 0.0,
+            stops: &stops,
+        };
+        assert_eq!(colors.color(0.0), Rgb([255, 0, 0]));
+        assert_eq!(colors.color(0.25), Rgb([128, 128, 0]));
+        assert_eq!(colors.color(0.5), Rgb([0, 255, 0]));
+        assert_eq!(colors.color(0.75), Rgb([0, 128, 128]));
+        assert_eq!(colors.color(1.0), Rgb([0, 0, 255]));
+
+        // The stops travel with the params, so a render needs nothing loaded beforehand
+        let params = GenParams {
+            palette,
+            palette_stops: stops,
+            ..small_params(Pattern::Mandelbrot)
+        };
+        assert!(render(&params).is_ok());
+        let unloaded = GenParams {
+            palette_stops: Vec::new(),
+            ..params
+        };
+        assert!(matches!(
+            render(&unloaded),
+            Err(RegenError::InvalidParams(_))
+        ));
+
+        for bad in [
+            r#"[{"position": 0.0, "rgb": [0, 0, 0]}, {"position": 1.5, "rgb": [9, 9, 9]}]"#,
+            r#"[{"position": 0.6, "rgb": [0, 0, 0]}, {"position": 0.4, "rgb": [9, 9, 9]}]"#,
+        ] {
+            fs::write(&path, bad).unwrap();
+            assert!(matches!(
+                read_palette_file(&path),
+                Err(RegenError::InvalidParams(_))
+            ));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deep_zoom_keeps_detail_that_plain_f64_loses() {
+        // Pixels here are a small fraction of an f64 ulp apart, so plain f64 rounds whole runs
+        // of neighbours onto the same point and the image comes out in flat blocks
+        let naive = GenParams {
+            x_pos: -0.743643887037151,
+            y_pos: 0.131825904205330,
+            view_scale: 1e-16,
+            max_iterations: 5000,
+            coloring: Coloring::Histogram,
+            ..small_params(Pattern::Mandelbrot)
+        };
+        let deep = GenParams {
+            deep_zoom: true,
+            ..naive.clone()
+        };
+        // Share of pixels the same color as their right-hand neighbour
+        let uniform = |img: &RgbImage| {
+            let (width, height) = img.dimensions();
+            let same = (0..height)
+                .flat_map(|y| (1..width).map(move |x| (x, y)))
+                .filter(|&(x, y)| img.get_pixel(x, y) == img.get_pixel(x - 1, y))
+                .count();
+            same as f64 / ((width - 1) * height) as f64
+        };
+        let (naive_uniform, deep_uniform) = (
+            uniform(&render(&naive).unwrap()),
+            uniform(&render(&deep).unwrap()),
+        );
+        assert!(
+            deep_uniform < naive_uniform / 2.0,
+            "deep {} vs naive {}",
+            deep_uniform,
+            naive_uniform
+        );
+    }
+
+    #[test]
+    fn square_aspect_gives_an_equal_view_width_and_height() {
+        for (width, height) in [(64, 64), (320, 200), (100, 400), (1, 3000)] {
+            let bounds = view_bounds(width, height, -0.5, 0.25, 0.8, Aspect::Square);
+            let (view_width, view_height) =
+                (bounds.x_max - bounds.x_min, bounds.y_max - bounds.y_min);
+            assert!(
+                (view_width - view_height).abs() < 1e-12,
+                "{}x{}: {} vs {}",
+                width,
+                height,
+                view_width,
+                view_height
+            );
+            assert_eq!(view_size(width, height, 0.8, Aspect::Square), (3.2, 3.2));
+            // The default still follows the pixels
+            let (view_width, view_height) = view_size(width, height, 0.8, Aspect::Pixel);
+            assert_eq!(view_height, view_width * height as f64 / width as f64);
+        }
+        assert_eq!(
+            view_size(100, 100, 1.0, "16:9".parse().unwrap()),
+            (4.0, 2.25)
+        );
+        for bad in ["wide", "16:0", "-4:3", "4:"] {
+            assert!(bad.parse::<Aspect>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn smoothed_escape_values_are_finite_and_ordered_near_the_bailout() {
+        for (bailout, power) in [(2.0, 2), (DEFAULT_BAILOUT, 2), (DEFAULT_BAILOUT, 3)] {
+            let bailout_sq = bailout * bailout;
+            // From "escaped" without reaching the bailout, through just past it, to overflow
+            let magnitudes = [
+                0.0,
+                1e-300,
+                0.5,
+                bailout_sq * 0.999,
+                bailout_sq,
+                bailout_sq * 1.001,
+                bailout_sq.powi(2),
+                bailout_sq.powi(power as i32),
+                1e300,
+                f64::INFINITY,
+            ];
+            let mut previous_max = f64::NEG_INFINITY;
+            for iterations in 1..6 {
+                let values: Vec<f64> = magnitudes
+                    .iter()
+                    .map(|&m| escape_value(iterations, m, 3, bailout, power))
+                    .collect();
+                assert!(
+                    values.iter().all(|v| v.is_finite()),
+                    "bailout {} power {}: {:?}",
+                    bailout,
+                    power,
+                    values
+                );
+                // A larger |z| escaped sooner, so it never counts as more iterations
+                assert!(
+                    values.windows(2).all(|pair| pair[0] >= pair[1]),
+                    "{:?}",
+                    values
+                );
+                // And one more iteration never counts as fewer
+                assert!(values[values.len() - 1] >= previous_max - 1e-9);
+                previous_max = values[0];
+            }
+        }
+    }
+
+    #[test]
+    fn custom_palette_without_enough_stops_still_colors() {
+        let colors = |stops| ColorMap {
+            palette: Palette::Custom,
+            offset: 0.0,
+            stops,
+        };
+        for t in [0.0, 0.5, 1.0] {
+            assert_eq!(colors(&[]).color(t), Palette::Grayscale.color(&[], t));
+            assert_eq!(colors(&[(0.5, [0, 0, 0])]).color(t), Rgb([1, 1, 1]));
+        }
+    }
+}
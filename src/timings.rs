@@ -0,0 +1,100 @@
+//! Per-item timing summaries for `generate` and `upload` batches.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Spread of the per-item durations in a batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingSummary {
+    pub count: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl TimingSummary {
+    /// Summarizes `durations`, or returns None if there are none.
+    pub fn from_durations(durations: &[Duration]) -> Option<TimingSummary> {
+        let mut sorted = durations.to_vec();
+        sorted.sort();
+        Some(TimingSummary {
+            count: sorted.len(),
+            min: *sorted.first()?,
+            median: percentile(&sorted, 50.0),
+            p95: percentile(&sorted, 95.0),
+            max: *sorted.last()?,
+        })
+    }
+}
+
+impl fmt::Display for TimingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min {:.2?}, median {:.2?}, p95 {:.2?}, max {:.2?}",
+            self.min, self.median, self.p95, self.max
+        )
+    }
+}
+
+/// Nearest-rank percentile of non-empty, sorted durations: the smallest value that at least
+/// `p` percent of the values are less than or equal to.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Items and bytes per second over `elapsed`, e.g. "2.50 images/s, 1.20 MB/s".
+pub fn throughput(count: usize, bytes: u64, elapsed: Duration, unit: &str) -> String {
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    format!(
+        "{:.2} {}/s, {}/s",
+        count as f64 / seconds,
+        unit,
+        crate::util::human_readable_size((bytes as f64 / seconds) as u64)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_uses_nearest_rank_percentiles() {
+        // 1..=20 ms, shuffled so the summary has to sort them
+        let durations: Vec<Duration> = (1..=20)
+            .map(|i| Duration::from_millis((i * 7 % 20 + 1) as u64))
+            .collect();
+        let summary = TimingSummary::from_durations(&durations).unwrap();
+        assert_eq!(
+            summary,
+            TimingSummary {
+                count: 20,
+                min: Duration::from_millis(1),
+                median: Duration::from_millis(10),
+                p95: Duration::from_millis(19),
+                max: Duration::from_millis(20),
+            }
+        );
+
+        let single = TimingSummary::from_durations(&[Duration::from_secs(3)]).unwrap();
+        assert_eq!(
+            (single.min, single.median, single.p95),
+            (
+                Duration::from_secs(3),
+                Duration::from_secs(3),
+                Duration::from_secs(3)
+            )
+        );
+        assert_eq!(TimingSummary::from_durations(&[]), None);
+    }
+
+    #[test]
+    fn throughput_is_per_second() {
+        assert_eq!(
+            throughput(5, 3 * 1024 * 1024, Duration::from_secs(2), "images"),
+            "2.50 images/s, 1.50 MB/s"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Small formatting helpers shared by the commands.
+
+use std::cmp::Ordering;
+
+const KB: u64 = 1024;
+const MB: u64 = KB * 1024;
+const GB: u64 = MB * 1024;
+const TB: u64 = GB * 1024;
+
+/// Formats a byte count as a human-readable string, e.g. "1.50 MB". Units are powers of 1024.
+pub fn human_readable_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= TB => format!("{:.2} TB", b as f64 / TB as f64),
+        b if b >= GB => format!("{:.2} GB", b as f64 / GB as f64),
+        b if b >= MB => format!("{:.2} MB", b as f64 / MB as f64),
+        b if b >= KB => format!("{:.2} KB", b as f64 / KB as f64),
+        b => format!("{} bytes", b),
+    }
+}
+
+/// Formats a byte count as KiB with two decimals, the form used by the manifest's
+/// `file_size_kib` column. Kept separate from `human_readable_size` so the column stays
+/// comparable between rows.
+pub fn format_kib(bytes: u64) -> String {
+    format!("{:.2}", bytes as f64 / KB as f64)
+}
+
+/// Parses a byte count such as `5MB`, `1.5 GB` or `1048576`. Units are powers of 1024 like
+/// `human_readable_size`, case-insensitive, and may be written `KiB`-style too.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '_'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: f64 = number
+        .replace('_', "")
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => KB,
+        "M" | "MB" | "MIB" => MB,
+        "G" | "GB" | "GIB" => GB,
+        "T" | "TB" | "TIB" => TB,
+        _ => return Err(format!("unknown size unit in '{}'", value)),
+    };
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Compares strings so runs of digits are ordered by their value, putting `mandelbrot_2.png`
+/// before `mandelbrot_10.png`. Everything else compares as usual, and numbers that are equal
+/// apart from leading zeros fall back to plain string order.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_rest, mut b_rest) = (a, b);
+    loop {
+        let (Some(a_char), Some(b_char)) = (a_rest.chars().next(), b_rest.chars().next()) else {
+            return a_rest.len().cmp(&b_rest.len()).then_with(|| a.cmp(b));
+        };
+        if a_char.is_ascii_digit() && b_char.is_ascii_digit() {
+            let (a_number, a_tail) = split_digits(a_rest);
+            let (b_number, b_tail) = split_digits(b_rest);
+            let (a_value, b_value) = (
+                a_number.trim_start_matches('0'),
+                b_number.trim_start_matches('0'),
+            );
+            let order = a_value
+                .len()
+                .cmp(&b_value.len())
+                .then_with(|| a_value.cmp(b_value));
+            if order != Ordering::Equal {
+                return order;
+            }
+            (a_rest, b_rest) = (a_tail, b_tail);
+        } else {
+            if a_char != b_char {
+                return a_char.cmp(&b_char);
+            }
+            (a_rest, b_rest) = (&a_rest[a_char.len_utf8()..], &b_rest[b_char.len_utf8()..]);
+        }
+    }
+}
+
+/// Splits a string into its leading run of ASCII digits and the rest.
+fn split_digits(s: &str) -> (&str, &str) {
+    s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_readable_size_switches_units_at_powers_of_1024() {
+        assert_eq!(human_readable_size(0), "0 bytes");
+        assert_eq!(human_readable_size(1023), "1023 bytes");
+        assert_eq!(human_readable_size(1024), "1.00 KB");
+        assert_eq!(human_readable_size(1536), "1.50 KB");
+        assert_eq!(human_readable_size(MB - 1), "1024.00 KB");
+        assert_eq!(human_readable_size(MB), "1.00 MB");
+        assert_eq!(human_readable_size(GB - 1), "1024.00 MB");
+        assert_eq!(human_readable_size(GB), "1.00 GB");
+        assert_eq!(human_readable_size(TB), "1.00 TB");
+        assert_eq!(human_readable_size(2048 * TB), "2048.00 TB");
+        assert_eq!(format_kib(1536), "1.50");
+        assert_eq!(format_kib(0), "0.00");
+    }
+}
@@ -0,0 +1,254 @@
+//! Text stamped onto finished images by `--watermark`. The characters come from a built-in
+//! 5x7 bitmap font, so no font files or text rendering crates are needed.
+
+use image::{DynamicImage, ImageBuffer, Pixel};
+
+/// Corner (or middle) of the image the watermark is placed in, selected with
+/// `--watermark-position`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+/// Blank font pixels between characters.
+const GLYPH_SPACING: u32 = 1;
+/// Image pixels per font pixel are the shorter side divided by this, so the text is about
+/// 1/40 of the image tall whatever the resolution.
+const SCALE_DIVISOR: u32 = 280;
+
+/// Columns of each printable ASCII character from space to `~`, left to right, with the top
+/// row in the lowest bit. Anything else is drawn as `?`.
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x08, 0x2A, 0x1C, 0x2A, 0x08], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // @
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // backslash
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // f
+    [0x0C, 0x52, 0x52, 0x52, 0x3E], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        ' '..='~' => FONT[c as usize - ' ' as usize],
+        _ => FONT['?' as usize - ' ' as usize],
+    }
+}
+
+/// Draws `text` in white with a dark drop shadow, blended over the image at `opacity`
+/// (0 to 1). The text is scaled with the image and shrunk to fit its width; a line too long
+/// even at one pixel per font pixel is cut off at the edge.
+pub fn draw_watermark(
+    img: &mut DynamicImage,
+    text: &str,
+    position: WatermarkPosition,
+    opacity: f64,
+) {
+    match img {
+        DynamicImage::ImageRgb8(buffer) => stamp(buffer, text, position, opacity),
+        DynamicImage::ImageRgba8(buffer) => stamp(buffer, text, position, opacity),
+        other => {
+            let mut buffer = other.to_rgba8();
+            stamp(&mut buffer, text, position, opacity);
+            *other = DynamicImage::ImageRgba8(buffer);
+        }
+    }
+}
+
+fn stamp<P: Pixel<Subpixel = u8>>(
+    img: &mut ImageBuffer<P, Vec<u8>>,
+    text: &str,
+    position: WatermarkPosition,
+    opacity: f64,
+) {
+    let (width, height) = img.dimensions();
+    let glyphs: Vec<[u8; 5]> = text.chars().map(glyph).collect();
+    if glyphs.is_empty() {
+        return;
+    }
+    // Font pixels across the line, plus one for the shadow
+    let text_width = glyphs.len() as u32 * (GLYPH_WIDTH + GLYPH_SPACING) - GLYPH_SPACING + 1;
+    let text_height = GLYPH_HEIGHT + 1;
+    let mut scale = (width.min(height) / SCALE_DIVISOR).max(1);
+    while scale > 1 && (text_width + GLYPH_HEIGHT) * scale > width {
+        scale -= 1;
+    }
+    let margin = GLYPH_HEIGHT * scale / 2;
+    let (text_width, text_height) = (text_width * scale, text_height * scale);
+    let left = || margin.min(width.saturating_sub(text_width));
+    let right = || width.saturating_sub(text_width + margin);
+    let top = || margin.min(height.saturating_sub(text_height));
+    let bottom = || height.saturating_sub(text_height + margin);
+    let (x0, y0) = match position {
+        WatermarkPosition::TopLeft => (left(), top()),
+        WatermarkPosition::TopRight => (right(), top()),
+        WatermarkPosition::BottomLeft => (left(), bottom()),
+        WatermarkPosition::BottomRight => (right(), bottom()),
+        WatermarkPosition::Center => (
+            width.saturating_sub(text_width) / 2,
+            height.saturating_sub(text_height) / 2,
+        ),
+    };
+
+    // The shadow goes down first, one font pixel down and right, so the text reads on both
+    // light and dark parts of the image
+    for (offset, ink) in [(1, 0u8), (0, 255u8)] {
+        for (index, columns) in glyphs.iter().enumerate() {
+            let glyph_x = index as u32 * (GLYPH_WIDTH + GLYPH_SPACING) + offset;
+            for (column, bits) in columns.iter().enumerate() {
+                for row in (0..GLYPH_HEIGHT).filter(|row| bits >> row & 1 == 1) {
+                    let cell_x = x0 + (glyph_x + column as u32) * scale;
+                    let cell_y = y0 + (row + offset) * scale;
+                    for y in cell_y..(cell_y + scale).min(height) {
+                        for x in cell_x..(cell_x + scale).min(width) {
+                            img.get_pixel_mut(x, y).apply_with_alpha(
+                                |c| (c as f64 + (ink as f64 - c as f64) * opacity).round() as u8,
+                                |a| (a as f64 + (255.0 - a as f64) * opacity).round() as u8,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn watermark_only_changes_pixels_in_its_corner() {
+        let base = DynamicImage::ImageRgb8(RgbImage::from_fn(320, 200, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }));
+        // "regen" at one image pixel per font pixel: 5 glyphs of 5 columns and a space, plus
+        // the shadow, is 30x8, inset by a 3 pixel margin
+        let boxes = [
+            (WatermarkPosition::TopLeft, (3, 3)),
+            (WatermarkPosition::TopRight, (287, 3)),
+            (WatermarkPosition::BottomLeft, (3, 189)),
+            (WatermarkPosition::BottomRight, (287, 189)),
+            (WatermarkPosition::Center, (145, 96)),
+        ];
+        for (position, (left, top)) in boxes {
+            let mut marked = base.clone();
+            draw_watermark(&mut marked, "regen", position, 0.8);
+            let (base, marked) = (base.to_rgb8(), marked.to_rgb8());
+            let mut changed = 0;
+            for (x, y, pixel) in marked.enumerate_pixels() {
+                if pixel != base.get_pixel(x, y) {
+                    changed += 1;
+                    assert!(
+                        (left..left + 30).contains(&x) && (top..top + 8).contains(&y),
+                        "{:?}: ({}, {}) changed",
+                        position,
+                        x,
+                        y
+                    );
+                }
+            }
+            assert!(changed > 30, "{:?}: {}", position, changed);
+        }
+
+        let mut invisible = base.clone();
+        draw_watermark(&mut invisible, "regen", WatermarkPosition::Center, 0.0);
+        assert_eq!(invisible, base);
+    }
+}
@@ -0,0 +1,590 @@
+//! End-to-end runs of the `regen` binary in a scratch directory.
+
+mod common;
+
+use clap::ValueEnum;
+use common::{MockS3, SMALL, file_names, regen, regen_unchecked, scratch_dir};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, ColorType, ImageFormat, ImageReader};
+use regen::render::{Palette, Pattern};
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[test]
+fn start_index_names_files_by_absolute_index() {
+    let dir = scratch_dir("start-index");
+    let mut args = vec!["generate", "--start-index", "10", "--count", "3"];
+    args.extend(SMALL);
+    regen(&dir, &args);
+    assert_eq!(
+        file_names(&dir.join("output")),
+        [
+            "mandelbrot_10.png",
+            "mandelbrot_11.png",
+            "mandelbrot_12.png"
+        ]
+    );
+}
+
+/// Three-frame zoom assembled into one animation at 4 fps.
+fn animated_zoom(dir: &Path, animation: &str) -> PathBuf {
+    regen(
+        dir,
+        &[
+            "zoom",
+            "--center-x",
+            "-0.75",
+            "--center-y",
+            "0",
+            "--end-radius",
+            "0.25",
+            "--frames",
+            "3",
+            "--width",
+            "24",
+            "--height",
+            "16",
+            "--max-iterations",
+            "50",
+            "--animate",
+            animation,
+            "--fps",
+            "4",
+        ],
+    );
+    let files = file_names(&dir.join("output"));
+    assert_eq!(files.len(), 1, "{:?}", files);
+    dir.join("output").join(&files[0])
+}
+
+#[test]
+fn zoom_animate_gif_has_every_frame_at_the_requested_delay() {
+    let path = animated_zoom(&scratch_dir("animate-gif"), "gif");
+    let decoder = GifDecoder::new(BufReader::new(fs::File::open(path).unwrap())).unwrap();
+    let frames = decoder.into_frames().collect_frames().unwrap();
+    assert_eq!(frames.len(), 3);
+    for frame in frames {
+        assert_eq!(Duration::from(frame.delay()), Duration::from_millis(250));
+        assert_eq!(frame.buffer().dimensions(), (24, 16));
+    }
+}
+
+#[test]
+fn zoom_animate_apng_has_every_frame_at_the_requested_delay() {
+    let path = animated_zoom(&scratch_dir("animate-apng"), "apng");
+    let decoder = PngDecoder::new(BufReader::new(fs::File::open(path).unwrap())).unwrap();
+    let frames = decoder
+        .apng()
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+    assert_eq!(frames.len(), 3);
+    for frame in frames {
+        assert_eq!(Duration::from(frame.delay()), Duration::from_millis(250));
+        assert_eq!(frame.buffer().dimensions(), (24, 16));
+    }
+}
+
+/// First word of every line `regen list <what>` prints.
+fn listed(what: &str) -> Vec<String> {
+    let output = regen(&scratch_dir(&format!("list-{}", what)), &["list", what]);
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.split_whitespace().next().map(String::from))
+        .collect()
+}
+
+#[test]
+fn list_shows_every_pattern_and_palette() {
+    let patterns = listed("patterns");
+    for pattern in Pattern::value_variants() {
+        let name = pattern.to_possible_value().unwrap();
+        assert!(patterns.iter().any(|p| p == name.get_name()), "{}", pattern);
+    }
+    let palettes = listed("palettes");
+    for palette in Palette::value_variants() {
+        let name = palette.to_possible_value().unwrap();
+        assert!(
+            palettes.iter().any(|p| p == name.get_name()),
+            "{:?}",
+            palette
+        );
+    }
+}
+
+#[test]
+fn output_dir_is_created_and_receives_the_images() {
+    let dir = scratch_dir("output-dir");
+    let target = dir.join("elsewhere/images");
+    let mut args = vec!["generate", "--count", "1", "--output-dir"];
+    args.push(target.to_str().unwrap());
+    args.extend(SMALL);
+    regen(&dir, &args);
+    assert_eq!(file_names(&target), ["mandelbrot_0.png"]);
+    assert!(!dir.join("output").exists());
+}
+
+#[test]
+fn same_seed_gives_byte_identical_images() {
+    let run = |name: &str| {
+        let dir = scratch_dir(name);
+        let args = [
+            "generate",
+            "--count",
+            "2",
+            "--seed",
+            "7",
+            "--width",
+            "32",
+            "--height",
+            "24",
+            "--pad-bytes",
+            "100..200",
+        ];
+        regen(&dir, &args);
+        let output = dir.join("output");
+        file_names(&output)
+            .into_iter()
+            .map(|name| (fs::read(output.join(&name)).unwrap(), name))
+            .collect::<Vec<_>>()
+    };
+    let first = run("seed-a");
+    assert_eq!(first.len(), 2);
+    assert_eq!(first, run("seed-b"));
+}
+
+#[test]
+fn narrow_ratio_band_gives_up_and_keeps_the_closest_render() {
+    let dir = scratch_dir("max-attempts");
+    let output = regen(
+        &dir,
+        &[
+            "generate",
+            "--count",
+            "1",
+            "--seed",
+            "3",
+            "--width",
+            "32",
+            "--height",
+            "24",
+            "--no-pad",
+            "--min-ratio",
+            "0.5",
+            "--max-ratio",
+            "0.5001",
+            "--max-attempts",
+            "3",
+        ],
+    );
+    assert_eq!(file_names(&dir.join("output")), ["mandelbrot_0.png"]);
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("closest"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn pad_bytes_range_and_no_pad_control_the_file_size() {
+    let size = |name: &str, pad: &[&str]| {
+        let dir = scratch_dir(name);
+        let mut args = vec!["generate", "--count", "1", "--seed", "5"];
+        // SMALL without its --no-pad
+        args.extend(&SMALL[..6]);
+        args.extend(pad);
+        regen(&dir, &args);
+        fs::read(dir.join("output/mandelbrot_0.png")).unwrap()
+    };
+    let unpadded = size("no-pad", &["--no-pad"]);
+    assert!(!unpadded.windows(4).any(|w| w == b"prVt"));
+    let padded = size("pad-range", &["--pad-bytes", "1000..2000"]);
+    // The padding chunk adds its length, type and CRC to the noise
+    let added = padded.len() - unpadded.len();
+    assert!(
+        (1000 + 12..=2000 + 12).contains(&added),
+        "{} bytes added",
+        added
+    );
+}
+
+#[test]
+fn each_format_gets_its_encoder_and_extension() {
+    for (format, file, expected) in [
+        ("png", "mandelbrot_0.png", ImageFormat::Png),
+        ("jpeg", "mandelbrot_0.jpg", ImageFormat::Jpeg),
+        ("webp", "mandelbrot_0.webp", ImageFormat::WebP),
+    ] {
+        let dir = scratch_dir(&format!("format-{}", format));
+        let mut args = vec!["generate", "--count", "1", "--format", format];
+        args.extend(SMALL);
+        regen(&dir, &args);
+        let path = dir.join("output").join(file);
+        let reader = ImageReader::open(&path)
+            .unwrap()
+            .with_guessed_format()
+            .unwrap();
+        assert_eq!(reader.format(), Some(expected));
+        let img = image::open(&path).unwrap();
+        assert_eq!(img.color(), ColorType::Rgb8, "{}", format);
+        assert_eq!((img.width(), img.height()), (32, 24));
+    }
+}
+
+#[test]
+fn wide_ratio_band_accepts_the_first_render() {
+    let dir = scratch_dir("wide-band");
+    let args = [
+        "generate",
+        "-v",
+        "--count",
+        "1",
+        "--seed",
+        "9",
+        "--width",
+        "32",
+        "--height",
+        "24",
+        "--no-pad",
+        "--min-ratio",
+        "0",
+        "--max-ratio",
+        "1",
+    ];
+    let output = regen(&dir, &args);
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(log.matches("Image 0: attempt").count(), 1, "{}", log);
+    assert!(!log.contains("Regenerating"), "{}", log);
+
+    let inverted = regen_unchecked(
+        &dir,
+        &[
+            "generate",
+            "--count",
+            "1",
+            "--min-ratio",
+            "0.6",
+            "--max-ratio",
+            "0.4",
+        ],
+    );
+    assert!(!inverted.status.success());
+    assert!(String::from_utf8_lossy(&inverted.stderr).contains("--min-ratio"));
+}
+
+#[test]
+fn clean_removes_only_images() {
+    let dir = scratch_dir("clean");
+    let images = dir.join("images");
+    fs::create_dir_all(&images).unwrap();
+    for file in ["a.png", "b.JPG", "c.webp", "notes.txt", "params.json"] {
+        fs::write(images.join(file), b"data").unwrap();
+    }
+    let output_dir = images.to_str().unwrap();
+
+    let listed = regen(&dir, &["clean", "--output-dir", output_dir, "--dry-run"]);
+    let listed = String::from_utf8_lossy(&listed.stdout);
+    assert!(
+        listed.contains("a.png") && !listed.contains("notes.txt"),
+        "{}",
+        listed
+    );
+    assert_eq!(file_names(&images).len(), 5);
+
+    regen(&dir, &["clean", "--output-dir", output_dir, "--yes"]);
+    assert_eq!(file_names(&images), ["notes.txt", "params.json"]);
+}
+
+#[test]
+fn zoom_writes_zero_padded_frames() {
+    let dir = scratch_dir("zoom-frames");
+    regen(
+        &dir,
+        &[
+            "zoom",
+            "--center-x",
+            "-0.75",
+            "--center-y",
+            "0.1",
+            "--end-radius",
+            "0.05",
+            "--frames",
+            "3",
+            "--width",
+            "24",
+            "--height",
+            "16",
+            "--max-iterations",
+            "50",
+        ],
+    );
+    let frames = file_names(&dir.join("output"));
+    assert_eq!(
+        frames,
+        ["frame_0001.png", "frame_0002.png", "frame_0003.png"]
+    );
+    let pixels: Vec<_> = frames
+        .iter()
+        .map(|frame| {
+            image::open(dir.join("output").join(frame))
+                .unwrap()
+                .to_rgb8()
+        })
+        .collect();
+    assert_ne!(pixels[0], pixels[2]);
+
+    let scales: Vec<f64> = frames
+        .iter()
+        .map(|frame| {
+            let params = saved_params(&dir, &dir.join("output").join(frame));
+            assert_eq!(params["x_pos"], -0.75);
+            assert_eq!(params["y_pos"], 0.1);
+            params["view_scale"].as_f64().unwrap()
+        })
+        .collect();
+    assert_eq!(scales[0], 1.0);
+    assert!(
+        scales.windows(2).all(|pair| pair[1] < pair[0]),
+        "{:?}",
+        scales
+    );
+    assert!((scales[2] - 0.05).abs() < 1e-12);
+}
+
+/// Parameters `regen params` reads back from a PNG.
+fn saved_params(dir: &Path, image: &Path) -> serde_json::Value {
+    let output = regen(dir, &["params", image.to_str().unwrap()]);
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn jobs_file_renders_each_row_as_listed() {
+    let dir = scratch_dir("jobs");
+    fs::write(
+        dir.join("jobs.csv"),
+        "filename,width,height,x_pos,y_pos,escape_radius,max_iterations,smoothness,color_step,pattern\n\
+         spiral,40,30,-0.745,0.113,0.01,300,4,2000,mandelbrot\n\
+         basilica,20,10,,,,,,,julia\n",
+    )
+    .unwrap();
+    regen(&dir, &["generate", "--jobs", "jobs.csv", "--no-pad"]);
+    assert_eq!(
+        file_names(&dir.join("output")),
+        ["basilica.png", "spiral.png"]
+    );
+
+    let spiral = saved_params(&dir, &dir.join("output/spiral.png"));
+    assert_eq!(spiral["pattern"], "mandelbrot");
+    assert_eq!(
+        (spiral["width"].as_u64(), spiral["height"].as_u64()),
+        (Some(40), Some(30))
+    );
+    assert_eq!(spiral["x_pos"], -0.745);
+    assert_eq!(spiral["y_pos"], 0.113);
+    assert_eq!(spiral["view_scale"], 0.01);
+    assert_eq!(spiral["max_iterations"], 300);
+    assert_eq!(spiral["smoothness"], 4);
+    assert_eq!(spiral["color_step"], 2000.0);
+
+    // Missing columns fall back to the Julia defaults
+    let basilica = saved_params(&dir, &dir.join("output/basilica.png"));
+    assert_eq!(basilica["pattern"], "julia");
+    assert_eq!(
+        (basilica["width"].as_u64(), basilica["height"].as_u64()),
+        (Some(20), Some(10))
+    );
+    assert_eq!(
+        (basilica["x_pos"].as_f64(), basilica["y_pos"].as_f64()),
+        (Some(0.0), Some(0.0))
+    );
+    assert_eq!(basilica["view_scale"], 0.75);
+}
+
+#[test]
+fn unknown_pattern_is_rejected_and_known_ones_are_listed() {
+    let dir = scratch_dir("patterns");
+    let output = regen_unchecked(&dir, &["generate", "--pattern", "spiral"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid value 'spiral'"), "{}", stderr);
+    assert!(!dir.join("output").exists());
+
+    let listed = regen(&dir, &["list", "patterns"]);
+    let names: Vec<String> = String::from_utf8_lossy(&listed.stdout)
+        .lines()
+        .filter_map(|line| line.split_whitespace().next().map(String::from))
+        .collect();
+    let known: Vec<String> = Pattern::value_variants()
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect();
+    assert_eq!(names, known);
+    assert!(known.contains(&"newton".to_string()));
+}
+
+#[test]
+fn json_log_lines_parse_back_with_the_expected_keys() {
+    let dir = scratch_dir("json-log");
+    let output = regen(&dir, &["--log-format", "json", "-v", "list", "patterns"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line)))
+        .collect();
+    let first = lines
+        .iter()
+        .find(|line| line["message"] == "Logger initialized.")
+        .unwrap_or_else(|| panic!("{}", stderr));
+    let mut keys: Vec<&str> = first
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    keys.sort();
+    assert_eq!(keys, ["level", "message", "target", "timestamp"]);
+    assert_eq!(first["level"], "INFO");
+    assert_eq!(first["target"], "regen");
+    assert!(first["timestamp"].as_str().unwrap().ends_with('Z'));
+
+    let text = regen(&dir, &["-v", "list", "patterns"]);
+    let stderr = String::from_utf8_lossy(&text.stderr);
+    assert!(stderr.contains("Logger initialized."));
+    assert!(serde_json::from_str::<serde_json::Value>(stderr.lines().next().unwrap()).is_err());
+}
+
+#[test]
+fn gen_concurrency_bounds_the_renders_in_flight() {
+    let dir = scratch_dir("gen-concurrency");
+    let mut args = vec!["-v", "generate", "--count", "6", "--gen-concurrency", "2"];
+    args.extend(SMALL);
+    let output = regen(&dir, &args);
+    assert_eq!(file_names(&dir.join("output")).len(), 6);
+
+    // A task logs its start once it holds a permit and its save before letting go of it
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let (mut in_flight, mut most) = (0, 0);
+    for line in stderr.lines() {
+        if line.contains("Starting generation for image") {
+            in_flight += 1;
+            most = most.max(in_flight);
+        } else if line.contains("Image saved to") {
+            in_flight -= 1;
+        }
+    }
+    assert_eq!(in_flight, 0, "{}", stderr);
+    assert!((1..=2).contains(&most), "{} renders at once", most);
+}
+
+#[test]
+fn each_pad_strategy_keeps_the_pixels_and_sets_the_size() {
+    const IEND: [u8; 12] = [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82];
+    let generate = |strategy: &str| {
+        let dir = scratch_dir(&format!("pad-{}", strategy));
+        let mut args = vec!["generate", "--count", "1", "--seed", "5"];
+        args.extend(&SMALL[..6]);
+        args.extend(["--pad-strategy", strategy, "--pad-bytes", "1000..2000"]);
+        regen(&dir, &args);
+        fs::read(dir.join("output/mandelbrot_0.png")).unwrap()
+    };
+    let none = generate("none");
+    let chunk = generate("chunk");
+    let append = generate("append");
+
+    // none and chunk end in IEND like any PNG; append leaves its noise after it
+    assert!(none.ends_with(&IEND) && chunk.ends_with(&IEND));
+    assert!(!none.windows(4).any(|w| w == b"prVt"));
+    assert!(chunk.windows(4).any(|w| w == b"prVt"));
+    let iend = append.windows(IEND.len()).position(|w| w == IEND).unwrap() + IEND.len();
+    assert_eq!(&append[..iend], &none[..]);
+    assert!((1000..=2000).contains(&(append.len() - iend)));
+    assert!((1000 + 12..=2000 + 12).contains(&(chunk.len() - none.len())));
+
+    let pixels = |bytes: &[u8]| image::load_from_memory(bytes).unwrap().to_rgb8();
+    assert_eq!(pixels(&chunk), pixels(&none));
+    assert_eq!(pixels(&append[..iend]), pixels(&none));
+}
+
+#[test]
+fn doctor_fails_missing_credentials_and_passes_a_writable_output_dir() {
+    let dir = scratch_dir("doctor");
+    let mock = MockS3::start();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_regen"))
+        .args([
+            "doctor",
+            "--endpoint",
+            &mock.endpoint,
+            "--region",
+            "us-east-1",
+            "--bucket",
+            "b",
+            "--credentials",
+            "keys",
+        ])
+        .env_remove("AWS_ACCESS_KEY_ID")
+        .env_remove("AWS_SECRET_ACCESS_KEY")
+        .env_remove("REGEN_CREDENTIALS")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let status = |check: &str| {
+        stdout
+            .lines()
+            .find(|line| line[6..].starts_with(check))
+            .map(|line| line[..4].to_string())
+    };
+    assert_eq!(status("credentials").as_deref(), Some("FAIL"), "{}", stdout);
+    assert_eq!(
+        status("output directory").as_deref(),
+        Some("PASS"),
+        "{}",
+        stdout
+    );
+    assert_eq!(status("endpoint").as_deref(), Some("PASS"), "{}", stdout);
+    // Checking changes nothing: no output directory, and the endpoint only saw a connection
+    assert!(!dir.join("output").exists());
+    assert!(mock.requests().is_empty());
+}
+
+#[test]
+fn data_uri_prints_a_png_of_the_requested_size_without_writing_files() {
+    let dir = scratch_dir("data-uri");
+    let output = regen(
+        &dir,
+        &[
+            "generate",
+            "--data-uri",
+            "--width",
+            "40",
+            "--height",
+            "30",
+            "--seed",
+            "7",
+        ],
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let encoded = stdout
+        .trim_end()
+        .strip_prefix("data:image/png;base64,")
+        .unwrap_or_else(|| panic!("not a PNG data URI: {}", stdout));
+    let png = base64::decode(encoded).unwrap();
+    assert_eq!(image::guess_format(&png).unwrap(), ImageFormat::Png);
+    let img = image::load_from_memory(&png).unwrap();
+    assert_eq!((img.width(), img.height()), (40, 30));
+    assert!(!dir.join("output").exists());
+
+    // Without a size it falls back to a small preview
+    let output = regen(&dir, &["generate", "--data-uri"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let encoded = stdout.trim_end().split_once(',').unwrap().1;
+    let img = image::load_from_memory(&base64::decode(encoded).unwrap()).unwrap();
+    assert_eq!((img.width(), img.height()), (320, 240));
+}
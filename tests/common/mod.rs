@@ -0,0 +1,281 @@
+//! Helpers shared by the integration tests that run the `regen` binary.
+// Each test crate compiles its own copy and uses only some of these
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Fresh, empty directory under the system temp dir for one test.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("regen-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs regen in `dir`, whether or not it succeeds.
+pub fn regen_unchecked(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_regen"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+/// Runs regen in `dir` and fails the test if it exits unsuccessfully.
+pub fn regen(dir: &Path, args: &[&str]) -> Output {
+    let output = regen_unchecked(dir, args);
+    assert!(
+        output.status.success(),
+        "regen {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Sorted names of the files in `dir`.
+pub fn file_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Small, quick, unpadded renders of a fixed view, so no attempt is ever retried.
+pub const SMALL: [&str; 7] = ["--width", "32", "--height", "24", "--zoom", "1", "--no-pad"];
+
+/// One request received by `MockS3`. Header names are lowercase.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Query string without the `?`, empty if there was none
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Minimal path-style S3 endpoint on a local port: PUT stores an object, HEAD and GET find
+/// it, multipart uploads are assembled on completion, and every request is recorded.
+pub struct MockS3 {
+    pub endpoint: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    refused: Arc<Mutex<HashSet<String>>>,
+    stalled: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Parts of the unfinished multipart uploads, by `bucket/key` and part number.
+type Parts = Mutex<HashMap<String, BTreeMap<u32, Vec<u8>>>>;
+
+impl MockS3 {
+    pub fn start() -> MockS3 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let objects = Arc::new(Mutex::new(HashMap::new()));
+        let refused = Arc::new(Mutex::new(HashSet::new()));
+        let stalled = Arc::new(Mutex::new(HashSet::new()));
+        let parts: Arc<Parts> = Arc::default();
+        let (log, store, refuse) = (requests.clone(), objects.clone(), refused.clone());
+        let stall = stalled.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (log, store, refuse) = (log.clone(), store.clone(), refuse.clone());
+                let (stall, parts) = (stall.clone(), parts.clone());
+                thread::spawn(move || {
+                    serve(stream.unwrap(), &log, &store, &refuse, &stall, &parts)
+                });
+            }
+        });
+        MockS3 {
+            endpoint,
+            requests,
+            objects,
+            refused,
+            stalled,
+        }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Deletes an object behind the uploader's back, by its `bucket/key` path.
+    pub fn remove(&self, path: &str) {
+        self.objects.lock().unwrap().remove(path);
+    }
+
+    /// Stores an object as if it had been uploaded earlier, under `bucket/key`.
+    pub fn insert(&self, path: &str, body: &[u8]) {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), body.to_vec());
+    }
+
+    /// Contents of the object at `bucket/key`, if there is one.
+    pub fn object(&self, path: &str) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(path).cloned()
+    }
+
+    /// Answers PUTs to `bucket/key` with 403 Forbidden, which isn't retried, until `accept`.
+    pub fn refuse(&self, path: &str) {
+        self.refused.lock().unwrap().insert(path.to_string());
+    }
+
+    pub fn accept(&self, path: &str) {
+        self.refused.lock().unwrap().remove(path);
+    }
+
+    /// Never answers PUTs to `bucket/key`, like a server that hangs, so a test can stop the
+    /// uploader part way through its run.
+    pub fn stall(&self, path: &str) {
+        self.stalled.lock().unwrap().insert(path.to_string());
+    }
+}
+
+/// Answers the requests on one keep-alive connection until the client closes it.
+fn serve(
+    stream: TcpStream,
+    log: &Mutex<Vec<Request>>,
+    objects: &Mutex<HashMap<String, Vec<u8>>>,
+    refused: &Mutex<HashSet<String>>,
+    stalled: &Mutex<HashSet<String>>,
+    parts: &Parts,
+) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut request_line = line.split_whitespace();
+        let method = request_line.next().unwrap_or_default().to_string();
+        let target = request_line.next().unwrap_or_default();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (path, query) = (path.trim_start_matches('/').to_string(), query.to_string());
+        let param = |name: &str| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .map(str::to_string)
+        };
+        let mut headers = Vec::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+            }
+        }
+        let length = headers
+            .iter()
+            .find(|(name, _)| name == "content-length")
+            .map_or(0, |(_, value)| value.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        if method == "PUT" && stalled.lock().unwrap().contains(&path) {
+            log.lock().unwrap().push(Request {
+                method,
+                path,
+                query,
+                headers,
+                body,
+            });
+            // Held open without a reply until the client goes away
+            let _ = reader.read_line(&mut String::new());
+            return;
+        }
+        let mut objects = objects.lock().unwrap();
+        let (status, response) = match method.as_str() {
+            "PUT" if refused.lock().unwrap().contains(&path) => ("403 Forbidden", Vec::new()),
+            "PUT" if param("uploadId").is_some() => {
+                let number = param("partNumber").unwrap().parse().unwrap();
+                parts
+                    .lock()
+                    .unwrap()
+                    .entry(path.clone())
+                    .or_default()
+                    .insert(number, body.clone());
+                ("200 OK", Vec::new())
+            }
+            "POST" if query == "uploads" || query == "uploads=" => {
+                parts.lock().unwrap().insert(path.clone(), BTreeMap::new());
+                let xml = format!(
+                    "<InitiateMultipartUploadResult><Key>{}</Key><UploadId>mock-upload</UploadId></InitiateMultipartUploadResult>",
+                    path
+                );
+                ("200 OK", xml.into_bytes())
+            }
+            "POST" if param("uploadId").is_some() => {
+                let uploaded = parts.lock().unwrap().remove(&path).unwrap_or_default();
+                objects.insert(path.clone(), uploaded.into_values().flatten().collect());
+                let xml = format!(
+                    "<CompleteMultipartUploadResult><Key>{}</Key><ETag>\"mock\"</ETag></CompleteMultipartUploadResult>",
+                    path
+                );
+                ("200 OK", xml.into_bytes())
+            }
+            "DELETE" if param("uploadId").is_some() => {
+                parts.lock().unwrap().remove(&path);
+                ("204 No Content", Vec::new())
+            }
+            "PUT" => {
+                objects.insert(path.clone(), body.clone());
+                ("200 OK", Vec::new())
+            }
+            "HEAD" | "GET" => match objects.get(&path) {
+                Some(object) => ("200 OK", object.clone()),
+                None => ("404 Not Found", Vec::new()),
+            },
+            _ => ("501 Not Implemented", Vec::new()),
+        };
+        drop(objects);
+        log.lock().unwrap().push(Request {
+            method: method.clone(),
+            path,
+            query,
+            headers,
+            body,
+        });
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nETag: \"mock\"\r\n\r\n",
+            status,
+            response.len()
+        );
+        // A HEAD response has the length of the object but no body
+        let body = if method == "HEAD" {
+            &[][..]
+        } else {
+            &response[..]
+        };
+        if writer.write_all(head.as_bytes()).is_err() || writer.write_all(body).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! The library's in-memory rendering, used the way a downstream crate would: no files, no
+//! binary, just `GenParams` in and pixels out.
+
+use image::Rgb;
+use regen::error::RegenError;
+use regen::render::{
+    Aspect, ColorClamp, Coloring, DEFAULT_BAILOUT, DEFAULT_POWER, DEFAULT_TILE_SIZE, GenParams,
+    Palette, Pattern, Trap, render, render_with_stats,
+};
+
+const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+
+/// 40x30 grayscale Mandelbrot render of the window centered on (x, y).
+fn mandelbrot(x_pos: f64, y_pos: f64, view_scale: f64) -> GenParams {
+    GenParams {
+        pattern: Pattern::Mandelbrot,
+        width: 40,
+        height: 30,
+        x_pos,
+        y_pos,
+        view_scale,
+        max_iterations: 200,
+        smoothness: 8,
+        color_step: 6000.0,
+        palette: Palette::Grayscale,
+        palette_stops: Vec::new(),
+        palette_offset: 0.0,
+        coloring: Coloring::Escape,
+        color_clamp: ColorClamp::Clamp,
+        trap: Trap::Point,
+        supersample: 1,
+        julia_constant: None,
+        power: DEFAULT_POWER,
+        bailout: DEFAULT_BAILOUT,
+        seed: Some(0),
+        index: 0,
+        deep_zoom: false,
+        aspect: Aspect::Pixel,
+        rgba: false,
+        pixel_center: true,
+        tile_size: DEFAULT_TILE_SIZE,
+    }
+}
+
+#[test]
+fn window_inside_the_main_cardioid_is_all_black() {
+    let img = render(&mandelbrot(-0.2, 0.0, 0.05)).unwrap();
+    assert_eq!(img.dimensions(), (40, 30));
+    assert!(img.pixels().all(|p| *p == BLACK));
+}
+
+#[test]
+fn window_far_outside_the_set_has_no_black() {
+    let img = render(&mandelbrot(1.5, 1.5, 0.05)).unwrap();
+    assert!(img.pixels().all(|p| *p != BLACK));
+}
+
+#[test]
+fn full_view_is_mirrored_across_the_real_axis() {
+    let img = render(&mandelbrot(-0.5, 0.0, 1.5)).unwrap();
+    let (width, height) = img.dimensions();
+    let in_set = |x, y| *img.get_pixel(x, y) == BLACK;
+    let mut black = 0;
+    for (x, y, _) in img.enumerate_pixels() {
+        assert_eq!(in_set(x, y), in_set(x, height - 1 - y), "({}, {})", x, y);
+        black += in_set(x, y) as u32;
+    }
+    assert!(black > 0 && black < width * height, "{}", black);
+}
+
+#[test]
+fn same_params_render_the_same_pixels() {
+    let params = mandelbrot(-0.745, 0.113, 0.05);
+    assert_eq!(render(&params).unwrap(), render(&params).unwrap());
+    let moved = GenParams {
+        x_pos: -0.74,
+        ..params.clone()
+    };
+    assert_ne!(render(&params).unwrap(), render(&moved).unwrap());
+}
+
+#[test]
+fn invalid_params_are_errors_rather_than_panics() {
+    let empty = GenParams {
+        width: 0,
+        ..mandelbrot(-0.5, 0.0, 1.5)
+    };
+    assert!(matches!(render(&empty), Err(RegenError::InvalidParams(_))));
+    let untiled = GenParams {
+        tile_size: 0,
+        ..mandelbrot(-0.5, 0.0, 1.5)
+    };
+    assert!(matches!(
+        render(&untiled),
+        Err(RegenError::InvalidParams(_))
+    ));
+}
+
+#[test]
+fn rgba_render_keeps_its_alpha_and_reports_stats() {
+    let params = GenParams {
+        rgba: true,
+        ..mandelbrot(-0.2, 0.0, 0.05)
+    };
+    let (img, stats) = render_with_stats(&params).unwrap();
+    let img = img.as_rgba8().expect("an rgba render has an alpha channel");
+    assert_eq!(img.dimensions(), (40, 30));
+    assert_eq!(stats.black_ratio, 1.0);
+    // Dropping the alpha leaves the same pixels `render` gives
+    assert_eq!(
+        image::DynamicImage::ImageRgba8(img.clone()).into_rgb8(),
+        render(&params).unwrap()
+    );
+}
@@ -0,0 +1,1049 @@
+//! Uploads generated images through the real upload path and reads them back.
+
+mod common;
+
+use common::{MockS3, SMALL, file_names, regen, regen_unchecked, scratch_dir};
+use rusoto_core::Region;
+use rusoto_core::credential::StaticProvider;
+use rusoto_s3::{CreateBucketRequest, GetObjectRequest, ListObjectsV2Request, S3, S3Client};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::process::Output;
+use tokio::io::AsyncReadExt;
+
+/// Generates two small images into `dir/output`.
+fn generate_two(dir: &Path) -> Vec<String> {
+    let mut args = vec!["generate", "--count", "2"];
+    args.extend(SMALL);
+    regen(dir, &args);
+    file_names(&dir.join("output"))
+}
+
+/// Uploads `dir/output` to bucket `b` on the mock with static keys and any extra arguments.
+fn upload_to(mock: &MockS3, dir: &Path, extra: &[&str]) -> Output {
+    let output = upload_to_unchecked(mock, dir, extra);
+    assert!(
+        output.status.success(),
+        "upload failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// `upload_to` without requiring the upload to succeed.
+fn upload_to_unchecked(mock: &MockS3, dir: &Path, extra: &[&str]) -> Output {
+    let mut args = vec![
+        "upload",
+        "--endpoint",
+        &mock.endpoint,
+        "--region",
+        "us-east-1",
+        "--bucket",
+        "b",
+        "--access-key",
+        "test",
+        "--secret-key",
+        "test",
+        "--yes",
+    ];
+    args.extend(extra);
+    regen_unchecked(dir, &args)
+}
+
+/// The PUT requests the mock received.
+fn puts(mock: &MockS3) -> Vec<common::Request> {
+    mock.requests()
+        .into_iter()
+        .filter(|request| request.method == "PUT")
+        .collect()
+}
+
+#[test]
+fn upload_round_trips_through_the_local_backend() {
+    let dir = scratch_dir("upload-local");
+    let names = generate_two(&dir);
+    regen(
+        &dir,
+        &[
+            "upload",
+            "--backend",
+            "local",
+            "--local-dir",
+            "store",
+            "--prefix",
+            "fractals",
+        ],
+    );
+
+    assert_eq!(file_names(&dir.join("store/fractals")), names);
+    for name in &names {
+        assert_eq!(
+            fs::read(dir.join("store/fractals").join(name)).unwrap(),
+            fs::read(dir.join("output").join(name)).unwrap(),
+            "{} changed on the way up",
+            name
+        );
+    }
+    let manifest = fs::read_to_string(dir.join("urls.csv")).unwrap();
+    for name in &names {
+        assert!(
+            manifest.contains(&format!("fractals/{}", name)),
+            "{}",
+            manifest
+        );
+    }
+}
+
+#[test]
+fn manifest_goes_next_to_the_output_dir_or_in_manifest_dir() {
+    let dir = scratch_dir("manifest-dir");
+    let mut args = vec!["generate", "--count", "1", "--output-dir", "site/images"];
+    args.extend(SMALL);
+    regen(&dir, &args);
+    let upload = |extra: &[&str]| {
+        let mut args = vec![
+            "upload",
+            "--output-dir",
+            "site/images",
+            "--backend",
+            "local",
+            "--local-dir",
+            "store",
+        ];
+        args.extend(extra);
+        regen(&dir, &args);
+    };
+
+    upload(&[]);
+    assert!(dir.join("site/urls.csv").exists());
+    assert!(!dir.join("urls.csv").exists());
+
+    upload(&["--manifest-dir", "records", "--manifest-format", "json"]);
+    assert!(dir.join("records/urls.json").exists());
+    let verified = regen(
+        &dir,
+        &[
+            "verify",
+            "--backend",
+            "local",
+            "--local-dir",
+            "store",
+            "--manifest-dir",
+            "records",
+            "--manifest-format",
+            "json",
+        ],
+    );
+    let stdout = String::from_utf8_lossy(&verified.stdout);
+    assert!(stdout.contains("1 ok, 0 missing"), "{}", stdout);
+}
+
+/// Runs against an S3-compatible service such as MinIO, e.g.
+/// `docker run -p 9000:9000 minio/minio server /data` and then
+/// `REGEN_TEST_ENDPOINT=http://localhost:9000 AWS_ACCESS_KEY_ID=minioadmin
+/// AWS_SECRET_ACCESS_KEY=minioadmin cargo test --test upload`. Skipped when the variable is
+/// unset, so the suite doesn't need Docker or the network.
+#[tokio::test]
+async fn upload_round_trips_through_an_s3_endpoint() {
+    let Ok(endpoint) = std::env::var("REGEN_TEST_ENDPOINT") else {
+        eprintln!("REGEN_TEST_ENDPOINT is unset, skipping the S3 round trip");
+        return;
+    };
+    let bucket = std::env::var("REGEN_TEST_BUCKET").unwrap_or_else(|_| "regen-test".to_string());
+    let client = S3Client::new_with(
+        rusoto_core::HttpClient::new().unwrap(),
+        StaticProvider::new_minimal(
+            std::env::var("AWS_ACCESS_KEY_ID").unwrap(),
+            std::env::var("AWS_SECRET_ACCESS_KEY").unwrap(),
+        ),
+        Region::Custom {
+            name: "us-east-1".to_string(),
+            endpoint: endpoint.clone(),
+        },
+    );
+    // Already existing from an earlier run is fine
+    let _ = client
+        .create_bucket(CreateBucketRequest {
+            bucket: bucket.clone(),
+            ..Default::default()
+        })
+        .await;
+
+    let dir = scratch_dir("upload-s3");
+    let names = generate_two(&dir);
+    let prefix = format!("regen-test-{}", std::process::id());
+    regen(
+        &dir,
+        &[
+            "upload",
+            "--endpoint",
+            &endpoint,
+            "--region",
+            "us-east-1",
+            "--bucket",
+            &bucket,
+            "--prefix",
+            &prefix,
+            "--yes",
+        ],
+    );
+
+    let listed = client
+        .list_objects_v2(ListObjectsV2Request {
+            bucket: bucket.clone(),
+            prefix: Some(format!("{}/", prefix)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let mut keys: Vec<String> = listed
+        .contents
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|object| object.key)
+        .collect();
+    keys.sort();
+    let expected: Vec<String> = names.iter().map(|n| format!("{}/{}", prefix, n)).collect();
+    assert_eq!(keys, expected);
+
+    for name in &names {
+        let object = client
+            .get_object(GetObjectRequest {
+                bucket: bucket.clone(),
+                key: format!("{}/{}", prefix, name),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut body = Vec::new();
+        object
+            .body
+            .unwrap()
+            .into_async_read()
+            .read_to_end(&mut body)
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            fs::read(dir.join("output").join(name)).unwrap(),
+            "{} changed on the way up",
+            name
+        );
+    }
+}
+
+#[test]
+fn dry_run_plans_keys_without_uploading() {
+    let dir = scratch_dir("upload-dry-run");
+    let names = generate_two(&dir);
+    // Nothing listens on port 1, so any request that did go out would fail the run
+    let output = regen(
+        &dir,
+        &[
+            "upload",
+            "--dry-run",
+            "--bucket",
+            "b",
+            "--region",
+            "us-east-1",
+            "--endpoint",
+            "http://127.0.0.1:1",
+            "--prefix",
+            "fractals",
+            "--yes",
+        ],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("bucket=b region=us-east-1 prefix=fractals/"),
+        "{}",
+        stdout
+    );
+    for name in &names {
+        assert!(
+            stdout.contains(&format!("-> fractals/{} (image/png)", name)),
+            "{}",
+            stdout
+        );
+    }
+    assert!(!dir.join("urls.csv").exists());
+
+    regen(
+        &dir,
+        &[
+            "upload",
+            "--dry-run",
+            "--backend",
+            "local",
+            "--local-dir",
+            "store",
+        ],
+    );
+    assert!(!dir.join("store").exists());
+}
+
+#[test]
+fn chosen_acl_is_sent_with_every_put() {
+    let dir = scratch_dir("upload-acl");
+    generate_two(&dir);
+    let public = MockS3::start();
+    upload_to(&public, &dir, &[]);
+    let private = MockS3::start();
+    upload_to(&private, &dir, &["--acl", "private"]);
+    for (mock, acl) in [(&public, "public-read"), (&private, "private")] {
+        let puts = puts(mock);
+        assert_eq!(puts.len(), 2);
+        for put in puts {
+            assert_eq!(put.header("x-amz-acl"), Some(acl), "{}", put.path);
+        }
+    }
+
+    let unknown = regen_unchecked(
+        &dir,
+        &[
+            "upload", "--bucket", "b", "--region", "nyc3", "--acl", "world",
+        ],
+    );
+    assert!(!unknown.status.success());
+    assert!(String::from_utf8_lossy(&unknown.stderr).contains("invalid value 'world'"));
+}
+
+#[test]
+fn cache_control_and_content_disposition_headers_are_sent() {
+    let dir = scratch_dir("upload-headers");
+    generate_two(&dir);
+    let defaults = MockS3::start();
+    upload_to(&defaults, &dir, &[]);
+    let custom = MockS3::start();
+    upload_to(
+        &custom,
+        &dir,
+        &[
+            "--cache-control",
+            "no-store",
+            "--content-disposition",
+            "inline",
+        ],
+    );
+    let bare = MockS3::start();
+    upload_to(&bare, &dir, &["--no-cache-control"]);
+
+    for put in puts(&defaults) {
+        assert_eq!(
+            put.header("cache-control"),
+            Some("public, max-age=31536000")
+        );
+        assert_eq!(put.header("content-disposition"), None);
+    }
+    for put in puts(&custom) {
+        assert_eq!(put.header("cache-control"), Some("no-store"));
+        assert_eq!(put.header("content-disposition"), Some("inline"));
+    }
+    for put in puts(&bare) {
+        assert_eq!(put.header("cache-control"), None);
+    }
+    for mock in [&defaults, &custom, &bare] {
+        assert_eq!(puts(mock).len(), 2);
+    }
+}
+
+/// A row of `urls.json`, read back the way a downstream consumer would.
+#[derive(Debug, serde::Deserialize)]
+struct ManifestRow {
+    cdn_url: String,
+    origin_url: String,
+    file_name: String,
+    file_size_kib: String,
+    sha256: String,
+}
+
+#[test]
+fn json_manifest_describes_the_uploaded_files() {
+    let dir = scratch_dir("upload-json");
+    let names = generate_two(&dir);
+    regen(
+        &dir,
+        &[
+            "upload",
+            "--backend",
+            "local",
+            "--local-dir",
+            "store",
+            "--manifest-format",
+            "json",
+        ],
+    );
+    assert!(!dir.join("urls.csv").exists());
+    let json = fs::read_to_string(dir.join("urls.json")).unwrap();
+    let mut rows: Vec<ManifestRow> = serde_json::from_str(&json).unwrap();
+    rows.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    assert_eq!(
+        rows.iter().map(|row| &row.file_name).collect::<Vec<_>>(),
+        names.iter().collect::<Vec<_>>()
+    );
+    for row in &rows {
+        let bytes = fs::read(dir.join("output").join(&row.file_name)).unwrap();
+        let url_end = format!("/store/{}", row.file_name);
+        assert!(row.cdn_url.starts_with("file://") && row.cdn_url.ends_with(&url_end));
+        assert_eq!(row.origin_url, row.cdn_url);
+        assert_eq!(
+            row.file_size_kib,
+            format!("{:.2}", bytes.len() as f64 / 1024.0)
+        );
+        assert_eq!(row.sha256, format!("{:x}", Sha256::digest(&bytes)));
+    }
+}
+
+#[test]
+fn changed_content_updates_its_manifest_row() {
+    let dir = scratch_dir("upload-rehash");
+    let upload = || {
+        regen(
+            &dir,
+            &["upload", "--backend", "local", "--local-dir", "store"],
+        );
+        let csv = fs::read_to_string(dir.join("urls.csv")).unwrap();
+        csv.lines().skip(1).map(String::from).collect::<Vec<_>>()
+    };
+    let generate = |seed: &str| {
+        let mut args = vec!["generate", "--count", "1", "--seed", seed];
+        args.extend(SMALL);
+        regen(&dir, &args);
+        fs::read(dir.join("output/mandelbrot_0.png")).unwrap()
+    };
+    let hash = |bytes: &[u8]| format!("{:x}", Sha256::digest(bytes));
+
+    let first = generate("1");
+    let rows = upload();
+    assert_eq!(rows.len(), 1);
+    assert!(rows[0].contains(&hash(&first)), "{}", rows[0]);
+    // Same content again is a duplicate and leaves the row alone
+    assert_eq!(upload(), rows);
+
+    let second = generate("2");
+    assert_ne!(first, second);
+    let rows = upload();
+    assert_eq!(rows.len(), 1, "{:?}", rows);
+    assert!(rows[0].contains(&hash(&second)), "{}", rows[0]);
+    assert_eq!(
+        fs::read(dir.join("store/mandelbrot_0.png")).unwrap(),
+        second
+    );
+}
+
+#[test]
+fn verify_reports_objects_missing_from_the_space() {
+    let dir = scratch_dir("verify");
+    let names = generate_two(&dir);
+    let mock = MockS3::start();
+    upload_to(&mock, &dir, &["--prefix", "fractals"]);
+    mock.remove(&format!("b/fractals/{}", names[0]));
+
+    let output = regen_unchecked(
+        &dir,
+        &[
+            "verify",
+            "--endpoint",
+            &mock.endpoint,
+            "--region",
+            "us-east-1",
+            "--bucket",
+            "b",
+            "--prefix",
+            "fractals",
+            "--access-key",
+            "test",
+            "--secret-key",
+            "test",
+        ],
+    );
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("1 ok, 1 missing, 0 mismatched, 0 failed"),
+        "{}",
+        stdout
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("Missing object: fractals/{}", names[0])),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn missing_credentials_fail_before_any_request() {
+    let dir = scratch_dir("upload-no-keys");
+    generate_two(&dir);
+    let mock = MockS3::start();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_regen"))
+        .args([
+            "upload",
+            "--endpoint",
+            &mock.endpoint,
+            "--region",
+            "us-east-1",
+            "--bucket",
+            "b",
+            "--credentials",
+            "keys",
+            "--yes",
+        ])
+        .env_remove("AWS_ACCESS_KEY_ID")
+        .env_remove("AWS_SECRET_ACCESS_KEY")
+        .env_remove("REGEN_CREDENTIALS")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("No Spaces credentials: set AWS_ACCESS_KEY_ID"),
+        "{}",
+        stderr
+    );
+    assert!(mock.requests().is_empty());
+    assert!(!dir.join("urls.csv").exists());
+}
+
+#[test]
+fn rerun_after_a_failure_skips_keys_already_uploaded() {
+    let dir = scratch_dir("upload-resume");
+    let names = generate_two(&dir);
+    let state = dir.join("output/.regen-upload-state.json");
+    let mock = MockS3::start();
+    // Sorted, since the uploads in one run finish in any order
+    let put_paths = |mock: &MockS3| {
+        let mut paths: Vec<String> = puts(mock).into_iter().map(|put| put.path).collect();
+        paths.sort();
+        paths
+    };
+    let upload = |mock: &MockS3, extra: &[&str]| {
+        let mut args = vec![
+            "upload",
+            "--endpoint",
+            &mock.endpoint,
+            "--region",
+            "us-east-1",
+            "--bucket",
+            "b",
+            "--access-key",
+            "test",
+            "--secret-key",
+            "test",
+            "--yes",
+        ];
+        args.extend(extra);
+        regen_unchecked(&dir, &args)
+    };
+    let (first, second) = (format!("b/{}", names[0]), format!("b/{}", names[1]));
+
+    mock.refuse(&second);
+    assert!(!upload(&mock, &[]).status.success());
+    let checkpoint: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&state).unwrap()).unwrap();
+    assert_eq!(checkpoint["keys"], serde_json::json!([names[0]]));
+
+    mock.accept(&second);
+    assert!(upload(&mock, &[]).status.success());
+    assert_eq!(
+        put_paths(&mock),
+        [first.clone(), second.clone(), second.clone()]
+    );
+    // A run that finished leaves nothing to resume
+    assert!(!state.exists());
+
+    // --restart ignores the checkpoint of an unfinished run
+    let mock = MockS3::start();
+    mock.refuse(&second);
+    assert!(!upload(&mock, &[]).status.success());
+    mock.accept(&second);
+    assert!(upload(&mock, &["--restart"]).status.success());
+    assert_eq!(
+        put_paths(&mock)
+            .iter()
+            .filter(|path| **path == first)
+            .count(),
+        2
+    );
+
+    // The checkpoint lives in the output directory but is never uploaded, and a corrupt one
+    // is reported rather than silently discarded
+    let mock = MockS3::start();
+    mock.refuse(&second);
+    assert!(!upload(&mock, &["--only-ext", "png,json"]).status.success());
+    assert!(state.exists());
+    assert!(
+        puts(&mock)
+            .iter()
+            .all(|put| !put.path.contains("upload-state"))
+    );
+    fs::write(&state, "{").unwrap();
+    let output = upload(&mock, &[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Unreadable upload checkpoint"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn local_backend_manifest_uses_the_base_url_and_prefix() {
+    let dir = scratch_dir("upload-base-url");
+    let names = generate_two(&dir);
+    regen(
+        &dir,
+        &[
+            "upload",
+            "--backend",
+            "local",
+            "--local-dir",
+            "store",
+            "--prefix",
+            "fractals",
+            "--base-url",
+            "https://images.example.com/",
+        ],
+    );
+
+    let mut reader = csv::Reader::from_path(dir.join("urls.csv")).unwrap();
+    let mut rows: Vec<(String, String, String)> = reader
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            (
+                record[0].to_string(),
+                record[1].to_string(),
+                record[2].to_string(),
+            )
+        })
+        .collect();
+    rows.sort();
+    let expected: Vec<(String, String, String)> = names
+        .iter()
+        .map(|name| {
+            let url = format!("https://images.example.com/fractals/{}", name);
+            (url.clone(), url, name.clone())
+        })
+        .collect();
+    assert_eq!(rows, expected);
+    assert_eq!(file_names(&dir.join("store/fractals")), names);
+}
+
+#[test]
+fn compress_gzips_svg_but_leaves_png_alone() {
+    let dir = scratch_dir("upload-compress");
+    fs::create_dir(dir.join("output")).unwrap();
+    let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\">".to_string()
+        + &"<rect/>".repeat(200)
+        + "</svg>";
+    fs::write(dir.join("output/shape.svg"), &svg).unwrap();
+    fs::write(dir.join("output/pixel.png"), b"\x89PNG not really").unwrap();
+    let mock = MockS3::start();
+    upload_to(&mock, &dir, &["--compress"]);
+
+    let puts = puts(&mock);
+    let put = |path: &str| puts.iter().find(|put| put.path == path).unwrap();
+    let svg_put = put("b/shape.svg");
+    assert_eq!(svg_put.header("content-encoding"), Some("gzip"));
+    assert_eq!(svg_put.header("content-type"), Some("image/svg+xml"));
+    assert!(svg_put.body.len() < svg.len());
+    let mut unzipped = String::new();
+    flate2::read::GzDecoder::new(&svg_put.body[..])
+        .read_to_string(&mut unzipped)
+        .unwrap();
+    assert_eq!(unzipped, svg);
+
+    let png_put = put("b/pixel.png");
+    assert_eq!(png_put.header("content-encoding"), None);
+    assert_eq!(png_put.body, b"\x89PNG not really");
+}
+
+#[test]
+fn skip_existing_sends_no_put_for_keys_already_there() {
+    let dir = scratch_dir("upload-skip-existing");
+    let names = generate_two(&dir);
+    let mock = MockS3::start();
+    let existing = format!("b/{}", names[0]);
+    mock.insert(&existing, b"uploaded earlier");
+
+    upload_to(&mock, &dir, &["--skip-existing"]);
+    let requests = mock.requests();
+    assert!(
+        requests
+            .iter()
+            .any(|request| request.method == "HEAD" && request.path == existing)
+    );
+    let put_paths: Vec<String> = puts(&mock).into_iter().map(|put| put.path).collect();
+    assert_eq!(put_paths, [format!("b/{}", names[1])]);
+
+    // With --match-size a stored object of a different size is replaced
+    let mock = MockS3::start();
+    mock.insert(&existing, b"uploaded earlier");
+    upload_to(&mock, &dir, &["--skip-existing", "--match-size"]);
+    assert_eq!(puts(&mock).len(), 2);
+
+    // The default overwrites without asking
+    let mock = MockS3::start();
+    mock.insert(&existing, b"uploaded earlier");
+    upload_to(&mock, &dir, &[]);
+    assert_eq!(puts(&mock).len(), 2);
+    assert!(
+        mock.requests()
+            .iter()
+            .all(|request| request.method != "HEAD")
+    );
+}
+
+#[test]
+fn file_just_over_max_file_size_is_refused() {
+    let dir = scratch_dir("upload-max-size");
+    fs::create_dir(dir.join("output")).unwrap();
+    fs::write(dir.join("output/fits.png"), vec![0u8; 1024]).unwrap();
+    fs::write(dir.join("output/over.png"), vec![0u8; 1025]).unwrap();
+    let upload = |limit: &str| {
+        regen_unchecked(
+            &dir,
+            &[
+                "upload",
+                "--backend",
+                "local",
+                "--local-dir",
+                "store",
+                "--max-file-size",
+                limit,
+            ],
+        )
+    };
+
+    let refused = upload("1KB");
+    assert!(!refused.status.success());
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(stderr.contains("over.png (1.00 KB)"), "{}", stderr);
+    assert!(!stderr.contains("fits.png"), "{}", stderr);
+    // Nothing is uploaded when any file is too big
+    assert!(!dir.join("store").exists());
+    assert!(!dir.join("urls.csv").exists());
+
+    assert!(upload("1025").status.success());
+    assert_eq!(file_names(&dir.join("store")), ["fits.png", "over.png"]);
+}
+
+#[test]
+fn content_md5_matches_the_payload_sent() {
+    let dir = scratch_dir("upload-md5");
+    let names = generate_two(&dir);
+    fs::write(dir.join("output/shape.svg"), "<svg>".repeat(100)).unwrap();
+    let mock = MockS3::start();
+    upload_to(&mock, &dir, &["--compress", "--only-ext", "png,svg"]);
+
+    let puts = puts(&mock);
+    assert_eq!(puts.len(), names.len() + 1);
+    for put in puts {
+        let expected = base64::encode(md5::Md5::digest(&put.body));
+        assert_eq!(
+            put.header("content-md5"),
+            Some(expected.as_str()),
+            "{}",
+            put.path
+        );
+    }
+}
+
+#[test]
+fn public_upload_without_a_terminal_needs_yes() {
+    let dir = scratch_dir("upload-confirm");
+    generate_two(&dir);
+    let mock = MockS3::start();
+    let upload = |extra: &[&str]| {
+        let mut args = vec![
+            "upload",
+            "--endpoint",
+            &mock.endpoint,
+            "--region",
+            "us-east-1",
+            "--bucket",
+            "b",
+            "--access-key",
+            "test",
+            "--secret-key",
+            "test",
+        ];
+        args.extend(extra);
+        // Output() gives the child a closed stdin, so there's no terminal to ask on
+        regen_unchecked(&dir, &args)
+    };
+
+    let refused = upload(&[]);
+    assert!(!refused.status.success());
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(
+        stderr.contains("to b as public-read without confirmation; pass --yes"),
+        "{}",
+        stderr
+    );
+    assert!(mock.requests().is_empty());
+
+    // Private objects, pre-approved Spaces and --yes all go ahead without asking
+    for extra in [
+        &["--acl", "private"][..],
+        &["--public-bucket", "a,b"],
+        &["--yes"],
+    ] {
+        let before = puts(&mock).len();
+        let output = upload(extra);
+        assert!(
+            output.status.success(),
+            "{:?}: {}",
+            extra,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(puts(&mock).len(), before + 2, "{:?}", extra);
+    }
+}
+
+#[test]
+fn generate_and_upload_honours_the_upload_options() {
+    let dir = scratch_dir("generate-and-upload");
+    let mut args = vec![
+        "generate",
+        "--count",
+        "3",
+        "--and-upload",
+        "--backend",
+        "local",
+        "--local-dir",
+        "store",
+        "--retries",
+        "1",
+        "--manifest-format",
+        "json",
+        "--sort-manifest",
+        "size",
+    ];
+    args.extend(SMALL);
+    regen(&dir, &args);
+
+    let names = file_names(&dir.join("output"));
+    assert_eq!(file_names(&dir.join("store")), names);
+    assert!(!dir.join("urls.csv").exists());
+    let json = fs::read_to_string(dir.join("urls.json")).unwrap();
+    let rows: Vec<ManifestRow> = serde_json::from_str(&json).unwrap();
+    assert_eq!(rows.len(), 3);
+    let sizes: Vec<f64> = rows
+        .iter()
+        .map(|row| row.file_size_kib.parse().unwrap())
+        .collect();
+    assert!(
+        sizes.windows(2).all(|pair| pair[0] <= pair[1]),
+        "{:?}",
+        sizes
+    );
+    // Every upload finished, so there is nothing left for `regen upload` to resume
+    assert!(!dir.join("output/.regen-upload-state.json").exists());
+}
+
+#[test]
+fn upload_options_on_generate_need_and_upload() {
+    let dir = scratch_dir("generate-upload-options");
+    let mut args = vec!["generate", "--count", "1", "--retries", "2"];
+    args.extend(SMALL);
+    let output = regen_unchecked(&dir, &args);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--and-upload"));
+    assert!(!dir.join("output").exists());
+}
+
+#[test]
+fn one_failed_upload_of_three_is_reported_and_the_rest_recorded() {
+    let dir = scratch_dir("upload-partial");
+    let mut args = vec!["generate", "--count", "3"];
+    args.extend(SMALL);
+    regen(&dir, &args);
+    let names = file_names(&dir.join("output"));
+    let mock = MockS3::start();
+    mock.refuse(&format!("b/{}", names[1]));
+
+    let output = upload_to_unchecked(&mock, &dir, &[]);
+    assert!(!output.status.success());
+    // Every upload was still attempted rather than stopping at the failure
+    assert_eq!(puts(&mock).len(), 3);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let report = stdout.split("Failed uploads:").nth(1).unwrap();
+    assert!(report.contains(&names[1]), "{}", stdout);
+    assert!(!report.contains(&names[0]) && !report.contains(&names[2]));
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&names[1]));
+
+    let mut reader = csv::Reader::from_path(dir.join("urls.csv")).unwrap();
+    let mut recorded: Vec<String> = reader
+        .records()
+        .map(|record| record.unwrap()[2].to_string())
+        .collect();
+    recorded.sort();
+    assert_eq!(recorded, [names[0].clone(), names[2].clone()]);
+}
+
+#[test]
+fn failed_upload_during_generate_is_reported_after_the_rest_are_recorded() {
+    let dir = scratch_dir("generate-and-upload-partial");
+    let mock = MockS3::start();
+    mock.refuse("b/mandelbrot_1.png");
+    let mut args = vec![
+        "generate",
+        "--count",
+        "3",
+        "--and-upload",
+        "--endpoint",
+        &mock.endpoint,
+        "--region",
+        "us-east-1",
+        "--bucket",
+        "b",
+        "--access-key",
+        "test",
+        "--secret-key",
+        "test",
+        "--yes",
+        "--retries",
+        "0",
+        "--metrics-file",
+        "regen.prom",
+    ];
+    args.extend(SMALL);
+    let output = regen_unchecked(&dir, &args);
+
+    assert!(!output.status.success());
+    assert_eq!(puts(&mock).len(), 3);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let report = stdout.split("Failed uploads:").nth(1).unwrap();
+    assert!(report.contains("mandelbrot_1.png"), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("mandelbrot_1.png"));
+    // The manifest and metrics were still written before the error
+    let mut reader = csv::Reader::from_path(dir.join("urls.csv")).unwrap();
+    let mut recorded: Vec<String> = reader
+        .records()
+        .map(|record| record.unwrap()[2].to_string())
+        .collect();
+    recorded.sort();
+    assert_eq!(recorded, ["mandelbrot_0.png", "mandelbrot_2.png"]);
+    let metrics = fs::read_to_string(dir.join("regen.prom")).unwrap();
+    assert!(metrics.contains("regen_failures_total 1"), "{}", metrics);
+}
+
+#[test]
+fn large_file_goes_up_in_parts_and_a_small_one_in_one_put() {
+    let dir = scratch_dir("upload-multipart");
+    let output = dir.join("output");
+    fs::create_dir_all(&output).unwrap();
+    // 8 MiB parts, so 17 MiB takes three of them
+    let large: Vec<u8> = (0..17 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    fs::write(output.join("large.png"), &large).unwrap();
+    fs::write(output.join("small.png"), b"small").unwrap();
+    let mock = MockS3::start();
+    upload_to(&mock, &dir, &["--multipart-threshold", "16MB"]);
+
+    let requests = mock.requests();
+    let calls = |path: &str| -> Vec<(String, String)> {
+        requests
+            .iter()
+            .filter(|request| request.path == path)
+            .map(|request| (request.method.clone(), request.query.clone()))
+            .collect()
+    };
+    assert_eq!(calls("b/small.png"), [("PUT".to_string(), String::new())]);
+
+    let large_calls = calls("b/large.png");
+    let (create, rest) = large_calls.split_first().unwrap();
+    let (complete, parts) = rest.split_last().unwrap();
+    assert_eq!(create.0, "POST");
+    assert!(create.1.starts_with("uploads"), "{:?}", create);
+    assert_eq!(complete.0, "POST");
+    assert!(
+        complete.1.contains("uploadId=mock-upload"),
+        "{:?}",
+        complete
+    );
+    let mut numbers: Vec<&str> = parts
+        .iter()
+        .map(|(method, query)| {
+            assert_eq!(method, "PUT");
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("partNumber="))
+                .unwrap()
+        })
+        .collect();
+    numbers.sort();
+    assert_eq!(numbers, ["1", "2", "3"]);
+
+    // The parts were assembled back into the file
+    assert!(mock.object("b/large.png").unwrap() == large);
+}
+
+#[test]
+fn manifest_is_flushed_before_a_crash_part_way_through() {
+    let dir = scratch_dir("upload-flush");
+    let mut args = vec!["generate", "--count", "3"];
+    args.extend(SMALL);
+    regen(&dir, &args);
+    let names = file_names(&dir.join("output"));
+    let mock = MockS3::start();
+    // The last upload never finishes, so the run is still going when it is killed
+    mock.stall(&format!("b/{}", names[2]));
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_regen"))
+        .args([
+            "upload",
+            "--endpoint",
+            &mock.endpoint,
+            "--region",
+            "us-east-1",
+            "--bucket",
+            "b",
+            "--access-key",
+            "test",
+            "--secret-key",
+            "test",
+            "--yes",
+            "--flush-every",
+            "1",
+        ])
+        .current_dir(&dir)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let csv_path = dir.join("urls.csv");
+    let recorded = || -> Vec<String> {
+        let Ok(mut reader) = csv::Reader::from_path(&csv_path) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = reader
+            .records()
+            .filter_map(|record| Some(record.ok()?[2].to_string()))
+            .collect();
+        names.sort();
+        names
+    };
+    let stalled_put = || {
+        puts(&mock)
+            .iter()
+            .any(|put| put.path == format!("b/{}", names[2]))
+    };
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    while (recorded().len() < 2 || !stalled_put()) && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(stalled_put(), "the stalled upload was never started");
+    assert_eq!(recorded(), [names[0].clone(), names[1].clone()]);
+}